@@ -1,34 +1,337 @@
-use clap::Parser;
-use globset::{Glob, GlobSet, GlobSetBuilder};
-use ksubst::substitute;
-use std::collections::HashMap;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use ksubst::{substitute, ListMerge, Lookup};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
-use std::io::{self, Read};
-use std::path::Path;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 use walkdir::WalkDir;
 
 #[derive(Parser, Debug)]
 #[command(version, about = "Variable substitution tool")]
 struct Args {
-    /// Path to .env file
-    #[arg(long = "env-file", conflicts_with = "env_vars")]
-    env_file: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    sources: Sources,
+
+    /// Options of the default render command
+    #[command(flatten)]
+    render: RenderArgs,
+
+    /// Print more details on stderr: processed files, variables sources
+    /// (can be specified multiple times)
+    #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only print errors on stderr
+    #[arg(short = 'q', long = "quiet", conflicts_with = "verbose", global = true)]
+    quiet: bool,
+
+    /// Exit successfully even if some variables are unresolved
+    #[arg(long = "exit-zero-on-missing", global = true)]
+    exit_zero_on_missing: bool,
+
+    /// Project configuration file, instead of ksubst.toml in the current
+    /// directory if any
+    #[arg(long = "config", value_name = "FILE", global = true)]
+    config: Option<String>,
+
+    /// Apply this profile of the project configuration
+    #[arg(long = "profile", value_name = "NAME", global = true)]
+    profile: Option<String>,
+}
+
+/// Project configuration file, looked up in the current directory.
+const CONFIG_FILE: &str = "ksubst.toml";
+
+/// Exit code of runs failing for any other reason, e.g. I/O errors.
+const EXIT_ERROR: u8 = 1;
+/// Exit code of runs failing because of unresolved variables.
+const EXIT_UNRESOLVED: u8 = 2;
+/// Exit code of runs failing because of invalid templates or variables.
+const EXIT_INVALID: u8 = 3;
+
+/// Error of runs with placeholders for undefined variables.
+#[derive(Debug)]
+struct Unresolved(usize);
+
+impl std::fmt::Display for Unresolved {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unresolved placeholders in {} input(s)", self.0)
+    }
+}
+
+impl std::error::Error for Unresolved {}
+
+/// Verbosity of messages printed to stderr.
+static VERBOSITY: AtomicU8 = AtomicU8::new(NORMAL);
+/// Only errors are printed.
+const QUIET: u8 = 0;
+/// Status messages are printed.
+const NORMAL: u8 = 1;
+/// Processed files and variables sources are printed.
+const VERBOSE: u8 = 2;
+/// Details like variables counts are printed.
+const DEBUG: u8 = 3;
+
+/// Print a message to stderr, if the verbosity is at least `level`.
+macro_rules! message {
+    ($level:expr, $($arg:tt)*) => {
+        if VERBOSITY.load(Ordering::Relaxed) >= $level {
+            eprintln!("ksubst: {}", redact(&format!($($arg)*)));
+        }
+    };
+}
+
+/// Values of secret variables, longest first, redacted from what is printed.
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Replacement of redacted secret values.
+const REDACTED: &str = "***";
+
+/// Register the values of the secret variables of a context.
+fn register_secrets(variables: &HashMap<String, String>, args: &RenderArgs) {
+    let mut secrets = SECRETS.lock().unwrap();
+    for (name, value) in variables {
+        if !value.is_empty() && is_secret(name, args) && !secrets.contains(value) {
+            secrets.push(value.clone());
+        }
+    }
+    secrets.sort_by_key(|value| std::cmp::Reverse(value.len()));
+}
+
+/// Whether a variable is a secret, by name or pattern.
+fn is_secret(name: &str, args: &RenderArgs) -> bool {
+    args.secrets.iter().any(|secret| secret == name) || args.secret_pattern.is_match(name)
+}
+
+/// Redact the values of secret variables from text.
+fn redact(text: &str) -> Cow<'_, str> {
+    let secrets = SECRETS.lock().unwrap();
+    let mut text = Cow::Borrowed(text);
+    for secret in secrets.iter() {
+        if text.contains(secret.as_str()) {
+            text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+        }
+    }
+    text
+}
+
+/// Variables sources, shared by all commands.
+#[derive(clap::Args, Debug)]
+struct Sources {
+    /// Path to .env file (can be specified multiple times)
+    ///
+    /// Later files override variables from earlier ones. Values may reference
+    /// other entries of the file, in any order, or else variables of earlier
+    /// files or the environment, like '$HOST' or '${HOST}'.
+    #[arg(long = "env-file", global = true)]
+    env_file: Vec<String>,
+
+    /// File descriptor to read .env formatted variables from (can be specified
+    /// multiple times)
+    ///
+    /// This keeps secrets out of arguments and off disk, e.g. with
+    /// '--vars-fd 3 3< <(fetch-secrets)'. These override variables from
+    /// --env-file, and behave the same as '--env-file /dev/fd/3'.
+    #[arg(long = "vars-fd", global = true)]
+    vars_fd: Vec<u32>,
+
+    /// Path to a JSON file of variables, or '-' for stdin
+    ///
+    /// Nested objects are flattened, keys being joined by underscores:
+    /// '{"db": {"host": "localhost"}}' defines ${db_host}. These override
+    /// variables from --env-file.
+    #[arg(long = "vars-json", global = true)]
+    vars_json: Option<String>,
+
+    /// Path to a YAML file of variables, or '-' for stdin
+    ///
+    /// Nested mappings are flattened like with --vars-json. These override
+    /// variables from --vars-json.
+    #[arg(long = "vars-yaml", global = true)]
+    vars_yaml: Option<String>,
+
+    /// Path to a TOML file of variables, or '-' for stdin
+    ///
+    /// Tables are flattened like objects with --vars-json. These override
+    /// variables from --vars-yaml.
+    #[arg(long = "vars-toml", global = true)]
+    vars_toml: Option<String>,
 
     /// Environment variables in 'KEY=VALUE' format, separated by commas
-    #[arg(long = "env-vars", conflicts_with = "env_file")]
+    ///
+    /// A value given as '@path' is read from the file at path. These
+    /// override variables from --env-file and variables files.
+    #[arg(long = "env-vars", global = true)]
     env_vars: Option<String>,
 
-    /// Recursively process files in input directory
-    #[arg(short = 'r', long = "recursive", requires_all = ["input_dir", "output_dir"])]
+    /// Path to .env file overriding the variables of all other sources
+    /// except --env (can be specified multiple times)
+    #[arg(long = "env-overrides-file", value_name = "PATH", global = true)]
+    env_overrides_file: Vec<String>,
+
+    /// Environment variable in 'KEY=VALUE' format (can be specified multiple times)
+    ///
+    /// Values may contain commas and '='. A value given as '@path' is read
+    /// from the file at path. These override variables from --env-vars and
+    /// --env-overrides-file.
+    #[arg(short = 'e', long = "env", value_parser = parse_key_value, global = true)]
+    env: Vec<(String, String)>,
+
+    /// Default value in 'KEY=VALUE' format, used if the variable is not set otherwise
+    /// (can be specified multiple times)
+    #[arg(long = "default", value_parser = parse_key_value, global = true)]
+    defaults: Vec<(String, String)>,
+
+    /// Only use environment variables starting with this prefix, stripped
+    /// from their names
+    ///
+    /// With '--prefix MYAPP_', MYAPP_PORT is available as ${PORT}. This
+    /// applies whenever environment variables are used.
+    #[arg(long = "prefix", global = true)]
+    prefix: Option<String>,
+
+    /// When to use environment variables, and their precedence over other
+    /// sources
+    #[arg(long = "process-env", value_enum, default_value_t = ProcessEnv::Auto, global = true)]
+    process_env: ProcessEnv,
+}
+
+/// Use of environment variables, among variables sources.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ProcessEnv {
+    /// Only if no other source is given
+    Auto,
+    /// Never
+    Never,
+    /// Beneath all other sources
+    Base,
+    /// Over files, beneath --env-vars, --env-overrides-file and --env
+    Override,
+}
+
+/// Options of the render command.
+#[derive(clap::Args, Debug)]
+struct RenderArgs {
+    /// Recursively process files in input directories
+    #[arg(short = 'r', long = "recursive", conflicts_with = "in_place")]
     recursive: bool,
 
-    /// Input directory (required if -r is specified)
-    #[arg()]
-    input_dir: Option<String>,
+    /// Substitute files in place, keeping backups with the given suffix if any
+    #[arg(
+        short = 'i',
+        long = "in-place",
+        value_name = "SUFFIX",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ""
+    )]
+    in_place: Option<String>,
+
+    /// Write the output to this file instead of stdout, creating parent
+    /// directories if needed
+    ///
+    /// This requires reading from stdin or a single input file.
+    #[arg(short = 'o', long = "output", conflicts_with_all = ["recursive", "in_place"])]
+    output: Option<String>,
+
+    /// Read input files of recursive mode from this archive, without
+    /// unpacking it
+    ///
+    /// The format is given by the extension: .tar, .tar.gz, .tgz or .zip.
+    /// Files of input directories, if any, override its entries.
+    #[arg(
+        long = "input-archive",
+        value_name = "ARCHIVE",
+        value_parser = parse_archive_path,
+        requires = "recursive",
+        conflicts_with = "watch"
+    )]
+    input_archive: Option<(String, ArchiveFormat)>,
+
+    /// Write the outputs of recursive mode to this archive instead of an
+    /// output directory, all paths being input directories
+    ///
+    /// The format is given by the extension: .tar, .tar.gz, .tgz or .zip.
+    #[arg(
+        long = "output-archive",
+        value_name = "ARCHIVE",
+        value_parser = parse_archive_path,
+        requires = "recursive",
+        conflicts_with_all = ["backup", "force", "update", "delete", "clean", "dry_run", "diff", "watch"]
+    )]
+    output_archive: Option<(String, ArchiveFormat)>,
 
-    /// Output directory (required if -r is specified)
-    #[arg()]
-    output_dir: Option<String>,
+    /// Before overwriting an output file with a different content, save its
+    /// previous content to a file with this suffix
+    ///
+    /// With -i, this is the same as -i=SUFFIX.
+    #[arg(long = "backup", value_name = "SUFFIX")]
+    backup: Option<String>,
+
+    /// Overwrite output files edited since they were last rendered in
+    /// recursive mode
+    #[arg(short = 'f', long = "force", requires = "recursive")]
+    force: bool,
+
+    /// Only overwrite output files edited since they were last rendered if
+    /// they are older than their input in recursive mode, keeping others
+    #[arg(
+        short = 'u',
+        long = "update",
+        requires = "recursive",
+        conflicts_with = "force"
+    )]
+    update: bool,
+
+    /// Input directories then the output directory with -r, files to
+    /// substitute in place with -i, or else files to substitute to stdout
+    /// instead of stdin
+    ///
+    /// With -r, files of later input directories override those of earlier
+    /// ones. Outside of -r, glob patterns like 'configs/**/*.tpl' are expanded.
+    ///
+    /// Without -r, these may start with a SHELL-FORMAT listing the variables
+    /// to substitute, like GNU envsubst: with '$HOST ${PORT}', only HOST and
+    /// PORT are substituted, and substituted with an empty value if unset.
+    ///
+    /// Outside of -r and -i, 'https://' URLs are fetched, if built with the
+    /// 'http' feature.
+    #[arg(value_name = "PATH")]
+    paths: Vec<String>,
+
+    /// Also substitute the files listed in FILE, or stdin if '-', separated
+    /// by NUL characters like the output of 'find -print0'
+    ///
+    /// Listed paths are not expanded as glob patterns.
+    #[arg(long = "files0-from", value_name = "FILE", conflicts_with_all = ["recursive", "stream"])]
+    files0_from: Option<String>,
+
+    /// Fail unless the SHA-256 digest of the template is HEX, pinning the
+    /// content of stdin or a single input file or URL
+    #[arg(long = "sha256", value_name = "HEX", conflicts_with_all = ["recursive", "in_place"])]
+    sha256: Option<String>,
+
+    /// Substitute stdin line by line, writing each line as soon as it is
+    /// read instead of waiting for the end of input
+    ///
+    /// Placeholders spanning several lines are not substituted.
+    #[arg(
+        long = "stream",
+        conflicts_with_all = [
+            "recursive", "in_place", "output", "sha256", "front_matter", "strict", "report",
+            "encoding", "output_encoding", "bom", "format", "k8s"
+        ]
+    )]
+    stream: bool,
 
     /// Exclude patterns (can be specified multiple times)
     #[arg(long = "exclude")]
@@ -37,33 +340,858 @@ struct Args {
     /// Filter patterns (can be specified multiple times)
     #[arg(long = "filter")]
     filter_patterns: Vec<String>,
+
+    /// Copy files not matching filter patterns verbatim in recursive mode,
+    /// instead of leaving them out of the output directory
+    #[arg(long = "copy-unmatched", requires = "recursive")]
+    copy_unmatched: bool,
+
+    /// Strip this extension from the outputs of templates in recursive mode,
+    /// e.g. '.tpl' renders nginx.conf.tpl to nginx.conf (can be specified
+    /// multiple times)
+    #[arg(long = "template-ext", value_name = "EXT", requires = "recursive")]
+    template_exts: Vec<String>,
+
+    /// Only substitute files with a --template-ext extension, and copy others
+    /// verbatim
+    #[arg(long = "templates-only", requires = "template_exts")]
+    templates_only: bool,
+
+    /// Also process files ignored by git in recursive mode
+    ///
+    /// By default, files matched by .gitignore files of git repositories,
+    /// .git/info/exclude and the global git excludes file are skipped.
+    /// Files matched by .ksubstignore files are always skipped.
+    #[arg(long = "no-ignore", requires = "recursive")]
+    no_ignore: bool,
+
+    /// Also process hidden files and directories in recursive mode
+    ///
+    /// By default, files and directories whose name starts with a dot, like
+    /// .git, are skipped.
+    #[arg(long = "hidden", requires = "recursive")]
+    hidden: bool,
+
+    /// Only descend N directories deep in recursive mode
+    ///
+    /// With 1, only files directly in the input directory are processed.
+    #[arg(long = "max-depth", value_name = "N", requires = "recursive")]
+    max_depth: Option<usize>,
+
+    /// Follow symbolic links in recursive mode
+    ///
+    /// Linked directories are processed like regular ones, skipping links
+    /// back to their ancestors, and linked files are rendered. By default,
+    /// symbolic links are recreated as-is in the output directory.
+    #[arg(long = "follow-symlinks", requires = "recursive")]
+    follow_symlinks: bool,
+
+    /// How to handle binary files in recursive mode
+    ///
+    /// Files with NUL bytes or invalid UTF-8 are considered binary.
+    #[arg(long = "binary", value_enum, default_value_t = BinaryPolicy::Copy, requires = "recursive")]
+    binary: BinaryPolicy,
+
+    /// Also copy these attributes of input files to outputs in recursive
+    /// mode, besides permissions
+    #[arg(
+        long = "preserve",
+        value_enum,
+        value_delimiter = ',',
+        value_name = "ATTRS",
+        requires = "recursive"
+    )]
+    preserve: Vec<Preserve>,
+
+    /// Delete files in output directory which have no corresponding input file
+    #[arg(long = "delete", requires = "recursive")]
+    delete: bool,
+
+    /// Remove the previous contents of the output directory before rendering
+    #[arg(long = "clean", requires = "recursive", conflicts_with = "delete")]
+    clean: bool,
+
+    /// Encoding of templates
+    ///
+    /// By default, UTF-16 templates are detected from their byte order mark
+    /// or NUL bytes, and others are read as UTF-8.
+    #[arg(long = "encoding", value_enum, default_value_t = Encoding::Auto)]
+    encoding: Encoding,
+
+    /// Encoding of outputs, by default the encoding of their template
+    ///
+    /// Outputs transcoded to UTF-16 start with a byte order mark.
+    #[arg(long = "output-encoding", value_enum, default_value_t = Encoding::Auto)]
+    output_encoding: Encoding,
+
+    /// Byte order mark of outputs
+    #[arg(long = "bom", value_enum, default_value_t = Bom::Preserve)]
+    bom: Bom,
+
+    /// Line endings of outputs
+    ///
+    /// By default, outputs use the dominant line endings of their template.
+    #[arg(long = "newline", value_enum, default_value_t = Newline::Preserve)]
+    newline: Newline,
+
+    /// Placeholder delimiters of templates, instead of '${' and '}'
+    ///
+    /// Other '${...}' text of templates is left as-is.
+    #[arg(long = "delims", value_name = "OPEN,CLOSE", value_parser = parse_delimiters)]
+    delims: Option<ksubst::Delimiters>,
+
+    /// Placeholder syntax of templates
+    #[arg(
+        long = "syntax",
+        value_enum,
+        default_value_t = Syntax::Braces,
+        conflicts_with = "delims"
+    )]
+    syntax: Syntax,
+
+    /// Read front matter at the top of templates
+    ///
+    /// Front matter is a YAML block between '---' lines, declaring
+    /// 'defaults' values and 'required' variables for its file only. It is
+    /// removed from outputs.
+    #[arg(long = "front-matter")]
+    front_matter: bool,
+
+    /// Format of templates
+    ///
+    /// Structured templates are parsed, substituting string values only, so
+    /// outputs always stay valid documents. Comments of YAML templates are
+    /// not kept.
+    #[arg(long = "format", value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Also substitute object keys of structured templates
+    #[arg(long = "substitute-keys")]
+    substitute_keys: bool,
+
+    /// Substitute templates as Kubernetes manifests, like with --format yaml
+    ///
+    /// Values of Secret 'data' changed by substitution are base64-encoded.
+    #[arg(long = "k8s", conflicts_with_all = ["format", "substitute_keys"])]
+    k8s: bool,
+
+    /// Leave values of manifests under this dotted path as-is, '*' matching
+    /// any key or index, e.g. 'metadata.annotations'
+    #[arg(long = "k8s-skip", value_name = "PATH", requires = "k8s")]
+    k8s_skip: Vec<String>,
+
+    /// Prompt on the terminal for the values of variables used by templates
+    /// but not set, with their '@var' description and default if any
+    #[arg(long = "interactive", conflicts_with = "stream")]
+    interactive: bool,
+
+    /// Treat variables matching this pattern as secrets
+    ///
+    /// Values of secrets are still substituted into outputs, but are
+    /// redacted from diffs, messages and reports, and prompts for them hide
+    /// their input.
+    #[arg(
+        long = "secret-pattern",
+        value_name = "REGEX",
+        default_value = "(?i)pass|secret|token|key|credential",
+        value_parser = regex::Regex::new
+    )]
+    secret_pattern: regex::Regex,
+
+    /// Treat this variable as a secret, like with --secret-pattern (can be
+    /// specified multiple times)
+    #[arg(long = "secret", value_name = "NAME")]
+    secrets: Vec<String>,
+
+    /// Fail if any placeholder cannot be resolved, without writing any output
+    #[arg(long = "strict")]
+    strict: bool,
+
+    /// Fail if any rendered output still contains placeholders, whatever
+    /// the reason, like values containing placeholders
+    ///
+    /// In recursive mode, no output is written then.
+    #[arg(long = "fail-if-templated")]
+    fail_if_templated: bool,
+
+    /// List the unresolved variables of recursive runs, with the lines
+    /// referencing them, without writing any output
+    ///
+    /// All paths are input directories.
+    #[arg(
+        long = "list-missing",
+        requires = "recursive",
+        conflicts_with_all = [
+            "output_archive", "delete", "clean", "watch", "diff", "dry_run", "interactive"
+        ]
+    )]
+    list_missing: bool,
+
+    /// Report which files would be created, modified, skipped or deleted,
+    /// and the variables they use, without writing anything
+    #[arg(long = "dry-run", requires = "recursive")]
+    dry_run: bool,
+
+    /// Print unified diffs between existing output files and rendered ones
+    ///
+    /// Combine with --dry-run to review changes without applying them.
+    #[arg(long = "diff", requires = "recursive")]
+    diff: bool,
+
+    /// Number of files processed in parallel, or 0 for one per available core
+    #[arg(
+        short = 'j',
+        long = "jobs",
+        default_value_t = 1,
+        requires = "recursive"
+    )]
+    jobs: usize,
+
+    /// Emit a machine-readable report of the run, to stderr by default
+    ///
+    /// The report lists processed files with their number of replacements,
+    /// missing variables and durations. It is emitted even if the run fails.
+    #[arg(long = "report", value_enum)]
+    report: Option<ReportFormat>,
+
+    /// Write the report to this file instead of stderr
+    #[arg(long = "report-file", requires = "report")]
+    report_file: Option<String>,
+
+    /// Write a JSON manifest of outputs to this file, with their SHA-256
+    /// digest, template and the variables substituted in them
+    #[arg(
+        long = "manifest",
+        value_name = "FILE",
+        conflicts_with_all = ["dry_run", "watch", "stream", "list_missing"]
+    )]
+    manifest: Option<String>,
+
+    /// When to show progress of recursive runs on stderr
+    #[arg(long = "progress", value_enum, default_value_t = When::Auto)]
+    progress: When,
+
+    /// Print the variables of variables sources which no template references
+    #[arg(long = "unused", conflicts_with_all = ["watch", "stream"])]
+    unused: bool,
+
+    /// Fail if includes or outputs of recursive runs resolve outside of
+    /// their input or output directory, following symbolic links
+    #[arg(long = "restrict-root")]
+    restrict_root: bool,
+
+    /// Don't print a summary of recursive runs on stderr
+    #[arg(long = "no-summary")]
+    no_summary: bool,
+
+    /// Keep running, and render again whenever templates or variables files
+    /// change
+    ///
+    /// Changed templates are rendered again, selected like in the initial
+    /// run, and all of them if a variables file changed.
+    #[arg(long = "watch", requires = "recursive", conflicts_with = "dry_run")]
+    watch: bool,
+
+    /// When to colorize diffs
+    #[arg(long = "color", value_enum, default_value_t = clap::ColorChoice::Auto)]
+    color: clap::ColorChoice,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+#[derive(Subcommand, Debug)]
+enum HookCommand {
+    /// Check templates for malformed placeholders and committed secrets
+    ///
+    /// Values of secret variables, from variables sources or else the
+    /// environment, found verbatim in templates are reported, without being
+    /// printed. Without paths, files staged in git are checked, in their
+    /// staged version.
+    PreCommit {
+        /// Files to check instead of staged files, as passed by pre-commit
+        /// frameworks
+        #[arg(value_name = "PATH")]
+        paths: Vec<String>,
 
-    // Load variables
-    let variables = if let Some(env_file) = args.env_file {
-        // Load variables from .env file
-        let mut vars = HashMap::new();
-        dotenvy::from_path_iter(env_file)?
-            .filter_map(Result::ok)
-            .for_each(|(key, value)| {
-                vars.insert(key, value);
-            });
-        vars
-    } else if let Some(env_vars_str) = args.env_vars {
-        // Parse variables from command-line string
-        parse_env_vars(&env_vars_str)?
-    } else {
-        // Use environment variables
-        env::vars().collect::<HashMap<String, String>>()
+        /// Treat variables matching this pattern as secrets
+        #[arg(
+            long = "secret-pattern",
+            value_name = "REGEX",
+            default_value = "(?i)pass|secret|token|key|credential",
+            value_parser = regex::Regex::new
+        )]
+        secret_pattern: regex::Regex,
+
+        /// Treat this variable as a secret (can be specified multiple times)
+        #[arg(long = "secret", value_name = "NAME")]
+        secrets: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Substitute variables in templates (default command)
+    Render(Box<RenderArgs>),
+    /// Serve templates rendering over HTTP
+    ///
+    /// Variables from --env-file, --env-vars or --env are used as defaults,
+    /// and can be overridden by the variables sent along each request.
+    Serve {
+        /// Address to listen on
+        #[arg(long = "listen", default_value = "127.0.0.1:8080")]
+        listen: String,
+
+        /// Number of requests handled concurrently
+        #[arg(long = "workers", value_name = "N", default_value_t = 8)]
+        workers: usize,
+    },
+    /// Substitute variables in a command arguments and environment, and run it
+    ///
+    /// The command replaces ksubst, inheriting its environment with
+    /// substituted values. Variables from --env-file, --env-vars or --env are
+    /// also exported to the command.
+    Exec {
+        /// Command to run, followed by its arguments
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Substitute variables in stdin, as a git smudge filter
+    ///
+    /// Only variables from --env-file, variables files, --env-vars or --env
+    /// are used, never the process environment, and outputs are written
+    /// as-is, without a trailing newline. Inputs which are not UTF-8 are
+    /// written unchanged.
+    Smudge,
+    /// Restore placeholders in stdin, as a git clean filter
+    ///
+    /// This reverses 'smudge' with the same variables: the template of the
+    /// file in the git index is rendered and compared to stdin, and only
+    /// rendered values left unchanged are replaced with their placeholders.
+    /// Files not in the index are written unchanged.
+    Clean {
+        /// Path of the file in the repository, given by git as '%f'
+        #[arg(value_name = "PATH")]
+        path: String,
+    },
+    /// Substitute variables in a .env file, and print its entries as exports
+    ///
+    /// Placeholders in values are substituted with the loaded variables and
+    /// earlier entries of the file, and nothing is printed if any is
+    /// unresolved. For instance: 'eval "$(ksubst env --env-file prod.env .env.tpl)"'.
+    Env {
+        /// Templated .env file, or stdin if '-'
+        #[arg(value_name = "FILE", default_value = "-")]
+        file: String,
+
+        /// Output format
+        #[arg(long = "format", value_enum, default_value_t = EnvFormat::Export)]
+        format: EnvFormat,
+    },
+    /// Run as a git hook
+    Hook {
+        #[command(subcommand)]
+        hook: HookCommand,
+    },
+    /// Check that all variables referenced by templates are defined
+    ///
+    /// Missing variables are listed per template, and the check fails if
+    /// any is missing. No output is written.
+    Check {
+        /// Templates to check: files, directories or glob patterns, or stdin
+        /// if none
+        #[arg(value_name = "PATH")]
+        paths: Vec<String>,
+
+        /// Output format
+        #[arg(long = "format", value_enum, default_value_t = CheckFormat::Text)]
+        format: CheckFormat,
+    },
+    /// Report likely mistakes in templates, with their location and severity
+    ///
+    /// Malformed placeholders are errors, and bare '$VAR' references or
+    /// names differing only in case are warnings. The check fails on errors,
+    /// or on warnings too with '--deny warnings'.
+    Lint {
+        /// Templates to lint: files, directories or glob patterns, or stdin
+        /// if none
+        #[arg(value_name = "PATH")]
+        paths: Vec<String>,
+
+        /// Also fail on diagnostics of this severity
+        #[arg(long = "deny", value_enum, value_name = "LEVEL")]
+        deny: Option<Deny>,
+    },
+    /// List variables referenced by templates, with their number of references
+    Vars {
+        /// Templates to scan: files, directories or glob patterns, or stdin
+        /// if none
+        #[arg(value_name = "PATH")]
+        paths: Vec<String>,
+
+        /// Output format
+        #[arg(long = "format", value_enum, default_value_t = VarsFormat::Text)]
+        format: VarsFormat,
+
+        /// Also list variables referenced by each template, in text format
+        #[arg(long = "per-file")]
+        per_file: bool,
+    },
+    /// Print a shell completion script
+    ///
+    /// For instance, with bash: 'source <(ksubst completions bash)'.
+    Completions {
+        /// Shell to generate completions for
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print a man page, generated from the command-line options
+    ///
+    /// For instance: 'ksubst man > /usr/share/man/man1/ksubst.1'.
+    Man,
+}
+
+/// When to enable a terminal feature.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum When {
+    /// Only if the output is a terminal
+    Auto,
+    /// Even if the output is not a terminal
+    Always,
+    /// Never
+    Never,
+}
+
+/// Handling of binary files in recursive mode.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum BinaryPolicy {
+    /// Omit them from the output directory
+    Skip,
+    /// Copy them verbatim to the output directory
+    Copy,
+    /// Fail the run
+    Error,
+}
+
+/// Text encoding of templates and outputs.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    /// Detected from the content, or the template encoding for outputs
+    Auto,
+    /// UTF-8
+    #[value(name = "utf-8")]
+    Utf8,
+    /// UTF-16, little-endian
+    #[value(name = "utf-16le")]
+    Utf16Le,
+    /// UTF-16, big-endian
+    #[value(name = "utf-16be")]
+    Utf16Be,
+    /// ISO-8859-1
+    Latin1,
+}
+
+/// Format of templates.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// Plain text, substituted everywhere
+    Text,
+    /// JSON document, substituted in string values
+    Json,
+    /// YAML documents, substituted in string scalars
+    Yaml,
+}
+
+/// Placeholder syntax of templates.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Syntax {
+    /// ${NAME}
+    Braces,
+    /// $NAME or ${NAME}, like shells
+    Dollar,
+    /// %NAME%, like Windows batch files
+    Percent,
+    /// {{NAME}}, like mustache templates
+    Mustache,
+}
+
+/// Byte order mark of outputs.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Bom {
+    /// Kept if the template has one
+    Preserve,
+    /// Always written, except in Latin-1
+    Add,
+    /// Never written
+    Remove,
+}
+
+/// Line endings of outputs.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Newline {
+    /// Dominant line endings of the template
+    Preserve,
+    /// Unix line endings
+    Lf,
+    /// Windows line endings
+    Crlf,
+}
+
+/// Format of archives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArchiveFormat {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+/// Attributes of input files copied to outputs.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Preserve {
+    /// Access and modification times
+    Timestamps,
+    /// Owner and group, if permitted
+    Ownership,
+}
+
+/// Output format of the `check` subcommand.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum CheckFormat {
+    /// Unresolved variables on stderr
+    Text,
+    /// SARIF log on stdout, also reporting malformed placeholders
+    Sarif,
+}
+
+/// Severity of lint diagnostics failing the `lint` subcommand.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Deny {
+    /// Warnings, besides errors
+    Warnings,
+}
+
+/// Output format of the `env` subcommand.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum EnvFormat {
+    /// 'export KEY='value'' lines, for eval or source
+    Export,
+    /// 'KEY='value'' lines, as a .env file
+    Dotenv,
+}
+
+/// Format of run reports.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ReportFormat {
+    /// JSON object
+    Json,
+}
+
+/// Output format of the `vars` subcommand.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum VarsFormat {
+    /// One variable per line, followed by its number of references
+    Text,
+    /// Object with the total and per template references of each variable
+    Json,
+    /// Skeleton .env file, using defaults and descriptions from `@var` comments
+    Env,
+}
+
+fn main() -> ExitCode {
+    let args = parse_args().unwrap_or_else(|e| {
+        // Usage errors are not told apart from other errors
+        if e.use_stderr() {
+            let _ = e.print();
+            std::process::exit(EXIT_ERROR.into());
+        }
+        e.exit()
+    });
+    let verbosity = match args.quiet {
+        true => QUIET,
+        false => NORMAL.saturating_add(args.verbose).min(DEBUG),
+    };
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+    let exit_zero_on_missing = args.exit_zero_on_missing;
+
+    let Err(e) = run(args) else {
+        return ExitCode::SUCCESS;
+    };
+    if e.is::<Unresolved>() {
+        if exit_zero_on_missing {
+            message!(NORMAL, "{}", e);
+            return ExitCode::SUCCESS;
+        }
+        eprintln!("Error: {}", redact(&e.to_string()));
+        return ExitCode::from(EXIT_UNRESOLVED);
+    }
+    eprintln!("Error: {}", redact(&e.to_string()));
+    match e.is::<ksubst::Error>() {
+        true => ExitCode::from(EXIT_INVALID),
+        false => ExitCode::from(EXIT_ERROR),
+    }
+}
+
+/// Parse arguments, preceded by the options of the project configuration.
+fn parse_args() -> Result<Args, clap::Error> {
+    let mut argv: Vec<std::ffi::OsString> = env::args_os().collect();
+    let args = Args::try_parse_from(&argv)?;
+    let config = match &args.config {
+        Some(path) => PathBuf::from(path),
+        None if Path::new(CONFIG_FILE).is_file() => PathBuf::from(CONFIG_FILE),
+        None if args.profile.is_some() => {
+            let message = format!("--profile requires a {} configuration\n", CONFIG_FILE);
+            return Err(clap::Error::raw(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                message,
+            ));
+        }
+        None => return Ok(args),
+    };
+
+    // Render options only apply to the render command
+    let (render, paths) = match &args.command {
+        None => (true, &args.render.paths),
+        Some(Command::Render(render_args)) => (true, &render_args.paths),
+        Some(_) => (false, &args.render.paths),
+    };
+    let options = config_options(&config, args.profile.as_deref(), render, paths.is_empty())
+        .map_err(|e| {
+            let message = format!("{}: {}\n", config.display(), e);
+            clap::Error::raw(clap::error::ErrorKind::InvalidValue, message)
+        })?;
+    let at = match args.command {
+        Some(Command::Render(_)) => argv
+            .iter()
+            .position(|arg| arg == "render")
+            .map_or(1, |i| i + 1),
+        _ => 1,
     };
+    argv.splice(at..at, options);
+    let matches = Args::command()
+        .args_override_self(true)
+        .try_get_matches_from(argv)?;
+    Args::from_arg_matches(&matches)
+}
+
+/// Arguments for the options of a project configuration and its `profile`.
+///
+/// Keys are long options, and their values are `true` for flags, or values
+/// of the option, lists giving it several times. The special `paths` key
+/// lists the paths to render if none are given.
+fn config_options(
+    path: &Path,
+    profile: Option<&str>,
+    render: bool,
+    paths: bool,
+) -> Result<Vec<std::ffi::OsString>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut config: toml::Table = content
+        .parse()
+        .map_err(|e: toml::de::Error| e.to_string())?;
+    let profiles = match config.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => return Err("'profiles' must be a table".to_string()),
+        None => toml::Table::new(),
+    };
+    if let Some(profile) = profile {
+        match profiles.get(profile) {
+            Some(toml::Value::Table(options)) => config.extend(options.clone()),
+            Some(_) => return Err(format!("profile '{}' must be a table", profile)),
+            None => {
+                let names: Vec<&str> = profiles.keys().map(String::as_str).collect();
+                return Err(format!(
+                    "unknown profile '{}', expected one of: {}",
+                    profile,
+                    names.join(", ")
+                ));
+            }
+        }
+    }
+
+    let command = Args::command();
+    let mut options = Vec::new();
+    let mut positionals = Vec::new();
+    for (key, value) in config {
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        let values = values
+            .into_iter()
+            .map(|value| match value {
+                toml::Value::String(s) => Ok(Some(s)),
+                toml::Value::Boolean(true) => Ok(None),
+                toml::Value::Integer(n) => Ok(Some(n.to_string())),
+                toml::Value::Float(n) => Ok(Some(n.to_string())),
+                _ => Err(format!("invalid value for '{}'", key)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if key == "paths" {
+            if render && paths {
+                positionals.extend(values.into_iter().flatten());
+            }
+            continue;
+        }
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+            .filter(|arg| !matches!(arg.get_id().as_str(), "config" | "profile"))
+            .ok_or_else(|| format!("unknown option '{}'", key))?;
+        if !render && !arg.is_global_set() {
+            continue;
+        }
+        for value in values {
+            options.push(match value {
+                Some(value) => format!("--{}={}", key, value),
+                None => format!("--{}", key),
+            });
+        }
+    }
+    options.extend(positionals);
+    Ok(options.into_iter().map(Into::into).collect())
+}
+
+fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let sources = &args.sources;
+    match args.command {
+        // Rendering is the default command
+        None => render(args.render, sources),
+        Some(Command::Render(render_args)) => render(*render_args, sources),
+        Some(Command::Serve { listen, workers }) => {
+            // Never expose the process environment to remote clients
+            let mut defaults = load_variables(sources, None)?.unwrap_or_default();
+            apply_defaults(&mut defaults, &sources.defaults);
+            serve(&listen, workers, &defaults)
+        }
+        Some(Command::Exec { command }) => exec(&command, sources),
+        Some(Command::Smudge) => git_filter(sources, None),
+        Some(Command::Clean { path }) => git_filter(sources, Some(&path)),
+        Some(Command::Env { file, format }) => render_env(
+            &file,
+            format,
+            &variables(sources)?,
+            &mut io::stdout().lock(),
+        ),
+        Some(Command::Hook {
+            hook:
+                HookCommand::PreCommit {
+                    paths,
+                    secret_pattern,
+                    secrets,
+                },
+        }) => {
+            let secrets: Vec<(String, String)> = variables(sources)?
+                .into_iter()
+                .filter(|(name, value)| {
+                    value.chars().count() >= MIN_SECRET_LEN
+                        && (secrets.contains(name) || secret_pattern.is_match(name))
+                })
+                .collect();
+            pre_commit(&paths, &secrets)
+        }
+        Some(Command::Check { paths, format }) => check(&paths, format, &variables(sources)?),
+        Some(Command::Lint { paths, deny }) => lint(&paths, deny),
+        Some(Command::Vars {
+            paths,
+            format,
+            per_file,
+        }) => list_vars(&paths, format, per_file),
+        Some(Command::Completions { shell }) => {
+            let mut command = Args::command();
+            clap_complete::generate(shell, &mut command, "ksubst", &mut io::stdout());
+            Ok(())
+        }
+        Some(Command::Man) => {
+            clap_mangen::Man::new(Args::command()).render(&mut io::stdout())?;
+            Ok(())
+        }
+    }
+}
+
+/// Substitute variables in templates, reading from stdin by default.
+fn render(args: RenderArgs, sources: &Sources) -> Result<(), Box<dyn std::error::Error>> {
+    let report = RunReport::new(args.report.is_some());
+    let result = render_templates(&args, sources, &report);
+    // Unresolved variables don't prevent finding unused ones
+    if args.unused
+        && result
+            .as_ref()
+            .map_or_else(|e| e.is::<Unresolved>(), |_| true)
+    {
+        let used = SUMMARY.variables.lock().unwrap();
+        let sourced = SOURCED.lock().unwrap();
+        let unused: Vec<&str> = sourced.difference(&used).map(String::as_str).collect();
+        if !unused.is_empty() {
+            message!(NORMAL, "unused variables: {}", unused.join(", "));
+        }
+    }
+    if args.report.is_some() {
+        report.emit(
+            args.report_file.as_deref(),
+            result.as_ref().err().map(|e| &**e),
+        )?;
+    }
+    if let (Some(path), Ok(())) = (&args.manifest, &result) {
+        let mut outputs = MANIFEST.lock().unwrap().clone();
+        outputs.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+        let manifest = serde_json::to_string_pretty(&serde_json::json!({ "outputs": outputs }))?;
+        write_output(Path::new(path), manifest + "\n", None)?;
+    }
+    result
+}
 
+/// Outputs listed by `--manifest`.
+static MANIFEST: Mutex<Vec<serde_json::Value>> = Mutex::new(Vec::new());
+
+/// Record an output in the manifest, if enabled, with the template it was
+/// rendered from, unless it was copied.
+fn record_output(
+    path: &str,
+    content: &[u8],
+    source: &str,
+    template: Option<&Template<'_>>,
+    args: &RenderArgs,
+) {
+    if args.manifest.is_none() {
+        return;
+    }
+    let mut variables = BTreeSet::new();
+    for segment in template.map_or_else(Vec::new, |t| ksubst::template::parse(t.body())) {
+        if let ksubst::template::Segment::Placeholder(p) = segment {
+            if template.is_some_and(|t| t.context.contains_key(p.name)) {
+                variables.insert(p.name);
+            }
+        }
+    }
+    MANIFEST.lock().unwrap().push(serde_json::json!({
+        "path": path,
+        "sha256": sha256_hex(content),
+        "template": source,
+        "variables": variables,
+    }));
+}
+
+fn render_templates(
+    args: &RenderArgs,
+    sources: &Sources,
+    report: &RunReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut variables = variables(sources)?;
+    register_secrets(&variables, args);
     if args.recursive {
         // Process directory recursively
-        let input_dir = args.input_dir.unwrap();
-        let output_dir = args.output_dir.unwrap();
+        let (output_dir, input_dirs) = match args.paths.split_last() {
+            // Archive entries are relative paths
+            _ if args.output_archive.is_some() || args.list_missing => ("", args.paths.as_slice()),
+            Some((output_dir, input_dirs)) => (output_dir.as_str(), input_dirs),
+            None => ("", [].as_slice()),
+        };
+        if input_dirs.is_empty() && args.input_archive.is_none() {
+            return Err(match args.output_archive {
+                Some(_) => "--output-archive requires input directories".into(),
+                None => "-r requires input and output directories".into(),
+            });
+        }
+        if output_dir.is_empty() && args.output_archive.is_none() && !args.list_missing {
+            return Err("-r requires an output directory".into());
+        }
 
         // Build exclude globset
         let exclude_globset = build_globset(&args.exclude_patterns)?;
@@ -71,82 +1199,3404 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         // Build filter globset
         let filter_globset = build_globset(&args.filter_patterns)?;
 
-        process_directory_recursively(
-            &input_dir,
-            &output_dir,
+        if args.clean {
+            clean_output_dir(input_dirs, output_dir, args.dry_run)?;
+        }
+        let start = Instant::now();
+        let state = !args.dry_run && !args.list_missing && args.output_archive.is_none();
+        if state {
+            load_state(output_dir)?;
+        }
+        let outputs = process_directory_recursively(
+            input_dirs,
+            output_dir,
             &variables,
             &exclude_globset,
             &filter_globset,
-        )?;
+            args,
+            report,
+        );
+        // Outputs written before a failure are recorded too
+        if state {
+            save_state(output_dir)?;
+        }
+        let outputs = outputs?;
+
+        if args.delete {
+            delete_stale_outputs(input_dirs, output_dir, &outputs, args.dry_run)?;
+        }
+        if !args.no_summary && !args.list_missing {
+            SUMMARY.print(start.elapsed());
+        }
+        if args.watch {
+            return watch(input_dirs, output_dir, variables, sources, args);
+        }
     } else {
+        if [&sources.vars_json, &sources.vars_yaml, &sources.vars_toml]
+            .iter()
+            .any(|path| path.as_deref() == Some("-"))
+        {
+            return Err("stdin can't be used for both variables and input".into());
+        }
+
+        // A SHELL-FORMAT is told apart from paths by its '$'
+        let mut paths = args.paths.as_slice();
+        if let Some((shell_format, rest)) = paths.split_first() {
+            if shell_format.contains('$') {
+                variables = restrict_variables(variables, shell_format);
+                paths = rest;
+            }
+        }
+
+        let mut paths = expand_globs(paths)?;
+        if let Some(list) = &args.files0_from {
+            let listed = read_files0(list)?;
+            if paths.is_empty() && listed.is_empty() {
+                // Don't fall back to stdin when nothing was found
+                return Ok(());
+            }
+            paths.extend(listed);
+        }
+        let paths = paths.as_slice();
+        if args.stream {
+            if let Some(path) = paths.first() {
+                return Err(format!("--stream reads from stdin, got '{}'", path).into());
+            }
+            return stream_stdin(&variables, args);
+        }
+        if let Some(suffix) = &args.in_place {
+            if paths.is_empty() {
+                return Err("-i requires files to substitute".into());
+            }
+            if let Some(url) = paths.iter().find(|path| is_url(path)) {
+                return Err(format!("-i can't substitute URLs, got '{}'", url).into());
+            }
+            let suffix = match (suffix.as_str(), &args.backup) {
+                ("", Some(backup)) => backup,
+                _ => suffix,
+            };
+            return substitute_in_place(paths, suffix, &variables, args, report);
+        }
+        if !paths.is_empty() {
+            return substitute_files(paths, args, &variables, report);
+        }
+
         // Read from stdin
-        let mut input = String::new();
-        io::stdin().read_to_string(&mut input)?;
+        let mut input = Vec::new();
+        io::stdin().read_to_end(&mut input)?;
+        if let Some(digest) = &args.sha256 {
+            verify_sha256("-", &input, digest)?;
+        }
+
+        let (input, encoding) = decode_input("-", input, args.encoding)?;
+        let template = Template::new("-", input, encoding, &variables, args)?;
+        if args.strict {
+            check_resolved([("-", template.body(), &*template.context)])?;
+        }
 
         // Perform substitution
-        let output = substitute(&input, &variables)?;
+        let start = Instant::now();
+        let mut output = template.substitute(&ksubst::Matcher::new(&variables)?)?;
+        check_templated("-", &output, &template.syntax, args)?;
+        report.record("-", template.body(), &template.context, start.elapsed());
+        SUMMARY.record(template.body(), &template.context);
 
-        // Write to stdout
-        println!("{}", output);
+        match &args.output {
+            Some(path) => {
+                let output = template.encode(&output, args)?;
+                record_output(path, &output, "-", Some(&template), args);
+                write_output(Path::new(path), output, args.backup.as_deref())?;
+            }
+            // Write to stdout, with a trailing newline
+            None => {
+                output.push('\n');
+                let output = template.encode(&output, args)?;
+                record_output("-", &output, "-", Some(&template), args);
+                io::stdout().write_all(&output)?;
+            }
+        }
     }
 
     Ok(())
 }
 
-fn build_globset(patterns: &[String]) -> Result<GlobSet, Box<dyn std::error::Error>> {
-    let mut builder = GlobSetBuilder::new();
-    for pattern in patterns {
-        let glob = Glob::new(pattern)?;
-        builder.add(glob);
+/// Read a NUL-separated list of paths from a file, or stdin if `path` is '-'.
+fn read_files0(path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut list = Vec::new();
+    match path {
+        "-" => {
+            io::stdin().read_to_end(&mut list)?;
+        }
+        _ => list = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?,
     }
-    Ok(builder.build()?)
+    list.split(|b| *b == 0)
+        .filter(|listed| !listed.is_empty())
+        .map(|listed| {
+            String::from_utf8(listed.to_vec()).map_err(|_| {
+                let listed = String::from_utf8_lossy(listed);
+                format!("{}: '{}' is not a valid UTF-8 path", path, listed).into()
+            })
+        })
+        .collect()
 }
 
-fn process_directory_recursively(
-    input_dir: &str,
-    output_dir: &str,
+/// Substitute stdin line by line, flushing each line once substituted.
+fn stream_stdin(
     variables: &HashMap<String, String>,
-    exclude_globset: &GlobSet,
-    filter_globset: &GlobSet,
+    args: &RenderArgs,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    for entry in WalkDir::new(input_dir) {
-        let entry = entry?;
-        let path = entry.path();
-
-        if path.is_file() {
-            // Get relative path
-            let relative_path = path.strip_prefix(input_dir)?;
-
-            // Check exclude patterns
-            if !exclude_globset.is_empty() && exclude_globset.is_match(relative_path) {
-                continue;
-            }
+    let matcher = ksubst::Matcher::new(variables)?;
+    let includes = IncludeDirs::new(args);
+    let mut stdin = io::stdin().lock();
+    let mut stdout = io::stdout().lock();
+    let syntax = template_syntax(args);
+    let mut line = String::new();
+    while stdin.read_line(&mut line)? > 0 {
+        let template = syntax.translate(&ksubst::expand_includes(&line, &includes)?);
+        let mut output = syntax.restore(&matcher.substitute(template)?);
+        check_templated("-", &output, &syntax, args)?;
+        if args.newline != Newline::Preserve {
+            output = convert_newlines(&output, args.newline == Newline::Crlf);
+        }
+        stdout.write_all(output.as_bytes())?;
+        stdout.flush()?;
+        line.clear();
+    }
+    Ok(())
+}
 
-            // If filter patterns are specified, only process files that match the filter patterns
-            if !filter_globset.is_empty() && !filter_globset.is_match(relative_path) {
-                continue;
-            }
+/// Load variables, falling back to environment variables, and apply defaults.
+fn variables(sources: &Sources) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let (mut variables, _) = source_variables(sources)?;
+    apply_defaults(&mut variables, &sources.defaults);
+    message!(DEBUG, "{} variables available", variables.len());
+    Ok(variables)
+}
 
-            // Read file content
-            let input_content = std::fs::read_to_string(path)?;
+/// Load variables with environment variables according to `--process-env`,
+/// without defaults, and whether other sources are given.
+fn source_variables(
+    sources: &Sources,
+) -> Result<(HashMap<String, String>, bool), Box<dyn std::error::Error>> {
+    let environment = || {
+        message!(VERBOSE, "using environment variables");
+        environment_variables(sources.prefix.as_deref())
+    };
+    let layered = match sources.process_env {
+        ProcessEnv::Base | ProcessEnv::Override => Some(environment()),
+        ProcessEnv::Auto | ProcessEnv::Never => None,
+    };
+    match load_variables(sources, layered)? {
+        Some(vars) => Ok((vars, true)),
+        None if sources.process_env == ProcessEnv::Never => Ok((HashMap::new(), false)),
+        None => Ok((environment(), false)),
+    }
+}
 
-            // Perform substitution
-            let output_content = substitute(&input_content, variables)?;
+/// Load variables from explicitly provided sources, if any, with
+/// `environment` variables at their `--process-env` precedence.
+///
+/// From lowest to highest precedence, sources are .env files and file
+/// descriptors, variables files, --env-vars, --env-overrides-file files and
+/// --env.
+fn load_variables(
+    sources: &Sources,
+    mut environment: Option<HashMap<String, String>>,
+) -> Result<Option<HashMap<String, String>>, Box<dyn std::error::Error>> {
+    if sources.env_file.is_empty()
+        && sources.vars_fd.is_empty()
+        && sources.vars_json.is_none()
+        && sources.vars_yaml.is_none()
+        && sources.vars_toml.is_none()
+        && sources.env_vars.is_none()
+        && sources.env_overrides_file.is_empty()
+        && sources.env.is_empty()
+    {
+        return Ok(None);
+    }
 
-            // Compute output path
-            let output_path = Path::new(output_dir).join(relative_path);
+    let mut vars = HashMap::new();
+    let mut sourced = BTreeSet::new();
+    if sources.process_env == ProcessEnv::Base {
+        vars.extend(environment.take().unwrap_or_default());
+    }
 
-            // Create parent directories if needed
-            if let Some(parent) = output_path.parent() {
-                std::fs::create_dir_all(parent)?;
-            }
+    // Load variables from .env files, later ones overriding earlier ones
+    let descriptors = sources.vars_fd.iter().map(|fd| format!("/dev/fd/{}", fd));
+    for env_file in sources.env_file.iter().cloned().chain(descriptors) {
+        let entries = read_env_file(&env_file, &vars)?;
+        let count = entries.len();
+        sourced.extend(entries.keys().cloned());
+        vars.extend(entries);
+        message!(VERBOSE, "loaded variables from {}", env_file);
+        message!(DEBUG, "{} variables in {}", count, env_file);
+    }
 
-            // Write output file
-            std::fs::write(output_path, output_content)?;
-        }
+    // Load structured variables, deep-merging later files into earlier ones
+    let mut context = ksubst::Context::new();
+    if let Some(path) = &sources.vars_json {
+        let value = serde_json::from_str(&read_source(path)?)
+            .map_err(|e| format!("failed to parse JSON variables from '{}': {}", path, e))?;
+        context.merge(ksubst::Context::from_json(value)?, ListMerge::Replace);
+        message!(VERBOSE, "loaded JSON variables from {}", path);
+    }
+    if let Some(path) = &sources.vars_yaml {
+        let value = serde_yaml::from_str(&read_source(path)?)
+            .map_err(|e| format!("failed to parse YAML variables from '{}': {}", path, e))?;
+        context.merge(ksubst::Context::from_json(value)?, ListMerge::Replace);
+        message!(VERBOSE, "loaded YAML variables from {}", path);
+    }
+    if let Some(path) = &sources.vars_toml {
+        let value = toml::from_str(&read_source(path)?)
+            .map_err(|e| format!("failed to parse TOML variables from '{}': {}", path, e))?;
+        context.merge(ksubst::Context::from_json(value)?, ListMerge::Replace);
+        message!(VERBOSE, "loaded TOML variables from {}", path);
+    }
+    for (key, value) in context.vars() {
+        sourced.insert(key.to_string());
+        vars.insert(key.into_owned(), value.into_owned());
+    }
+    if sources.process_env == ProcessEnv::Override {
+        vars.extend(environment.take().unwrap_or_default());
     }
 
-    Ok(())
+    // Parse variables from command-line arguments
+    if let Some(env_vars_str) = &sources.env_vars {
+        let entries = parse_env_vars(env_vars_str)?;
+        sourced.extend(entries.keys().cloned());
+        vars.extend(entries);
+        message!(VERBOSE, "loaded variables from --env-vars");
+    }
+    for env_file in &sources.env_overrides_file {
+        let entries = read_env_file(env_file, &vars)?;
+        sourced.extend(entries.keys().cloned());
+        vars.extend(entries);
+        message!(VERBOSE, "loaded overriding variables from {}", env_file);
+    }
+    for (key, value) in &sources.env {
+        sourced.insert(key.clone());
+        vars.insert(key.clone(), read_value(key, value)?);
+    }
+    if !sources.env.is_empty() {
+        message!(VERBOSE, "loaded variables from --env");
+    }
+    SOURCED.lock().unwrap().extend(sourced);
+    message!(DEBUG, "{} variables loaded", vars.len());
+    Ok(Some(vars))
+}
+
+/// Marker of references in .env values, a noncharacter never found in text.
+const ENV_REFERENCE: char = '\u{fdd0}';
+
+/// Read the entries of a .env file, expanding references between them.
+///
+/// References to variables which are not entries of the file are resolved
+/// from `loaded` variables, then from the environment, and are empty if
+/// unset. Entries referencing themselves, like 'PATH=$PATH:/bin', get the
+/// value they override.
+fn read_env_file(
+    path: &str,
+    loaded: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read variables from '{}': {}", path, e))?;
+    let marked = mark_env_references(&content);
+    let mut entries = HashMap::new();
+    for (key, value) in dotenvy::from_read_iter(marked.as_bytes()).filter_map(Result::ok) {
+        entries.insert(key, value);
+    }
+    let mut resolved = HashMap::new();
+    for name in entries.keys() {
+        resolve_env_entry(path, name, &entries, loaded, &mut resolved, &mut Vec::new())?;
+    }
+    Ok(resolved)
+}
+
+/// Replace the `$` of references in .env content with [`ENV_REFERENCE`],
+/// so they are kept from being expanded by the parser.
+fn mark_env_references(content: &str) -> String {
+    let mut marked = String::with_capacity(content.len());
+    let (mut quote, mut escaped, mut comment, mut blank) = (None, false, false, true);
+    for c in content.chars() {
+        match (quote, c) {
+            _ if comment || escaped => {
+                comment &= c != '\n';
+                escaped = false;
+            }
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (_, '$') => {
+                marked.push(ENV_REFERENCE);
+                continue;
+            }
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '#') => comment = blank,
+            _ => {}
+        }
+        blank = quote.is_none() && c.is_whitespace();
+        marked.push(c);
+    }
+    marked
+}
+
+/// Resolve an entry of a .env file, given the stack of entries referencing it.
+fn resolve_env_entry(
+    path: &str,
+    name: &str,
+    entries: &HashMap<String, String>,
+    loaded: &HashMap<String, String>,
+    resolved: &mut HashMap<String, String>,
+    stack: &mut Vec<String>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(value) = resolved.get(name) {
+        return Ok(value.clone());
+    }
+    let outer = || {
+        let value = loaded.get(name).cloned();
+        value.or_else(|| env::var(name).ok()).unwrap_or_default()
+    };
+    let Some(raw) = entries.get(name) else {
+        return Ok(outer());
+    };
+    if stack.iter().any(|referencing| referencing == name) {
+        stack.push(name.to_string());
+        return Err(format!("variables cycle in '{}': {}", path, stack.join(" -> ")).into());
+    }
+
+    stack.push(name.to_string());
+    let mut value = String::with_capacity(raw.len());
+    let mut rest = raw.as_str();
+    while let Some(start) = rest.find(ENV_REFERENCE) {
+        value.push_str(&rest[..start]);
+        rest = &rest[start + ENV_REFERENCE.len_utf8()..];
+        let (reference, len) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+        if reference.is_empty() {
+            value.push('$');
+            continue;
+        }
+        rest = &rest[len..];
+        value += &match reference == name {
+            true => outer(),
+            false => resolve_env_entry(path, reference, entries, loaded, resolved, stack)?,
+        };
+    }
+    value.push_str(rest);
+    stack.pop();
+    resolved.insert(name.to_string(), value.clone());
+    Ok(value)
+}
+
+/// Read a variables file, or stdin if path is '-'.
+fn read_source(path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if path == "-" {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        Ok(input)
+    } else {
+        std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read variables from '{}': {}", path, e).into())
+    }
+}
+
+/// Restrict variables to the ones listed in a GNU envsubst SHELL-FORMAT.
+///
+/// Listed variables which are not set are substituted with an empty value.
+fn restrict_variables(
+    variables: HashMap<String, String>,
+    shell_format: &str,
+) -> HashMap<String, String> {
+    let pattern =
+        regex::Regex::new(r"\$(?:\{([A-Za-z_][A-Za-z0-9_]*)\}|([A-Za-z_][A-Za-z0-9_]*))").unwrap();
+    pattern
+        .captures_iter(shell_format)
+        .filter_map(|caps| caps.get(1).or_else(|| caps.get(2)))
+        .map(|name| {
+            let value = variables.get(name.as_str()).cloned().unwrap_or_default();
+            (name.as_str().to_string(), value)
+        })
+        .collect()
+}
+
+/// Collect environment variables, only keeping the ones starting with
+/// `prefix` if any, with the prefix stripped.
+fn environment_variables(prefix: Option<&str>) -> HashMap<String, String> {
+    let environment: HashMap<String, String> = env::vars().collect();
+    match prefix {
+        Some(prefix) => ksubst::Prefixed::new(&environment, prefix)
+            .vars()
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect(),
+        None => environment,
+    }
+}
+
+/// Set default values of variables which are not set.
+fn apply_defaults(variables: &mut HashMap<String, String>, defaults: &[(String, String)]) {
+    for (key, value) in defaults {
+        variables
+            .entry(key.clone())
+            .or_insert_with(|| value.clone());
+    }
+}
+
+/// Expand glob patterns among input paths, in file name order.
+///
+/// Existing paths are kept as-is, even if they contain glob characters.
+fn expand_globs(paths: &[String]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let is_glob = |s: &str| s.contains(['*', '?', '[', '{']);
+    let mut expanded = Vec::new();
+    for path in paths {
+        if !is_glob(path) || is_url(path) || Path::new(path).exists() {
+            expanded.push(path.clone());
+            continue;
+        }
+
+        let glob = GlobBuilder::new(path)
+            .literal_separator(true)
+            .build()?
+            .compile_matcher();
+        // Only walk the directory before the first glob component
+        let base: PathBuf = Path::new(path)
+            .components()
+            .take_while(|c| !is_glob(&c.as_os_str().to_string_lossy()))
+            .collect();
+        let walk_root = if base.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            base.as_path()
+        };
+
+        let count = expanded.len();
+        for entry in WalkDir::new(walk_root).sort_by_file_name() {
+            let entry = entry?;
+            let candidate = if base.as_os_str().is_empty() {
+                entry.path().strip_prefix(".")?
+            } else {
+                entry.path()
+            };
+            if entry.file_type().is_file() && glob.is_match(candidate) {
+                expanded.push(candidate.to_string_lossy().into_owned());
+            }
+        }
+        if expanded.len() == count {
+            return Err(format!("no files match '{}'", path).into());
+        }
+    }
+    Ok(expanded)
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet, Box<dyn std::error::Error>> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern)?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+/// Input file of a recursive run.
+struct InputFile<'a> {
+    source: Source<'a>,
+    relative_path: PathBuf,
+    /// Output path, relative to the output directory.
+    output_path: PathBuf,
+    /// Whether this is a symbolic link to recreate.
+    link: bool,
+    selected: bool,
+    copied: bool,
+}
+
+/// Where the content of an input file comes from.
+enum Source<'a> {
+    /// File of an input directory.
+    Dir(&'a str),
+    /// Entry of the input archive.
+    Entry(ArchiveEntry),
+}
+
+/// Entry of an input archive, read in memory.
+struct ArchiveEntry {
+    content: Vec<u8>,
+    attributes: Attributes,
+    /// Target of the entry, if it is a symbolic link.
+    link: Option<PathBuf>,
+}
+
+/// Attributes of an input file, copied to its output.
+#[derive(Clone, Debug, Default)]
+struct Attributes {
+    permissions: Option<std::fs::Permissions>,
+    accessed: Option<SystemTime>,
+    modified: Option<SystemTime>,
+    /// Owner and group IDs.
+    owner: Option<(u32, u32)>,
+}
+
+impl From<&std::fs::Metadata> for Attributes {
+    fn from(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let owner = {
+            use std::os::unix::fs::MetadataExt;
+            Some((metadata.uid(), metadata.gid()))
+        };
+        #[cfg(not(unix))]
+        let owner = None;
+        Self {
+            permissions: Some(metadata.permissions()),
+            accessed: metadata.accessed().ok(),
+            modified: metadata.modified().ok(),
+            owner,
+        }
+    }
+}
+
+impl Attributes {
+    /// Unix permission bits, if known.
+    fn mode(&self) -> Option<u32> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            self.permissions.as_ref().map(|p| p.mode() & 0o7777)
+        }
+        #[cfg(not(unix))]
+        {
+            None
+        }
+    }
+}
+
+/// Permissions from Unix permission bits, on platforms supporting them.
+fn mode_permissions(mode: u32) -> Option<std::fs::Permissions> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(std::fs::Permissions::from_mode(mode & 0o7777))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = mode;
+        None
+    }
+}
+
+impl InputFile<'_> {
+    /// Read the content and attributes of this input.
+    fn read(&self) -> io::Result<(Vec<u8>, Attributes)> {
+        match &self.source {
+            Source::Dir(input_dir) => {
+                let path = Path::new(input_dir).join(&self.relative_path);
+                let content = std::fs::read(&path)?;
+                Ok((content, (&std::fs::metadata(&path)?).into()))
+            }
+            Source::Entry(entry) => Ok((entry.content.clone(), entry.attributes.clone())),
+        }
+    }
+
+    /// Target of this input, which is a symbolic link.
+    fn read_link(&self) -> io::Result<PathBuf> {
+        match &self.source {
+            Source::Dir(input_dir) => {
+                std::fs::read_link(Path::new(input_dir).join(&self.relative_path))
+            }
+            Source::Entry(entry) => Ok(entry.link.clone().unwrap_or_default()),
+        }
+    }
+}
+
+/// Name of per-directory ignore files, in gitignore syntax, applying to
+/// recursive walks of the directory and its subdirectories.
+const IGNORE_FILE: &str = ".ksubstignore";
+
+/// Render input directories into an output directory, files of later
+/// input directories overriding those of earlier ones, and those of the
+/// input archive.
+///
+/// Returns the paths of outputs relative to the output directory.
+fn process_directory_recursively(
+    input_dirs: &[String],
+    output_dir: &str,
+    variables: &HashMap<String, String>,
+    exclude_globset: &GlobSet,
+    filter_globset: &GlobSet,
+    args: &RenderArgs,
+    report: &RunReport,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    // Variables are shared across all files
+    let matcher = ksubst::Matcher::new(variables)?;
+    let inputs = list_inputs(input_dirs, exclude_globset, filter_globset, &matcher, args)?;
+    render_inputs(inputs, output_dir, &matcher, variables, args, report)
+}
+
+/// List the inputs of a recursive run, by relative path, files of later
+/// input directories overriding those of earlier ones.
+fn list_inputs<'a>(
+    input_dirs: &'a [String],
+    exclude_globset: &GlobSet,
+    filter_globset: &GlobSet,
+    matcher: &ksubst::Matcher<'_>,
+    args: &RenderArgs,
+) -> Result<BTreeMap<PathBuf, InputFile<'a>>, Box<dyn std::error::Error>> {
+    // Files are listed first, to be processed in parallel
+    let mut inputs = BTreeMap::new();
+    if let Some((archive, format)) = &args.input_archive {
+        for (relative_path, entry) in read_archive(archive, *format)? {
+            let hidden = relative_path
+                .components()
+                .any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+            let depth = relative_path.components().count();
+            if (hidden && !args.hidden) || args.max_depth.is_some_and(|max| depth > max) {
+                continue;
+            }
+            let link = entry.link.is_some();
+            let input = select_input(
+                Source::Entry(entry),
+                relative_path,
+                link,
+                exclude_globset,
+                filter_globset,
+                matcher,
+                args,
+            )?;
+            if let Some(input) = input {
+                inputs.insert(input.relative_path.clone(), input);
+            }
+        }
+    }
+    for input_dir in input_dirs {
+        let walker = ignore::WalkBuilder::new(input_dir)
+            .standard_filters(false)
+            .hidden(!args.hidden)
+            .max_depth(args.max_depth)
+            .git_ignore(!args.no_ignore)
+            .git_global(!args.no_ignore)
+            .git_exclude(!args.no_ignore)
+            .parents(!args.no_ignore)
+            .add_custom_ignore_filename(IGNORE_FILE)
+            .follow_links(args.follow_symlinks)
+            .build();
+        for entry in walker {
+            let entry = match entry {
+                Err(e) if is_symlink_loop(&e) => {
+                    message!(NORMAL, "skipping symbolic link loop: {}", e);
+                    continue;
+                }
+                entry => entry?,
+            };
+            // Get relative path
+            let relative_path = entry.path().strip_prefix(input_dir)?.to_path_buf();
+            // Links are only reported as such when they are not followed
+            let link = entry.depth() > 0 && entry.file_type().is_some_and(|t| t.is_symlink());
+            if (!link && !entry.path().is_file()) || entry.file_name() == IGNORE_FILE {
+                continue;
+            }
+            let input = select_input(
+                Source::Dir(input_dir),
+                relative_path,
+                link,
+                exclude_globset,
+                filter_globset,
+                matcher,
+                args,
+            )?;
+            if let Some(input) = input {
+                inputs.insert(input.relative_path.clone(), input);
+            }
+        }
+    }
+    Ok(inputs)
+}
+
+/// Render the listed inputs of a recursive run into an output directory.
+///
+/// Returns the paths of outputs relative to the output directory.
+fn render_inputs(
+    inputs: BTreeMap<PathBuf, InputFile<'_>>,
+    output_dir: &str,
+    matcher: &ksubst::Matcher<'_>,
+    variables: &HashMap<String, String>,
+    args: &RenderArgs,
+    report: &RunReport,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let (links, files): (Vec<_>, Vec<_>) = inputs.into_values().partition(|input| input.link);
+    let outputs = files
+        .iter()
+        .chain(&links)
+        .filter(|input| input.selected || input.copied)
+        .map(|input| input.output_path.clone())
+        .collect();
+    if args.list_missing {
+        let selected: Vec<&InputFile> = files.iter().filter(|input| input.selected).collect();
+        list_missing(&selected, variables, args)?;
+        return Ok(outputs);
+    }
+
+    let progress = match args.progress {
+        When::Always => true,
+        When::Never => false,
+        When::Auto => io::stderr().is_terminal(),
+    } && VERBOSITY.load(Ordering::Relaxed) > QUIET;
+    let total = files.iter().filter(|input| input.selected).count();
+    let progress = Progress::new(total, progress);
+    let results = parallel_map(&files, args.jobs, |input| {
+        let relative_path = &input.relative_path;
+        let output_path = Path::new(output_dir).join(&input.output_path);
+        if input.copied {
+            let (content, attributes) = input.read()?;
+            return copy_verbatim(&attributes, relative_path, output_path, content, args);
+        }
+        if !input.selected {
+            if args.dry_run {
+                println!("skip {}", relative_path.display());
+            }
+            message!(VERBOSE, "skipped {}", relative_path.display());
+            SUMMARY.skipped.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        }
+        let result = render_file(input, output_path, matcher, variables, args, report);
+        progress.file_done(relative_path);
+        result
+    });
+    progress.finish();
+    // In strict mode, outputs are only written once all files are resolved,
+    // or all outputs are checked with --fail-if-templated
+    let mut pending = Vec::new();
+    for result in results {
+        if let Some(rendered) = result.map_err(|e| e as Box<dyn std::error::Error>)? {
+            pending.push(rendered);
+        }
+    }
+
+    if args.strict {
+        check_resolved(pending.iter().map(|(_, relative_path, template, _, _)| {
+            (relative_path.display(), template.body(), &*template.context)
+        }))?;
+    }
+    if let Some((archive, format)) = &args.output_archive {
+        write_archive(Path::new(archive), *format, &pending, &links, args)?;
+        return Ok(outputs);
+    }
+    if (args.strict || args.fail_if_templated) && !args.dry_run {
+        // Nothing is written if some output file can't be overwritten
+        let pending: Vec<_> = pending
+            .into_iter()
+            .map(
+                |(attributes, relative_path, _, output_path, output_content)| {
+                    let write = may_overwrite(&attributes, &output_path, &output_content, args)?;
+                    Ok((
+                        attributes,
+                        relative_path,
+                        output_path,
+                        output_content,
+                        write,
+                    ))
+                },
+            )
+            .collect::<io::Result<_>>()?;
+        for (attributes, relative_path, output_path, output_content, write) in pending {
+            if write {
+                write_tree_output(
+                    &attributes,
+                    &relative_path,
+                    &output_path,
+                    output_content,
+                    args,
+                    "rendered",
+                )?;
+            }
+        }
+    }
+
+    for input in links {
+        let relative_path = &input.relative_path;
+        let target = input.read_link()?;
+        if args.dry_run {
+            println!("link {} -> {}", relative_path.display(), target.display());
+        } else {
+            let output_path = Path::new(output_dir).join(&input.output_path);
+            if args.restrict_root {
+                check_under_root(output_path.parent().unwrap(), Path::new(output_dir))?;
+            }
+            write_link(&output_path, &target)?;
+            message!(VERBOSE, "linked {}", relative_path.display());
+        }
+    }
+
+    Ok(outputs)
+}
+
+/// Select an input file of a recursive run, according to filters and
+/// template extensions.
+///
+/// Returns `None` for symbolic links which are not selected.
+fn select_input<'a>(
+    source: Source<'a>,
+    relative_path: PathBuf,
+    link: bool,
+    exclude_globset: &GlobSet,
+    filter_globset: &GlobSet,
+    matcher: &ksubst::Matcher<'_>,
+    args: &RenderArgs,
+) -> Result<Option<InputFile<'a>>, Box<dyn std::error::Error>> {
+    let mut selected = is_selected(&relative_path, exclude_globset, filter_globset);
+    let mut copied = !link
+        && !selected
+        && args.copy_unmatched
+        && is_selected(&relative_path, exclude_globset, &GlobSet::empty());
+    if link && !selected {
+        return Ok(None);
+    }
+    let template_path = strip_template_ext(&relative_path, &args.template_exts);
+    if !link && selected && args.templates_only && template_path.is_none() {
+        (selected, copied) = (false, true);
+    }
+    let output_path = template_path.as_deref().unwrap_or(&relative_path);
+    let output_path = render_path(output_path, matcher)?;
+    Ok(Some(InputFile {
+        source,
+        relative_path,
+        output_path,
+        link,
+        selected,
+        copied,
+    }))
+}
+
+/// Strip the first matching template extension from a path, if any.
+fn strip_template_ext(path: &Path, extensions: &[String]) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    extensions.iter().find_map(|ext| {
+        let ext = ext.strip_prefix('.').unwrap_or(ext);
+        let stem = name.strip_suffix(ext)?.strip_suffix('.')?;
+        Some(path.with_file_name(stem)).filter(|_| !stem.is_empty())
+    })
+}
+
+/// Substitute variables in the components of a relative path.
+///
+/// Substituted components must be file or directory names, so that
+/// outputs can't escape the output directory.
+fn render_path(
+    relative_path: &Path,
+    matcher: &ksubst::Matcher<'_>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut rendered = PathBuf::new();
+    for component in relative_path.components() {
+        let name = component.as_os_str();
+        match name.to_str() {
+            Some(name) if name.contains("${") => {
+                for segment in ksubst::template::parse(name) {
+                    if let ksubst::template::Segment::Placeholder(p) = segment {
+                        SUMMARY.variables.lock().unwrap().insert(p.name.to_string());
+                    }
+                }
+                let name = matcher.substitute(name)?;
+                if matches!(name.as_str(), "" | "." | "..") || name.contains(['/', '\\']) {
+                    return Err(format!(
+                        "{}: invalid output path component '{}'",
+                        relative_path.display(),
+                        name
+                    )
+                    .into());
+                }
+                rendered.push(name);
+            }
+            _ => rendered.push(name),
+        }
+    }
+    Ok(rendered)
+}
+
+/// Rendered file, as its input attributes, relative path, template, output
+/// path and output.
+type RenderedFile<'a> = (Attributes, PathBuf, Template<'a>, PathBuf, Vec<u8>);
+
+/// Template read from an input, with its own context if it has front matter.
+struct Template<'a> {
+    /// Encoding the template was read from.
+    encoding: Encoding,
+    /// Whether the template starts with a byte order mark.
+    bom: bool,
+    /// Whether the template mostly has CRLF line endings, if it has any.
+    crlf: Option<bool>,
+    /// Template without its front matter, with includes expanded.
+    body: String,
+    /// Template parsed as a structured document, with `--format`.
+    document: Option<Document>,
+    /// Placeholder syntax the body was translated from.
+    syntax: ksubst::Syntax,
+    /// Variables of the template, with its front matter defaults.
+    context: Cow<'a, HashMap<String, String>>,
+}
+
+impl<'a> Template<'a> {
+    /// Read the front matter of a template, if enabled, and expand its
+    /// includes.
+    fn new(
+        name: impl std::fmt::Display,
+        mut content: String,
+        encoding: Encoding,
+        variables: &'a HashMap<String, String>,
+        args: &RenderArgs,
+    ) -> Result<Self, String> {
+        let with_name = |e: ksubst::Error| format!("{}: {}", name, e);
+        let bom = content.starts_with('\u{feff}');
+        if bom {
+            content.remove(0);
+        }
+        let crlf = crlf_dominant(&content);
+        let (front_matter, body) = match args.front_matter {
+            true => ksubst::FrontMatter::split(&content).map_err(with_name)?,
+            false => (None, content.as_str()),
+        };
+        let context = match front_matter {
+            Some(front_matter) => Cow::Owned(front_matter.context(variables).map_err(with_name)?),
+            None => Cow::Borrowed(variables),
+        };
+        let body = ksubst::expand_includes(body, &IncludeDirs::new(args)).map_err(with_name)?;
+        let syntax = template_syntax(args);
+        let body = syntax.translate(&body);
+        let document = Document::parse(&body, args).map_err(|e| format!("{}: {}", name, e))?;
+        let context = match args.interactive {
+            true => prompt_missing(&body, context, args)?,
+            false => context,
+        };
+        if let Cow::Owned(context) = &context {
+            register_secrets(context, args);
+        }
+        Ok(Self {
+            encoding,
+            bom,
+            crlf,
+            body,
+            document,
+            syntax,
+            context,
+        })
+    }
+
+    /// Encode an output of the template, in the template encoding and with
+    /// its byte order mark and line endings, unless others are requested.
+    fn encode(&self, output: &str, args: &RenderArgs) -> Result<Vec<u8>, String> {
+        let encoding = match args.output_encoding {
+            Encoding::Auto => self.encoding,
+            encoding => encoding,
+        };
+        let utf16 = matches!(encoding, Encoding::Utf16Le | Encoding::Utf16Be);
+        let bom = encoding != Encoding::Latin1
+            && match args.bom {
+                Bom::Preserve => self.bom || (utf16 && encoding != self.encoding),
+                Bom::Add => true,
+                Bom::Remove => false,
+            };
+        let crlf = match args.newline {
+            Newline::Preserve => self.crlf,
+            Newline::Lf => Some(false),
+            Newline::Crlf => Some(true),
+        };
+
+        let mut bytes = match bom {
+            true => encoding.encode("\u{feff}")?,
+            false => Vec::new(),
+        };
+        match crlf {
+            Some(crlf) => bytes.extend(encoding.encode(&convert_newlines(output, crlf))?),
+            None => bytes.extend(encoding.encode(output)?),
+        }
+        Ok(bytes)
+    }
+
+    /// Template without its front matter, with includes expanded, in the
+    /// `${...}` syntax.
+    fn body(&self) -> &str {
+        &self.body
+    }
+
+    /// Substitute the template, with `matcher` unless it has its own context.
+    fn substitute(&self, matcher: &ksubst::Matcher<'_>) -> Result<String, ksubst::Error> {
+        let output = match (&self.document, &self.context) {
+            (Some(document), _) => document.substitute(&self.context)?,
+            (None, Cow::Borrowed(_)) => matcher.substitute(self.body())?,
+            (None, Cow::Owned(context)) => substitute(self.body(), context)?,
+        };
+        Ok(self.syntax.restore(&output))
+    }
+}
+
+/// Values given to interactive prompts, asked once per run.
+static PROMPTED: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Prompt for the variables used by a template but missing from its context.
+fn prompt_missing<'a>(
+    template: &str,
+    context: Cow<'a, HashMap<String, String>>,
+    args: &RenderArgs,
+) -> Result<Cow<'a, HashMap<String, String>>, String> {
+    let mut missing = Vec::new();
+    for segment in ksubst::template::parse(template) {
+        if let ksubst::template::Segment::Placeholder(p) = segment {
+            if !context.contains_key(p.name) && !missing.contains(&p.name) {
+                missing.push(p.name);
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(context);
+    }
+
+    // Prompts of concurrently rendered files are not interleaved
+    let mut prompted = PROMPTED.lock().unwrap();
+    let metadata = ksubst::variables_metadata(template);
+    let mut context = context.into_owned();
+    for name in missing {
+        if let Some(value) = prompted.get(name) {
+            context.insert(name.to_string(), value.clone());
+            continue;
+        }
+        let info = metadata.iter().rev().find(|info| info.name == name);
+        let mut prompt = name.to_string();
+        if let Some(description) = info.and_then(|info| info.description.as_deref()) {
+            prompt += &format!(" ({})", description);
+        }
+        let default = info.and_then(|info| info.default.as_deref());
+        if let Some(default) = default {
+            prompt += &format!(" [{}]", default);
+        }
+        let value = prompt_value(&prompt, is_secret(name, args))
+            .map_err(|e| format!("failed to prompt for {}: {}", name, e))?;
+        let value = match (value.is_empty(), default) {
+            (true, Some(default)) => default.to_string(),
+            _ => value,
+        };
+        prompted.insert(name.to_string(), value.clone());
+        context.insert(name.to_string(), value);
+    }
+    Ok(Cow::Owned(context))
+}
+
+/// Read a line from the terminal, not echoing it if `hidden`.
+///
+/// Input is only hidden on Unix.
+fn prompt_value(prompt: &str, hidden: bool) -> io::Result<String> {
+    #[cfg(unix)]
+    let (input, mut output) = {
+        let tty = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")?;
+        (tty.try_clone()?, tty)
+    };
+    #[cfg(not(unix))]
+    let (input, mut output) = (std::fs::File::open("CONIN$")?, io::stderr());
+
+    write!(output, "{}: ", prompt)?;
+    output.flush()?;
+    let echo = |enabled: bool| -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            let mode = if enabled { "echo" } else { "-echo" };
+            std::process::Command::new("stty")
+                .arg(mode)
+                .stdin(input.try_clone()?)
+                .status()?;
+        }
+        #[cfg(not(unix))]
+        let _ = enabled;
+        Ok(())
+    };
+    if hidden {
+        echo(false)?;
+    }
+    let mut line = String::new();
+    let read = io::BufReader::new(&input).read_line(&mut line);
+    if hidden {
+        echo(true)?;
+        writeln!(output)?;
+    }
+    if read? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "no value given",
+        ));
+    }
+    Ok(line.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Structured template, with the layout to serialize it back.
+enum Document {
+    Json {
+        value: serde_json::Value,
+        /// Indentation, or `None` for documents on a single line.
+        indent: Option<String>,
+        /// Whether the template ends with a line ending.
+        newline: bool,
+        keys: bool,
+    },
+    Yaml {
+        documents: Vec<serde_yaml::Value>,
+        /// Whether the first document starts with a `---` line.
+        explicit_start: bool,
+        keys: bool,
+        /// Skipped paths of Kubernetes manifests, with `--k8s`.
+        manifest_skip: Option<Vec<Vec<String>>>,
+    },
+}
+
+impl Document {
+    /// Parse a template according to `--format`.
+    fn parse(body: &str, args: &RenderArgs) -> Result<Option<Self>, String> {
+        let newline = body.ends_with('\n');
+        let format = match args.k8s {
+            true => Format::Yaml,
+            false => args.format,
+        };
+        match format {
+            Format::Text => Ok(None),
+            Format::Json => {
+                let value =
+                    serde_json::from_str(body).map_err(|e| format!("invalid JSON: {}", e))?;
+                // Indentation is that of the first nested line
+                let body = body.trim();
+                let indent = body.contains('\n').then(|| {
+                    let line = body.lines().skip(1).find(|line| !line.trim().is_empty());
+                    match line.map(|line| &line[..line.len() - line.trim_start().len()]) {
+                        Some(indent) if !indent.is_empty() => indent.to_string(),
+                        _ => "  ".to_string(),
+                    }
+                });
+                Ok(Some(Document::Json {
+                    value,
+                    indent,
+                    newline,
+                    keys: args.substitute_keys,
+                }))
+            }
+            Format::Yaml => {
+                let documents = serde_yaml::Deserializer::from_str(body)
+                    .map(<serde_yaml::Value as serde::Deserialize>::deserialize)
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("invalid YAML: {}", e))?;
+                Ok(Some(Document::Yaml {
+                    documents,
+                    explicit_start: body.trim_start().starts_with("---"),
+                    keys: args.substitute_keys,
+                    manifest_skip: args.k8s.then(|| {
+                        let split = |path: &String| path.split('.').map(str::to_string).collect();
+                        args.k8s_skip.iter().map(split).collect()
+                    }),
+                }))
+            }
+        }
+    }
+
+    /// Substitute the document, and serialize it back.
+    fn substitute(&self, variables: &HashMap<String, String>) -> Result<String, ksubst::Error> {
+        match self {
+            Document::Json {
+                value,
+                indent,
+                newline,
+                keys,
+            } => {
+                let mut value = value.clone();
+                ksubst::substitute_json(&mut value, variables, *keys)?;
+                let mut output = match indent {
+                    Some(indent) => {
+                        let mut output = Vec::new();
+                        let formatter =
+                            serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+                        let mut serializer =
+                            serde_json::Serializer::with_formatter(&mut output, formatter);
+                        serde::Serialize::serialize(&value, &mut serializer).unwrap();
+                        String::from_utf8(output).unwrap()
+                    }
+                    None => value.to_string(),
+                };
+                if *newline {
+                    output.push('\n');
+                }
+                Ok(output)
+            }
+            Document::Yaml {
+                documents,
+                explicit_start,
+                keys,
+                manifest_skip,
+            } => {
+                let mut output = String::new();
+                for (i, document) in documents.iter().enumerate() {
+                    let mut document = document.clone();
+                    match manifest_skip {
+                        Some(skip) => substitute_manifest(&mut document, variables, skip)?,
+                        None => ksubst::substitute_yaml(&mut document, variables, *keys)?,
+                    }
+                    if i > 0 || *explicit_start {
+                        output.push_str("---\n");
+                    }
+                    output.push_str(&serde_yaml::to_string(&document).unwrap());
+                }
+                Ok(output)
+            }
+        }
+    }
+}
+
+/// Substitute a Kubernetes manifest, leaving values under `skip` paths
+/// as-is, and base64-encoding the values of Secret data changed by
+/// substitution.
+fn substitute_manifest(
+    manifest: &mut serde_yaml::Value,
+    variables: &HashMap<String, String>,
+    skip: &[Vec<String>],
+) -> Result<(), ksubst::Error> {
+    let secret = manifest.get("kind").and_then(serde_yaml::Value::as_str) == Some("Secret");
+    substitute_manifest_node(manifest, &mut Vec::new(), secret, variables, skip)
+}
+
+/// Substitute a node of a Kubernetes manifest, given its path.
+fn substitute_manifest_node(
+    node: &mut serde_yaml::Value,
+    path: &mut Vec<String>,
+    secret: bool,
+    variables: &HashMap<String, String>,
+    skip: &[Vec<String>],
+) -> Result<(), ksubst::Error> {
+    use base64::Engine;
+    use serde_yaml::Value;
+
+    let skipped = |pattern: &Vec<String>| {
+        pattern.len() == path.len()
+            && pattern
+                .iter()
+                .zip(path.iter())
+                .all(|(p, c)| p == "*" || p == c)
+    };
+    if skip.iter().any(skipped) {
+        return Ok(());
+    }
+    match node {
+        Value::Mapping(map) => {
+            for (key, value) in map.iter_mut() {
+                path.push(match key {
+                    Value::String(key) => key.clone(),
+                    key => serde_yaml::to_string(key)
+                        .unwrap_or_default()
+                        .trim_end()
+                        .to_string(),
+                });
+                substitute_manifest_node(value, path, secret, variables, skip)?;
+                path.pop();
+            }
+        }
+        Value::Sequence(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                path.push(i.to_string());
+                substitute_manifest_node(item, path, secret, variables, skip)?;
+                path.pop();
+            }
+        }
+        Value::Tagged(tagged) => {
+            substitute_manifest_node(&mut tagged.value, path, secret, variables, skip)?;
+        }
+        Value::String(value) => {
+            let substituted = substitute(value.as_str(), variables)?;
+            let data = secret && path.len() == 2 && path[0] == "data";
+            *value = match data && substituted != *value {
+                true => base64::engine::general_purpose::STANDARD.encode(substituted),
+                false => substituted,
+            };
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+/// Resolver of included templates, relative to the input directories.
+///
+/// Later input directories override earlier ones, like for rendered files.
+/// Outside of recursive mode, includes are relative to the current directory.
+struct IncludeDirs<'a> {
+    dirs: Vec<&'a Path>,
+    /// Whether included files must resolve under their directory.
+    restrict_root: bool,
+}
+
+impl<'a> IncludeDirs<'a> {
+    fn new(args: &'a RenderArgs) -> Self {
+        let dirs = match args.paths.split_last() {
+            Some((_, input_dirs)) if args.recursive => input_dirs.iter().map(Path::new).collect(),
+            _ => vec![Path::new(".")],
+        };
+        Self {
+            dirs,
+            restrict_root: args.restrict_root,
+        }
+    }
+}
+
+impl ksubst::Resolver for IncludeDirs<'_> {
+    fn resolve(&self, path: &str) -> io::Result<String> {
+        let relative_path = Path::new(path);
+        // Includes can't escape the input directories
+        if !is_contained(relative_path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path must be relative to the input directory",
+            ));
+        }
+        for input_dir in self.dirs.iter().rev() {
+            let path = input_dir.join(relative_path);
+            if path.is_file() {
+                if self.restrict_root {
+                    check_under_root(&path, input_dir)?;
+                }
+                return std::fs::read_to_string(path);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no such file in input directories",
+        ))
+    }
+}
+
+/// Render a file of a recursive run, reporting its changes if requested.
+///
+/// The output is written right away, unless in strict mode, where the
+/// rendered file is returned to be written once all files are checked, or
+/// when writing an archive.
+fn render_file<'a>(
+    input: &InputFile<'_>,
+    output_path: PathBuf,
+    matcher: &ksubst::Matcher<'_>,
+    variables: &'a HashMap<String, String>,
+    args: &RenderArgs,
+    run_report: &RunReport,
+) -> Result<Option<RenderedFile<'a>>, Box<dyn std::error::Error + Send + Sync>> {
+    let relative_path = input.relative_path.as_path();
+    // Read file content
+    let (content, attributes) = input.read()?;
+    let (input_content, encoding) = match decode_text(content, args.encoding) {
+        Ok(decoded) => decoded,
+        Err(content) => {
+            return copy_binary_file(&attributes, relative_path, output_path, content, args)
+        }
+    };
+
+    let template = Template::new(
+        relative_path.display(),
+        input_content,
+        encoding,
+        variables,
+        args,
+    )?;
+
+    // Perform substitution
+    let start = Instant::now();
+    let output_content = template.substitute(matcher)?;
+    check_templated(
+        &relative_path.display().to_string(),
+        &output_content,
+        &template.syntax,
+        args,
+    )?;
+    run_report.record(
+        &relative_path.display().to_string(),
+        template.body(),
+        &template.context,
+        start.elapsed(),
+    );
+    SUMMARY.record(template.body(), &template.context);
+
+    // Reports are printed at once, not to be interleaved with other files
+    let mut report = String::new();
+    if args.diff {
+        let color = match args.color {
+            clap::ColorChoice::Always => true,
+            clap::ColorChoice::Never => false,
+            clap::ColorChoice::Auto => io::stdout().is_terminal(),
+        };
+        report += &format_diff(relative_path, &output_path, &output_content, color);
+    }
+    if args.dry_run {
+        report += &format_planned_change(
+            relative_path,
+            &output_path,
+            template.body(),
+            &output_content,
+            &template.context,
+        );
+    }
+    print!("{}", report);
+
+    let output_content = template.encode(&output_content, args)?;
+    record_output(
+        &output_path.display().to_string(),
+        &output_content,
+        &relative_path.display().to_string(),
+        Some(&template),
+        args,
+    );
+    if args.strict || args.fail_if_templated || args.output_archive.is_some() {
+        return Ok(Some((
+            attributes,
+            relative_path.to_path_buf(),
+            template,
+            output_path,
+            output_content,
+        )));
+    }
+    if !args.dry_run && may_overwrite(&attributes, &output_path, &output_content, args)? {
+        write_tree_output(
+            &attributes,
+            relative_path,
+            &output_path,
+            output_content,
+            args,
+            "rendered",
+        )?;
+    }
+    Ok(None)
+}
+
+/// Whether CRLF line endings are dominant in text, if it has any.
+fn crlf_dominant(text: &str) -> Option<bool> {
+    let lines = text.matches('\n').count();
+    let crlf = text.matches("\r\n").count();
+    (lines > 0).then_some(crlf > lines - crlf)
+}
+
+/// Convert all line endings of text to CRLF, or to LF.
+fn convert_newlines(text: &str, crlf: bool) -> String {
+    let newline = match crlf {
+        true => "\r\n",
+        false => "\n",
+    };
+    let mut output = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        match line.strip_suffix('\n') {
+            Some(line) => {
+                output.push_str(line.strip_suffix('\r').unwrap_or(line));
+                output.push_str(newline);
+            }
+            None => output.push_str(line),
+        }
+    }
+    output
+}
+
+/// Decode file contents as text, or give them back if they look binary.
+fn decode_text(content: Vec<u8>, encoding: Encoding) -> Result<(String, Encoding), Vec<u8>> {
+    let (text, encoding) = encoding.decode(content)?;
+    // Like git, only look for NUL characters at the start of files
+    if text.chars().take(8000).any(|c| c == '\0') {
+        return Err(encoding.encode(&text).unwrap_or_default());
+    }
+    Ok((text, encoding))
+}
+
+/// Decode an input as text, failing if it is not valid in `encoding`.
+fn decode_input(
+    name: &str,
+    content: Vec<u8>,
+    encoding: Encoding,
+) -> Result<(String, Encoding), String> {
+    encoding
+        .decode(content)
+        .map_err(|_| format!("{}: invalid {} text", name, encoding.name()))
+}
+
+impl Encoding {
+    /// Name of the encoding, in messages.
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Auto | Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Latin1 => "Latin-1",
+        }
+    }
+
+    /// Decode text, detecting its encoding if `Auto`, or give the content
+    /// back if it is not valid in this encoding.
+    ///
+    /// Byte order marks are kept, as a leading U+FEFF character.
+    fn decode(self, content: Vec<u8>) -> Result<(String, Encoding), Vec<u8>> {
+        let encoding = match self {
+            Encoding::Auto => Encoding::detect(&content),
+            encoding => encoding,
+        };
+        let utf16 = |from_bytes: fn([u8; 2]) -> u16| {
+            if content.len() % 2 != 0 {
+                return None;
+            }
+            let units = content.chunks_exact(2).map(|c| from_bytes([c[0], c[1]]));
+            char::decode_utf16(units)
+                .collect::<Result<String, _>>()
+                .ok()
+        };
+        let text = match encoding {
+            Encoding::Auto | Encoding::Utf8 => {
+                return String::from_utf8(content)
+                    .map(|text| (text, Encoding::Utf8))
+                    .map_err(|e| e.into_bytes())
+            }
+            Encoding::Utf16Le => utf16(u16::from_le_bytes),
+            Encoding::Utf16Be => utf16(u16::from_be_bytes),
+            Encoding::Latin1 => Some(content.iter().map(|b| char::from(*b)).collect()),
+        };
+        match text {
+            Some(text) => Ok((text, encoding)),
+            None => Err(content),
+        }
+    }
+
+    /// Detect UTF-16 from a byte order mark, or from NUL bytes of ASCII
+    /// characters, or else assume UTF-8.
+    fn detect(content: &[u8]) -> Encoding {
+        match content {
+            [0xff, 0xfe, ..] => return Encoding::Utf16Le,
+            [0xfe, 0xff, ..] => return Encoding::Utf16Be,
+            _ => {}
+        }
+        if content.len() < 2 || content.len() % 2 != 0 {
+            return Encoding::Utf8;
+        }
+        let sample = &content[..content.len().min(8000)];
+        let nul_count = |offset: usize| {
+            sample
+                .iter()
+                .skip(offset)
+                .step_by(2)
+                .filter(|b| **b == 0)
+                .count()
+        };
+        let (even, odd) = (nul_count(0), nul_count(1));
+        let half = sample.len() / 4;
+        match (even, odd) {
+            (0, odd) if odd > half => Encoding::Utf16Le,
+            (even, 0) if even > half => Encoding::Utf16Be,
+            _ => Encoding::Utf8,
+        }
+    }
+
+    /// Encode text, failing if some characters can't be represented.
+    fn encode(self, text: &str) -> Result<Vec<u8>, String> {
+        let utf16 = |to_bytes: fn(u16) -> [u8; 2]| text.encode_utf16().flat_map(to_bytes).collect();
+        match self {
+            Encoding::Auto | Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Utf16Le => Ok(utf16(u16::to_le_bytes)),
+            Encoding::Utf16Be => Ok(utf16(u16::to_be_bytes)),
+            Encoding::Latin1 => text
+                .chars()
+                .map(|c| {
+                    u8::try_from(c).map_err(|_| format!("'{}' can't be encoded in Latin-1", c))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Handle a binary file of a recursive run according to `--binary`.
+fn copy_binary_file(
+    attributes: &Attributes,
+    relative_path: &Path,
+    output_path: PathBuf,
+    content: Vec<u8>,
+    args: &RenderArgs,
+) -> Result<Option<RenderedFile<'static>>, Box<dyn std::error::Error + Send + Sync>> {
+    match args.binary {
+        BinaryPolicy::Error => {
+            return Err(format!("{}: binary file", relative_path.display()).into());
+        }
+        BinaryPolicy::Skip => {
+            if args.dry_run {
+                println!("skip {}", relative_path.display());
+            }
+            message!(VERBOSE, "skipped binary {}", relative_path.display());
+            SUMMARY.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        BinaryPolicy::Copy => {
+            return copy_verbatim(attributes, relative_path, output_path, content, args)
+        }
+    }
+    Ok(None)
+}
+
+/// Copy a file of a recursive run without substituting it.
+fn copy_verbatim(
+    attributes: &Attributes,
+    relative_path: &Path,
+    output_path: PathBuf,
+    content: Vec<u8>,
+    args: &RenderArgs,
+) -> Result<Option<RenderedFile<'static>>, Box<dyn std::error::Error + Send + Sync>> {
+    SUMMARY.processed.fetch_add(1, Ordering::Relaxed);
+    record_output(
+        &output_path.display().to_string(),
+        &content,
+        &relative_path.display().to_string(),
+        None,
+        args,
+    );
+    if args.strict || args.fail_if_templated || args.output_archive.is_some() {
+        // Copied files have no placeholders to check
+        return Ok(Some((
+            attributes.clone(),
+            relative_path.to_path_buf(),
+            Template {
+                encoding: Encoding::Utf8,
+                bom: false,
+                crlf: None,
+                body: String::new(),
+                document: None,
+                syntax: ksubst::Syntax::Braces,
+                context: Cow::Owned(HashMap::new()),
+            },
+            output_path,
+            content,
+        )));
+    }
+    if args.dry_run {
+        println!("copy {}", relative_path.display());
+    } else if may_overwrite(attributes, &output_path, &content, args)? {
+        write_tree_output(
+            attributes,
+            relative_path,
+            &output_path,
+            content,
+            args,
+            "copied",
+        )?;
+    }
+    Ok(None)
+}
+
+/// Machine-readable report of a render run.
+struct RunReport {
+    enabled: bool,
+    start: Instant,
+    files: Mutex<Vec<serde_json::Value>>,
+}
+
+impl RunReport {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            start: Instant::now(),
+            files: Mutex::default(),
+        }
+    }
+
+    /// Record a rendered template, if reporting is enabled.
+    fn record(
+        &self,
+        name: &str,
+        template: &str,
+        variables: &HashMap<String, String>,
+        duration: Duration,
+    ) {
+        if !self.enabled {
+            return;
+        }
+        let mut replacements = 0;
+        let mut missing = Vec::new();
+        for segment in ksubst::template::parse(template) {
+            if let ksubst::template::Segment::Placeholder(p) = segment {
+                if variables.contains_key(p.name) {
+                    replacements += 1;
+                } else if !missing.contains(&p.name) {
+                    missing.push(p.name);
+                }
+            }
+        }
+        self.files.lock().unwrap().push(serde_json::json!({
+            "path": name,
+            "replacements": replacements,
+            "missing": missing,
+            "duration_ms": duration.as_secs_f64() * 1000.0,
+        }));
+    }
+
+    /// Write the report to `path`, or stderr.
+    fn emit(
+        &self,
+        path: Option<&str>,
+        error: Option<&dyn std::error::Error>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut files = self.files.lock().unwrap().clone();
+        files.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+        let mut missing: Vec<&str> = files
+            .iter()
+            .flat_map(|file| file["missing"].as_array().unwrap())
+            .filter_map(|name| name.as_str())
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+
+        let report = serde_json::json!({
+            "success": error.is_none(),
+            "error": error.map(|e| redact(&e.to_string()).into_owned()),
+            "duration_ms": self.start.elapsed().as_secs_f64() * 1000.0,
+            "files": files,
+            "missing": missing,
+        });
+        let report = serde_json::to_string_pretty(&report)?;
+        match path {
+            Some(path) => {
+                write_output(Path::new(path), report + "\n", None)?;
+            }
+            None => eprintln!("{}", report),
+        }
+        Ok(())
+    }
+}
+
+/// Names of the variables loaded from explicit sources, for `--unused`.
+static SOURCED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Counts of recursive runs, printed at their end.
+struct Summary {
+    /// Number of rendered or copied files.
+    processed: AtomicUsize,
+    skipped: AtomicUsize,
+    /// Number of output files already up to date.
+    unchanged: AtomicUsize,
+    replacements: AtomicUsize,
+    /// Names of the variables substituted, also for `--unused`.
+    variables: Mutex<BTreeSet<String>>,
+}
+
+static SUMMARY: Summary = Summary {
+    processed: AtomicUsize::new(0),
+    skipped: AtomicUsize::new(0),
+    unchanged: AtomicUsize::new(0),
+    replacements: AtomicUsize::new(0),
+    variables: Mutex::new(BTreeSet::new()),
+};
+
+impl Summary {
+    /// Record a rendered template.
+    fn record(&self, template: &str, variables: &HashMap<String, String>) {
+        let mut replacements = 0;
+        let mut used = self.variables.lock().unwrap();
+        for segment in ksubst::template::parse(template) {
+            if let ksubst::template::Segment::Placeholder(p) = segment {
+                if variables.contains_key(p.name) {
+                    replacements += 1;
+                    if !used.contains(p.name) {
+                        used.insert(p.name.to_string());
+                    }
+                }
+            }
+        }
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        self.replacements.fetch_add(replacements, Ordering::Relaxed);
+    }
+
+    /// Print the summary of a run which took `elapsed`.
+    fn print(&self, elapsed: Duration) {
+        message!(
+            NORMAL,
+            "{} files processed ({} unchanged), {} skipped, {} replacements of {} variables in {:.2}s",
+            self.processed.load(Ordering::Relaxed),
+            self.unchanged.load(Ordering::Relaxed),
+            self.skipped.load(Ordering::Relaxed),
+            self.replacements.load(Ordering::Relaxed),
+            self.variables.lock().unwrap().len(),
+            elapsed.as_secs_f64()
+        );
+    }
+}
+
+/// Progress of a recursive run, shown as a status line on stderr.
+struct Progress {
+    total: usize,
+    enabled: bool,
+    start: std::time::Instant,
+    /// Number of files done, and when the status line was last shown.
+    state: Mutex<(usize, Option<std::time::Instant>)>,
+}
+
+impl Progress {
+    /// Minimum delay between status line updates.
+    const INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+    fn new(total: usize, enabled: bool) -> Self {
+        Self {
+            total,
+            enabled,
+            start: std::time::Instant::now(),
+            state: Mutex::new((0, None)),
+        }
+    }
+
+    /// Record that a file is done, updating the status line if due.
+    fn file_done(&self, relative_path: &Path) {
+        if !self.enabled {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.0 += 1;
+        let (done, shown) = *state;
+        if shown.is_some_and(|shown| shown.elapsed() < Self::INTERVAL) && done < self.total {
+            return;
+        }
+        state.1 = Some(std::time::Instant::now());
+
+        let elapsed = self.start.elapsed();
+        let remaining = elapsed.mul_f64((self.total - done) as f64 / done as f64);
+        eprint!(
+            "\r\x1b[Kksubst: [{}/{}] ETA {}s {}",
+            done,
+            self.total,
+            remaining.as_secs(),
+            relative_path.display()
+        );
+    }
+
+    /// Clear the status line, and report the run duration.
+    fn finish(&self) {
+        if self.enabled {
+            eprintln!(
+                "\r\x1b[Kksubst: processed {} files in {:.1}s",
+                self.state.lock().unwrap().0,
+                self.start.elapsed().as_secs_f64()
+            );
+        }
+    }
+}
+
+/// Apply `f` to all items on up to `jobs` threads, returning results in order.
+///
+/// With 0 jobs, a thread is used per available core.
+fn parallel_map<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let jobs = match jobs {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        jobs => jobs,
+    };
+    if jobs == 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(items.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+                let result = f(item);
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Read input files, checking that they are resolved in strict mode.
+fn read_inputs<'a, 'b>(
+    paths: &'a [String],
+    variables: &'b HashMap<String, String>,
+    args: &RenderArgs,
+) -> Result<Vec<(&'a String, Template<'b>)>, Box<dyn std::error::Error>> {
+    if args.sha256.is_some() && paths.len() > 1 {
+        return Err("--sha256 requires a single input".into());
+    }
+    let inputs = paths
+        .iter()
+        .map(|path| {
+            let input = read_input(path)?;
+            if let Some(digest) = &args.sha256 {
+                verify_sha256(path, &input, digest)?;
+            }
+            let (input, encoding) = decode_input(path, input, args.encoding)?;
+            Ok((path, Template::new(path, input, encoding, variables, args)?))
+        })
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+    if args.strict {
+        check_resolved(
+            inputs
+                .iter()
+                .map(|(path, template)| (path, template.body(), &*template.context)),
+        )?;
+    }
+    Ok(inputs)
+}
+
+/// Maximum size of templates fetched from URLs.
+#[cfg(feature = "http")]
+const MAX_URL_SIZE: u64 = 16 << 20;
+
+/// Whether an input path is a URL to fetch.
+fn is_url(path: &str) -> bool {
+    path.starts_with("https://")
+}
+
+/// Read an input file, or fetch a URL with the `http` feature.
+fn read_input(path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if !is_url(path) {
+        return Ok(std::fs::read(path)?);
+    }
+    #[cfg(feature = "http")]
+    {
+        let response = ureq::get(path)
+            .call()
+            // Errors already mention the URL
+            .map_err(|e| e.to_string())?;
+        let mut input = Vec::new();
+        response
+            .into_reader()
+            .take(MAX_URL_SIZE + 1)
+            .read_to_end(&mut input)
+            .map_err(|e| format!("failed to fetch {}: {}", path, e))?;
+        if input.len() as u64 > MAX_URL_SIZE {
+            return Err(format!("{}: template larger than {} bytes", path, MAX_URL_SIZE).into());
+        }
+        message!(VERBOSE, "fetched {}", path);
+        Ok(input)
+    }
+    #[cfg(not(feature = "http"))]
+    {
+        Err(format!("{}: URL inputs require the 'http' feature", path).into())
+    }
+}
+
+/// Fail unless the SHA-256 digest of a template is the expected one.
+fn verify_sha256(
+    name: &str,
+    template: &[u8],
+    expected: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let digest = sha256_hex(template);
+    if !digest.eq_ignore_ascii_case(expected.trim()) {
+        return Err(format!(
+            "{}: SHA-256 digest {} doesn't match the pinned {}",
+            name, digest, expected
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Substitute files, writing their outputs to stdout one after the other,
+/// or to `-o` for a single file.
+fn substitute_files(
+    paths: &[String],
+    args: &RenderArgs,
+    variables: &HashMap<String, String>,
+    report: &RunReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if args.output.is_some() && paths.len() > 1 {
+        return Err("-o requires a single input file".into());
+    }
+    let matcher = ksubst::Matcher::new(variables)?;
+    let inputs = read_inputs(paths, variables, args)?;
+
+    let mut stdout = io::stdout().lock();
+    for (path, template) in &inputs {
+        let start = Instant::now();
+        let output_content = template.substitute(&matcher)?;
+        check_templated(path, &output_content, &template.syntax, args)?;
+        report.record(path, template.body(), &template.context, start.elapsed());
+        SUMMARY.record(template.body(), &template.context);
+        let output_content = template.encode(&output_content, args)?;
+        let output = args.output.as_deref().unwrap_or("-");
+        record_output(output, &output_content, path, Some(template), args);
+        match &args.output {
+            Some(output) => {
+                write_output(Path::new(output), output_content, args.backup.as_deref())?;
+            }
+            None => stdout.write_all(&output_content)?,
+        }
+    }
+    Ok(())
+}
+
+/// Substitute files in place, backing them up first if `suffix` is not empty.
+fn substitute_in_place(
+    paths: &[String],
+    suffix: &str,
+    variables: &HashMap<String, String>,
+    args: &RenderArgs,
+    report: &RunReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let matcher = ksubst::Matcher::new(variables)?;
+    let inputs = read_inputs(paths, variables, args)?;
+
+    // Substitute all files before writing any
+    let outputs = inputs
+        .iter()
+        .map(|(path, template)| {
+            let start = Instant::now();
+            let output = template.substitute(&matcher)?;
+            check_templated(path, &output, &template.syntax, args)?;
+            report.record(path, template.body(), &template.context, start.elapsed());
+            SUMMARY.record(template.body(), &template.context);
+            let output = template.encode(&output, args)?;
+            record_output(path, &output, path, Some(template), args);
+            Ok(output)
+        })
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+    for (path, _) in &inputs {
+        if !suffix.is_empty() {
+            let input = std::fs::read(path)?;
+            write_atomically(Path::new(&format!("{}{}", path, suffix)), input)?;
+        }
+    }
+    for ((path, _), output) in inputs.iter().zip(outputs) {
+        write_atomically(Path::new(path), output)?;
+        message!(VERBOSE, "rendered {}", path);
+    }
+    Ok(())
+}
+
+/// Whether a file is processed, according to exclude and filter patterns.
+fn is_selected(relative_path: &Path, exclude_globset: &GlobSet, filter_globset: &GlobSet) -> bool {
+    // Check exclude patterns, and if filter patterns are specified, only
+    // process files that match the filter patterns
+    (exclude_globset.is_empty() || !exclude_globset.is_match(relative_path))
+        && (filter_globset.is_empty() || filter_globset.is_match(relative_path))
+}
+
+/// Render templates again whenever they or variables files change, until
+/// the process is terminated.
+///
+/// Errors are reported on stderr, and don't stop watching.
+fn watch(
+    input_dirs: &[String],
+    output_dir: &str,
+    mut variables: HashMap<String, String>,
+    sources: &Sources,
+    args: &RenderArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use notify::{EventKind, RecursiveMode, Watcher};
+
+    let input_roots = input_dirs
+        .iter()
+        .map(|dir| Path::new(dir).canonicalize())
+        .collect::<Result<Vec<_>, _>>()?;
+    let variables_files = variables_files(sources)?;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for root in &input_roots {
+        watcher.watch(root, RecursiveMode::Recursive)?;
+    }
+    // Parent directories are watched, since editors often replace files
+    for file in &variables_files {
+        if let Some(parent) = file.parent() {
+            watcher.watch(parent, RecursiveMode::NonRecursive)?;
+        }
+    }
+    message!(NORMAL, "watching {} for changes", input_dirs.join(", "));
+
+    while let Ok(event) = rx.recv() {
+        // Changes often come in bursts, which are handled at once
+        let mut events = vec![event];
+        while let Ok(event) = rx.recv_timeout(std::time::Duration::from_millis(50)) {
+            events.push(event);
+        }
+        let mut changed = Vec::new();
+        for event in events {
+            match event {
+                Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                    changed.extend(event.paths)
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("ksubst: watch error: {}", e),
+            }
+        }
+
+        let report = RunReport::new(args.report.is_some());
+        let result = rerender(
+            &changed,
+            input_dirs,
+            output_dir,
+            &mut variables,
+            sources,
+            args,
+            &report,
+        );
+        // Outputs written before a failure are recorded too
+        let saved = save_state(output_dir);
+        if args.report.is_some() {
+            let error = result.as_ref().err().map(|e| &**e);
+            if let Err(e) = report.emit(args.report_file.as_deref(), error) {
+                eprintln!("ksubst: {}", e);
+            }
+        }
+        if let Err(e) = result.and_then(|()| Ok(saved?)) {
+            eprintln!("ksubst: {}", redact(&e.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Canonical paths of the variables files of sources, to be watched.
+fn variables_files(sources: &Sources) -> io::Result<Vec<PathBuf>> {
+    sources
+        .env_file
+        .iter()
+        .chain(&sources.env_overrides_file)
+        .chain(&sources.vars_json)
+        .chain(&sources.vars_yaml)
+        .chain(&sources.vars_toml)
+        .filter(|path| *path != "-")
+        .map(|path| Path::new(path).canonicalize())
+        .collect()
+}
+
+/// Render again the changed files of a watch run, or all templates if a
+/// variables file changed, through the same selection and rendering as
+/// recursive runs.
+fn rerender(
+    changed: &[PathBuf],
+    input_dirs: &[String],
+    output_dir: &str,
+    variables: &mut HashMap<String, String>,
+    sources: &Sources,
+    args: &RenderArgs,
+    report: &RunReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let exclude_globset = build_globset(&args.exclude_patterns)?;
+    let filter_globset = build_globset(&args.filter_patterns)?;
+    let variables_files = variables_files(sources)?;
+    let all = changed.iter().any(|path| variables_files.contains(path));
+    if all {
+        message!(NORMAL, "variables changed, rendering all templates");
+        *variables = self::variables(sources)?;
+        register_secrets(variables, args);
+    }
+
+    let input_roots = input_dirs
+        .iter()
+        .map(|dir| Path::new(dir).canonicalize())
+        .collect::<Result<Vec<_>, _>>()?;
+    let changed: BTreeSet<&Path> = changed
+        .iter()
+        .filter_map(|path| {
+            let root = input_roots.iter().find(|root| path.starts_with(root))?;
+            path.strip_prefix(root).ok()
+        })
+        .collect();
+    let matcher = ksubst::Matcher::new(variables)?;
+    let mut inputs = list_inputs(
+        input_dirs,
+        &exclude_globset,
+        &filter_globset,
+        &matcher,
+        args,
+    )?;
+    // Files overridden by later input directories are listed from them
+    inputs.retain(|relative_path, _| all || changed.contains(relative_path.as_path()));
+    for input in inputs.values().filter(|input| !all && input.selected) {
+        message!(NORMAL, "rendering {}", input.relative_path.display());
+    }
+    render_inputs(inputs, output_dir, &matcher, variables, args, report)?;
+    Ok(())
+}
+
+/// Format whether an output file would be created or modified, with the
+/// variables its template uses.
+fn format_planned_change(
+    relative_path: &Path,
+    output_path: &Path,
+    input_content: &str,
+    output_content: &str,
+    variables: &HashMap<String, String>,
+) -> String {
+    let action = match std::fs::read(output_path) {
+        Ok(existing) if existing == output_content.as_bytes() => "unchanged",
+        Ok(_) => "modify",
+        Err(_) => "create",
+    };
+    let mut used = Vec::new();
+    for segment in ksubst::template::parse(input_content) {
+        if let ksubst::template::Segment::Placeholder(p) = segment {
+            if variables.contains_key(p.name) && !used.contains(&p.name) {
+                used.push(p.name);
+            }
+        }
+    }
+    if used.is_empty() {
+        format!("{} {}\n", action, relative_path.display())
+    } else {
+        format!(
+            "{} {} ({})\n",
+            action,
+            relative_path.display(),
+            used.join(", ")
+        )
+    }
+}
+
+/// Format a unified diff between an existing output file and its rendered
+/// content, if they differ.
+fn format_diff(
+    relative_path: &Path,
+    output_path: &Path,
+    output_content: &str,
+    color: bool,
+) -> String {
+    let existing = std::fs::read_to_string(output_path).ok();
+    if existing.as_deref() == Some(output_content) {
+        return String::new();
+    }
+    let name = relative_path.display();
+    let old_header = match existing {
+        Some(_) => format!("a/{}", name),
+        None => "/dev/null".to_string(),
+    };
+    let existing = existing.unwrap_or_default();
+    let diff = similar::TextDiff::from_lines(existing.as_str(), output_content);
+    let unified = diff
+        .unified_diff()
+        .header(&old_header, &format!("b/{}", name))
+        .to_string();
+    let unified = redact(&unified);
+
+    let mut formatted = String::new();
+    for line in unified.lines() {
+        let style = match line.as_bytes().first() {
+            _ if !color => None,
+            _ if line.starts_with("---") || line.starts_with("+++") => Some("1"),
+            Some(b'-') => Some("31"),
+            Some(b'+') => Some("32"),
+            Some(b'@') => Some("36"),
+            _ => None,
+        };
+        match style {
+            Some(style) => formatted += &format!("\x1b[{}m{}\x1b[0m\n", style, line),
+            None => formatted += &format!("{}\n", line),
+        }
+    }
+    formatted
+}
+
+/// Write an output file, creating parent directories, unless it already
+/// has this content.
+///
+/// Unchanged files are left untouched, keeping their modification time.
+/// Other existing files are first copied to a file with the `backup`
+/// suffix, if any. Returns whether the file was written.
+fn write_output(
+    output_path: &Path,
+    output_content: impl AsRef<[u8]>,
+    backup: Option<&str>,
+) -> io::Result<bool> {
+    let output_content = output_content.as_ref();
+    let metadata = std::fs::metadata(output_path).ok();
+    // Only files of the same size are read to be compared
+    let same_size = metadata
+        .as_ref()
+        .is_some_and(|m| m.is_file() && m.len() == output_content.len() as u64);
+    if same_size && std::fs::read(output_path)? == output_content {
+        return Ok(false);
+    }
+    if let Some(suffix) = backup.filter(|_| metadata.is_some_and(|m| m.is_file())) {
+        let mut backup_path = output_path.as_os_str().to_owned();
+        backup_path.push(suffix);
+        std::fs::copy(output_path, backup_path)?;
+    }
+
+    // Create parent directories if needed
+    if let Some(parent) = output_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // Write output file
+    write_atomically(output_path, output_content)?;
+    Ok(true)
+}
+
+/// Name of the file recording outputs as last rendered, in output directories.
+const STATE_FILE: &str = ".ksubst-state.json";
+
+/// SHA-256 digests of output files as last rendered, by output path.
+static RENDERED: Mutex<BTreeMap<PathBuf, String>> = Mutex::new(BTreeMap::new());
+
+/// Load the outputs recorded in the state file of an output directory.
+fn load_state(output_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(output_dir).join(STATE_FILE);
+    let state = match std::fs::read(&path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        state => state?,
+    };
+    let state: BTreeMap<String, BTreeMap<PathBuf, String>> = serde_json::from_slice(&state)
+        .map_err(|e| format!("{}: invalid state file: {}", path.display(), e))?;
+    let mut rendered = RENDERED.lock().unwrap();
+    for (relative_path, digest) in state.into_values().flatten() {
+        rendered.insert(Path::new(output_dir).join(relative_path), digest);
+    }
+    Ok(())
+}
+
+/// Write the state file of an output directory, with its recorded outputs
+/// still existing.
+fn save_state(output_dir: &str) -> io::Result<()> {
+    let rendered = RENDERED.lock().unwrap();
+    let outputs: BTreeMap<_, _> = rendered
+        .iter()
+        .filter(|(path, _)| path.is_file())
+        .filter_map(|(path, digest)| Some((path.strip_prefix(output_dir).ok()?, digest)))
+        .collect();
+    if outputs.is_empty() && !Path::new(output_dir).join(STATE_FILE).exists() {
+        return Ok(());
+    }
+    let state = serde_json::to_string_pretty(&serde_json::json!({ "outputs": outputs }))?;
+    write_atomically(&Path::new(output_dir).join(STATE_FILE), state + "\n")
+}
+
+/// Hexadecimal SHA-256 digest of content.
+fn sha256_hex(content: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    format!("{:x}", Sha256::digest(content))
+}
+
+/// Whether an output file of a recursive run may be written.
+///
+/// Existing files edited since they were last rendered, according to the
+/// state file of the output directory, are only overwritten with `--force`,
+/// or with `--update` if they are older than their input. Files not
+/// rendered before are overwritten.
+fn may_overwrite(
+    attributes: &Attributes,
+    output_path: &Path,
+    content: &[u8],
+    args: &RenderArgs,
+) -> io::Result<bool> {
+    if args.force {
+        return Ok(true);
+    }
+    let Ok(output_metadata) = std::fs::metadata(output_path) else {
+        return Ok(true);
+    };
+    if !output_metadata.is_file() {
+        return Ok(true);
+    }
+    let current = std::fs::read(output_path)?;
+    if current == content {
+        return Ok(true);
+    }
+    match RENDERED.lock().unwrap().get(output_path) {
+        Some(digest) if *digest != sha256_hex(&current) => {}
+        _ => return Ok(true),
+    }
+    if args.update {
+        let output_modified = output_metadata.modified()?;
+        let newer = attributes
+            .modified
+            .is_none_or(|modified| modified > output_modified);
+        if !newer {
+            message!(
+                VERBOSE,
+                "kept {}, newer than its input",
+                output_path.display()
+            );
+        }
+        return Ok(newer);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!(
+            "{} was edited since it was last rendered, use --force to overwrite it",
+            output_path.display()
+        ),
+    ))
+}
+
+/// Write an output file of a recursive run, with the permissions of its input.
+fn write_tree_output(
+    attributes: &Attributes,
+    relative_path: &Path,
+    output_path: &Path,
+    content: impl AsRef<[u8]>,
+    args: &RenderArgs,
+    action: &str,
+) -> io::Result<()> {
+    if let Some(output_dir) = args.paths.last().filter(|_| args.restrict_root) {
+        check_under_root(output_path, Path::new(output_dir))?;
+    }
+    let digest = sha256_hex(content.as_ref());
+    if write_output(output_path, content, args.backup.as_deref())? {
+        message!(VERBOSE, "{} {}", action, relative_path.display());
+    } else {
+        message!(VERBOSE, "unchanged {}", relative_path.display());
+        SUMMARY.unchanged.fetch_add(1, Ordering::Relaxed);
+    }
+    RENDERED
+        .lock()
+        .unwrap()
+        .insert(output_path.to_path_buf(), digest);
+    copy_metadata(attributes, output_path, &args.preserve)
+}
+
+/// Copy the permissions of an input file to its output, and the other
+/// attributes given by `--preserve`.
+fn copy_metadata(
+    attributes: &Attributes,
+    output_path: &Path,
+    preserve: &[Preserve],
+) -> io::Result<()> {
+    if preserve.contains(&Preserve::Timestamps) {
+        let mut times = std::fs::FileTimes::new();
+        if let Some(accessed) = attributes.accessed {
+            times = times.set_accessed(accessed);
+        }
+        if let Some(modified) = attributes.modified {
+            times = times.set_modified(modified);
+        }
+        std::fs::File::open(output_path)?.set_times(times)?;
+    }
+    #[cfg(unix)]
+    if let (true, Some((uid, gid))) = (preserve.contains(&Preserve::Ownership), attributes.owner) {
+        // Only privileged users can give files away
+        match std::os::unix::fs::chown(output_path, Some(uid), Some(gid)) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => message!(
+                VERBOSE,
+                "cannot preserve ownership of {}",
+                output_path.display()
+            ),
+            result => result?,
+        }
+    }
+    match &attributes.permissions {
+        Some(permissions) => std::fs::set_permissions(output_path, permissions.clone()),
+        None => Ok(()),
+    }
+}
+
+/// Write rendered files and symbolic links of a recursive run to an archive.
+///
+/// Entries get the permissions of their input files, and their timestamps
+/// and ownership only if preserved, so that archives of the same outputs
+/// are identical.
+fn write_archive(
+    path: &Path,
+    format: ArchiveFormat,
+    files: &[RenderedFile<'_>],
+    links: &[InputFile<'_>],
+    args: &RenderArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let targets = links
+        .iter()
+        .map(InputFile::read_link)
+        .collect::<io::Result<Vec<_>>>()?;
+    let content = match format {
+        ArchiveFormat::Tar => tar_archive(Vec::new(), files, links, &targets, args)?,
+        ArchiveFormat::TarGz => {
+            let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            tar_archive(encoder, files, links, &targets, args)?.finish()?
+        }
+        ArchiveFormat::Zip => zip_archive(files, links, &targets, args)?,
+    };
+    if write_output(path, content, None)? {
+        message!(
+            NORMAL,
+            "wrote {} entries to {}",
+            files.len() + links.len(),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Write rendered files and symbolic links to a tar archive.
+fn tar_archive<W: Write>(
+    writer: W,
+    files: &[RenderedFile<'_>],
+    links: &[InputFile<'_>],
+    targets: &[PathBuf],
+    args: &RenderArgs,
+) -> io::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    for (attributes, _, _, output_path, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(attributes.mode().unwrap_or(0o644));
+        header.set_size(content.len() as u64);
+        header.set_mtime(0);
+        if let (true, Some((uid, gid))) = (
+            args.preserve.contains(&Preserve::Ownership),
+            attributes.owner,
+        ) {
+            header.set_uid(uid.into());
+            header.set_gid(gid.into());
+        }
+        if let (true, Some(modified)) = (
+            args.preserve.contains(&Preserve::Timestamps),
+            attributes.modified,
+        ) {
+            let modified = modified.duration_since(std::time::UNIX_EPOCH);
+            header.set_mtime(modified.map_or(0, |d| d.as_secs()));
+        }
+        builder.append_data(&mut header, output_path, content.as_slice())?;
+        message!(VERBOSE, "archived {}", output_path.display());
+    }
+    for (input, target) in links.iter().zip(targets) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_mode(0o777);
+        header.set_size(0);
+        builder.append_link(&mut header, &input.output_path, target)?;
+        message!(VERBOSE, "archived link {}", input.output_path.display());
+    }
+    builder.into_inner()
+}
+
+/// Write rendered files and symbolic links to a zip archive.
+///
+/// Zip archives don't keep ownership, and keep timestamps only if preserved.
+fn zip_archive(
+    files: &[RenderedFile<'_>],
+    links: &[InputFile<'_>],
+    targets: &[PathBuf],
+    args: &RenderArgs,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    use zip::write::SimpleFileOptions;
+
+    let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    for (attributes, _, _, output_path, content) in files {
+        let mut options = options;
+        if let Some(mode) = attributes.mode() {
+            options = options.unix_permissions(mode);
+        }
+        if let (true, Some(modified)) = (
+            args.preserve.contains(&Preserve::Timestamps),
+            attributes.modified,
+        ) {
+            let modified = chrono::DateTime::<chrono::Local>::from(modified);
+            options = options.last_modified_time(zip_time(modified.naive_local())?);
+        }
+        writer.start_file_from_path(output_path, options)?;
+        writer.write_all(content)?;
+        message!(VERBOSE, "archived {}", output_path.display());
+    }
+    for (input, target) in links.iter().zip(targets) {
+        let path = input.output_path.to_string_lossy().replace('\\', "/");
+        writer.add_symlink(path, target.to_string_lossy(), options)?;
+        message!(VERBOSE, "archived link {}", input.output_path.display());
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+/// Convert a local time to a zip timestamp.
+fn zip_time(time: chrono::NaiveDateTime) -> Result<zip::DateTime, zip::result::DateTimeRangeError> {
+    use chrono::{Datelike, Timelike};
+    zip::DateTime::from_date_and_time(
+        time.year().try_into().unwrap_or(0),
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+}
+
+/// Read the entries of an input archive, as their relative paths and
+/// contents.
+///
+/// Directories and special files are skipped, and entries outside of the
+/// archive root are rejected.
+fn read_archive(
+    path: &str,
+    format: ArchiveFormat,
+) -> Result<Vec<(PathBuf, ArchiveEntry)>, Box<dyn std::error::Error>> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let entries = match format {
+        ArchiveFormat::Tar => read_tar(tar::Archive::new(file)),
+        ArchiveFormat::TarGz => read_tar(tar::Archive::new(flate2::read::GzDecoder::new(file))),
+        ArchiveFormat::Zip => read_zip(file),
+    };
+    entries.map_err(|e| format!("{}: {}", path, e).into())
+}
+
+/// Read the entries of a tar archive.
+fn read_tar<R: Read>(
+    mut archive: tar::Archive<R>,
+) -> Result<Vec<(PathBuf, ArchiveEntry)>, Box<dyn std::error::Error>> {
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        if !entry_type.is_file() && !entry_type.is_symlink() {
+            continue;
+        }
+        let relative_path = archive_entry_path(&entry.path()?)?;
+        let link = match entry.link_name()? {
+            Some(target) if entry_type.is_symlink() => Some(target.into_owned()),
+            _ => None,
+        };
+        let header = entry.header();
+        let attributes = Attributes {
+            permissions: header.mode().ok().and_then(mode_permissions),
+            accessed: None,
+            modified: header
+                .mtime()
+                .ok()
+                .map(|mtime| std::time::UNIX_EPOCH + Duration::from_secs(mtime)),
+            owner: header
+                .uid()
+                .ok()
+                .zip(header.gid().ok())
+                .and_then(|(uid, gid)| Some((uid.try_into().ok()?, gid.try_into().ok()?))),
+        };
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        let entry = ArchiveEntry {
+            content,
+            attributes,
+            link,
+        };
+        entries.push((relative_path, entry));
+    }
+    Ok(entries)
+}
+
+/// Read the entries of a zip archive.
+fn read_zip(
+    file: std::fs::File,
+) -> Result<Vec<(PathBuf, ArchiveEntry)>, Box<dyn std::error::Error>> {
+    use chrono::TimeZone;
+
+    let mut archive = zip::ZipArchive::new(io::BufReader::new(file))?;
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        if file.is_dir() {
+            continue;
+        }
+        let relative_path = archive_entry_path(Path::new(file.name()))?;
+        let modified = file.last_modified().and_then(|time| {
+            let date = chrono::NaiveDate::from_ymd_opt(
+                time.year().into(),
+                time.month().into(),
+                time.day().into(),
+            )?;
+            let time = date.and_hms_opt(
+                time.hour().into(),
+                time.minute().into(),
+                time.second().into(),
+            )?;
+            Some(chrono::Local.from_local_datetime(&time).earliest()?.into())
+        });
+        let attributes = Attributes {
+            permissions: file.unix_mode().and_then(mode_permissions),
+            accessed: None,
+            modified,
+            owner: None,
+        };
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        let link = file
+            .is_symlink()
+            .then(|| PathBuf::from(String::from_utf8_lossy(&content).into_owned()));
+        let entry = ArchiveEntry {
+            content,
+            attributes,
+            link,
+        };
+        entries.push((relative_path, entry));
+    }
+    Ok(entries)
+}
+
+/// Relative path of an archive entry, which can't escape the archive root.
+fn archive_entry_path(path: &Path) -> Result<PathBuf, String> {
+    let relative_path: PathBuf = path
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect();
+    if !is_contained(path) || relative_path.as_os_str().is_empty() {
+        return Err(format!("invalid entry path '{}'", path.display()));
+    }
+    Ok(relative_path)
+}
+
+/// Whether a relative path stays inside its base directory.
+fn is_contained(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Fail unless a path resolves under `root`, following symbolic links.
+///
+/// Paths which don't exist yet are checked by their nearest existing
+/// ancestor, the directories created under it not being links.
+fn check_under_root(path: &Path, root: &Path) -> io::Result<()> {
+    let root = root.canonicalize()?;
+    let mut existing = path;
+    let resolved = loop {
+        match existing.canonicalize() {
+            Ok(resolved) => break resolved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                existing = match existing.parent() {
+                    Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+                    Some(parent) => parent,
+                    None => return Err(e),
+                };
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    if !resolved.starts_with(&root) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} resolves outside of {}", path.display(), root.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Write a file through a temporary sibling renamed into place, so that
+/// readers never see it half-written.
+///
+/// Replaced files keep their permissions. Symbolic links are written
+/// through, and devices or pipes like `/dev/stdout` are written directly.
+fn write_atomically(path: &Path, content: impl AsRef<[u8]>) -> io::Result<()> {
+    let path = match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => std::fs::canonicalize(path)?,
+        _ => path.to_path_buf(),
+    };
+    let metadata = std::fs::metadata(&path).ok();
+    if metadata.as_ref().is_some_and(|m| !m.is_file()) {
+        return std::fs::write(path, content);
+    }
+
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        )
+    })?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = std::fs::write(&temp_path, content)
+        .and_then(|()| match metadata {
+            Some(metadata) => std::fs::set_permissions(&temp_path, metadata.permissions()),
+            None => Ok(()),
+        })
+        .and_then(|()| std::fs::rename(&temp_path, &path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Create a symbolic link to `target`, replacing any existing file.
+fn write_link(link_path: &Path, target: &Path) -> io::Result<()> {
+    if let Some(parent) = link_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match std::fs::symlink_metadata(link_path) {
+        Ok(metadata) if metadata.is_dir() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is a directory", link_path.display()),
+            ))
+        }
+        Ok(_) => std::fs::remove_file(link_path)?,
+        Err(_) => {}
+    }
+
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, link_path);
+    #[cfg(windows)]
+    {
+        let resolved = link_path.parent().unwrap_or(Path::new("")).join(target);
+        if resolved.is_dir() {
+            std::os::windows::fs::symlink_dir(target, link_path)
+        } else {
+            std::os::windows::fs::symlink_file(target, link_path)
+        }
+    }
+}
+
+/// Whether a walk error is a symbolic link pointing to one of its ancestors.
+fn is_symlink_loop(error: &ignore::Error) -> bool {
+    match error {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::WithPath { err, .. }
+        | ignore::Error::WithDepth { err, .. }
+        | ignore::Error::WithLineNumber { err, .. } => is_symlink_loop(err),
+        _ => false,
+    }
+}
+
+/// Check that templates only reference defined variables.
+fn check(
+    paths: &[String],
+    format: CheckFormat,
+    variables: &HashMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    ksubst::validate_vars(variables)?;
+    let templates = read_templates(paths)?;
+    if format == CheckFormat::Sarif {
+        return check_sarif(&templates, variables);
+    }
+    check_resolved(
+        templates
+            .iter()
+            .map(|(path, template)| (path, template, variables)),
+    )
+}
+
+/// SARIF rules of the `check` subcommand, with their level.
+const SARIF_RULES: &[(&str, &str, &str)] = &[
+    (
+        "unresolved-variable",
+        "error",
+        "Placeholder for an undefined variable",
+    ),
+    (
+        "empty-name",
+        "warning",
+        "Placeholder without a variable name",
+    ),
+    (
+        "unbalanced-braces",
+        "warning",
+        "Placeholder without a closing brace",
+    ),
+    (
+        "nested-placeholder",
+        "warning",
+        "Placeholder inside another placeholder",
+    ),
+];
+
+/// Print a SARIF log of unresolved and malformed placeholders.
+///
+/// Like in text format, this fails if some variables are unresolved.
+fn check_sarif(
+    templates: &[(String, String)],
+    variables: &HashMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (log, failed) = sarif_log(templates, variables);
+    println!("{}", serde_json::to_string_pretty(&log)?);
+
+    match failed {
+        0 => Ok(()),
+        failed => Err(Unresolved(failed).into()),
+    }
+}
+
+/// Build a SARIF log of unresolved and malformed placeholders, along with
+/// the count of templates with unresolved variables.
+fn sarif_log(
+    templates: &[(String, String)],
+    variables: &HashMap<String, String>,
+) -> (serde_json::Value, usize) {
+    let mut results = Vec::new();
+    let mut failed = 0;
+    for (name, template) in templates {
+        let mut findings = Vec::new();
+        for segment in ksubst::template::parse(template) {
+            if let ksubst::template::Segment::Placeholder(p) = segment {
+                if !variables.contains_key(p.name) {
+                    let message = format!("variable `{}` is not defined", p.name);
+                    findings.push(("unresolved-variable", p.span, message));
+                }
+            }
+        }
+        if !findings.is_empty() {
+            failed += 1;
+        }
+        for diagnostic in ksubst::lint(template) {
+            let rule = match diagnostic.lint {
+                ksubst::Lint::EmptyName => "empty-name",
+                ksubst::Lint::UnbalancedBraces => "unbalanced-braces",
+                ksubst::Lint::NestedPlaceholder => "nested-placeholder",
+                _ => continue,
+            };
+            findings.push((rule, diagnostic.span, diagnostic.message));
+        }
+        findings.sort_by_key(|(_, span, _)| span.start);
+
+        for (rule, span, message) in findings {
+            let level = SARIF_RULES.iter().find(|r| r.0 == rule).map(|r| r.1);
+            let (start_line, start_column) = line_column(template, span.start);
+            let (end_line, end_column) = line_column(template, span.end);
+            results.push(serde_json::json!({
+                "ruleId": rule,
+                "level": level,
+                "message": {"text": message},
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": {"uri": name.replace('\\', "/")},
+                        "region": {
+                            "startLine": start_line,
+                            "startColumn": start_column,
+                            "endLine": end_line,
+                            "endColumn": end_column,
+                        },
+                    },
+                }],
+            }));
+        }
+    }
+
+    let rules: Vec<_> = SARIF_RULES
+        .iter()
+        .map(|(id, level, description)| {
+            serde_json::json!({
+                "id": id,
+                "shortDescription": {"text": description},
+                "defaultConfiguration": {"level": level},
+            })
+        })
+        .collect();
+    let log = serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "ksubst",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                    "rules": rules,
+                },
+            },
+            "columnKind": "unicodeCodePoints",
+            "results": results,
+        }],
+    });
+    (log, failed)
+}
+
+/// One-based line and column of a byte offset in a template.
+fn line_column(template: &str, offset: usize) -> (usize, usize) {
+    let before = &template[..offset];
+    let line_start = before.rfind('\n').map_or(0, |pos| pos + 1);
+    let line = before.matches('\n').count() + 1;
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// Print the variables referenced by templates.
+fn list_vars(
+    paths: &[String],
+    format: VarsFormat,
+    per_file: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let templates = read_templates(paths)?;
+    let mut stats = ksubst::UsageStats::new();
+    for (name, template) in &templates {
+        stats.add(name.as_str(), template);
+    }
+
+    match format {
+        VarsFormat::Text => {
+            for (variable, count) in stats.frequency() {
+                println!("{} {}", variable, count);
+            }
+            if per_file {
+                for (name, usage) in stats.matrix() {
+                    if usage.is_empty() {
+                        continue;
+                    }
+                    let usage: Vec<String> = usage
+                        .iter()
+                        .map(|(variable, count)| format!("{} ({})", variable, count))
+                        .collect();
+                    println!("{}: {}", name, usage.join(", "));
+                }
+            }
+        }
+        VarsFormat::Json => {
+            let output = serde_json::json!({
+                "variables": stats.frequency(),
+                "templates": stats.matrix(),
+            });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+        VarsFormat::Env => {
+            // Later declarations override earlier ones, like in JSON schemas
+            let mut documented = HashMap::new();
+            for (_, template) in &templates {
+                for info in ksubst::variables_metadata(template) {
+                    documented.insert(info.name.clone(), info);
+                }
+            }
+            for variable in stats.frequency().keys() {
+                let info = documented.get(*variable);
+                if let Some(description) = info.and_then(|info| info.description.as_ref()) {
+                    println!("# {}", description);
+                }
+                let default = info.and_then(|info| info.default.as_deref());
+                println!("{}={}", variable, default.unwrap_or_default());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read templates from files, directories or glob patterns, or stdin if
+/// there are no paths, along with their name.
+fn read_templates(paths: &[String]) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    if paths.is_empty() {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        return Ok(vec![("-".to_string(), input)]);
+    }
+
+    let mut templates = Vec::new();
+    for path in expand_globs(paths)? {
+        if Path::new(&path).is_dir() {
+            for entry in WalkDir::new(&path).sort_by_file_name() {
+                let entry = entry?;
+                if entry.file_type().is_file() {
+                    templates.push(entry.into_path());
+                }
+            }
+        } else {
+            templates.push(PathBuf::from(path));
+        }
+    }
+    templates
+        .iter()
+        .map(|path| {
+            let template = std::fs::read_to_string(path)?;
+            Ok((path.display().to_string(), template))
+        })
+        .collect()
+}
+
+/// Print the lint diagnostics of templates, failing on errors, or on
+/// warnings if denied.
+fn lint(paths: &[String], deny: Option<Deny>) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut errors, mut warnings) = (0, 0);
+    for (name, template) in read_templates(paths)? {
+        for diagnostic in ksubst::lint(&template) {
+            let (rule, error) = match diagnostic.lint {
+                ksubst::Lint::EmptyName => ("empty-name", true),
+                ksubst::Lint::UnbalancedBraces => ("unbalanced-braces", true),
+                ksubst::Lint::NestedPlaceholder => ("nested-placeholder", true),
+                ksubst::Lint::BareVariable => ("bare-variable", false),
+                ksubst::Lint::MixedCase => ("mixed-case", false),
+                _ => ("other", false),
+            };
+            let severity = match error {
+                true => "error",
+                false => "warning",
+            };
+            let (line, column) = line_column(&template, diagnostic.span.start);
+            eprintln!(
+                "ksubst: {}:{}:{}: {}: {} [{}]",
+                name, line, column, severity, diagnostic.message, rule
+            );
+            match error {
+                true => errors += 1,
+                false => warnings += 1,
+            }
+        }
+    }
+    if errors > 0 || (warnings > 0 && deny == Some(Deny::Warnings)) {
+        return Err(format!("lint found {} error(s) and {} warning(s)", errors, warnings).into());
+    }
+    Ok(())
+}
+
+/// Shortest secret values searched by pre-commit checks, shorter ones
+/// matching by chance.
+const MIN_SECRET_LEN: usize = 4;
+
+/// Check templates, or staged files, for malformed placeholders and values
+/// of `secrets`.
+fn pre_commit(
+    paths: &[String],
+    secrets: &[(String, String)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let templates = match paths.is_empty() {
+        true => staged_files()?,
+        false => read_templates(paths)?,
+    };
+    let mut failed = 0;
+    for (name, template) in &templates {
+        let mut findings = Vec::new();
+        for diagnostic in ksubst::lint(template) {
+            if matches!(
+                diagnostic.lint,
+                ksubst::Lint::EmptyName
+                    | ksubst::Lint::UnbalancedBraces
+                    | ksubst::Lint::NestedPlaceholder
+            ) {
+                findings.push((diagnostic.span.start, diagnostic.message));
+            }
+        }
+        for (secret, value) in secrets {
+            for (offset, _) in template.match_indices(value.as_str()) {
+                findings.push((offset, format!("value of secret `{}` committed", secret)));
+            }
+        }
+        findings.sort();
+        for (offset, message) in &findings {
+            let (line, column) = line_column(template, *offset);
+            eprintln!("ksubst: {}:{}:{}: {}", name, line, column, message);
+        }
+        if !findings.is_empty() {
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!("pre-commit checks failed in {} file(s)", failed).into()),
+    }
+}
+
+/// Files staged in git, in their staged version, except non-UTF-8 ones.
+fn staged_files() -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let git = |args: &[&str]| -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let output = std::process::Command::new("git").args(args).output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("git {}: {}", args[0], stderr.trim_end()).into());
+        }
+        Ok(output.stdout)
+    };
+    let names = git(&[
+        "diff",
+        "--cached",
+        "--name-only",
+        "-z",
+        "--diff-filter=ACMR",
+    ])?;
+    let mut files = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let name = String::from_utf8_lossy(name);
+        if let Ok(content) = String::from_utf8(git(&["show", &format!(":{}", name)])?) {
+            files.push((name.into_owned(), content));
+        }
+    }
+    Ok(files)
+}
+
+/// Fail if some templates have placeholders for undefined variables.
+///
+/// Every offending template is reported on stderr, with the names of its
+/// undefined variables.
+fn check_resolved<'a, N, T, I>(templates: I) -> Result<(), Box<dyn std::error::Error>>
+where
+    N: std::fmt::Display,
+    T: AsRef<str>,
+    I: IntoIterator<Item = (N, T, &'a HashMap<String, String>)>,
+{
+    let mut failed = 0;
+    for (name, template, variables) in templates {
+        let mut missing = Vec::new();
+        for segment in ksubst::template::parse(template.as_ref()) {
+            if let ksubst::template::Segment::Placeholder(p) = segment {
+                if !variables.contains_key(p.name) && !missing.contains(&p.name) {
+                    missing.push(p.name);
+                }
+            }
+        }
+        if !missing.is_empty() {
+            eprintln!(
+                "ksubst: {}: unresolved variables {}",
+                name,
+                missing.join(", ")
+            );
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(Unresolved(failed).into()),
+    }
+}
+
+/// Fail if a rendered output still contains placeholders of `syntax`, with
+/// `--fail-if-templated`.
+fn check_templated(
+    name: &str,
+    output: &str,
+    syntax: &ksubst::Syntax,
+    args: &RenderArgs,
+) -> Result<(), String> {
+    if !args.fail_if_templated {
+        return Ok(());
+    }
+    let translated = syntax.translate(output);
+    let mut left = Vec::new();
+    for segment in ksubst::template::parse(&translated) {
+        if let ksubst::template::Segment::Placeholder(p) = segment {
+            if !left.contains(&p.name) {
+                left.push(p.name);
+            }
+        }
+    }
+    match left.is_empty() {
+        true => Ok(()),
+        false => Err(format!(
+            "{}: output still contains placeholders for {}",
+            name,
+            left.join(", ")
+        )),
+    }
+}
+
+/// Print the unresolved variables of input files, with the lines
+/// referencing them, failing if there are any.
+fn list_missing(
+    inputs: &[&InputFile<'_>],
+    variables: &HashMap<String, String>,
+    args: &RenderArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut missing: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut failed = 0;
+    for input in inputs {
+        let name = input.relative_path.display();
+        let (content, _) = input.read()?;
+        let Ok((text, encoding)) = decode_text(content, args.encoding) else {
+            continue;
+        };
+        // Lines are those of the file itself, not of its included templates
+        let source = template_syntax(args).translate(&text);
+        let template = Template::new(&name, text, encoding, variables, args)?;
+        let mut unresolved = false;
+        for segment in ksubst::template::parse(&source) {
+            if let ksubst::template::Segment::Placeholder(p) = segment {
+                if !template.context.contains_key(p.name) {
+                    let (line, _) = line_column(&source, p.span.start);
+                    let references = missing.entry(p.name.to_string()).or_default();
+                    references.push(format!("{}:{}", name, line));
+                    unresolved = true;
+                }
+            }
+        }
+        failed += usize::from(unresolved);
+        SUMMARY.record(&source, &template.context);
+    }
+
+    let mut stdout = io::stdout().lock();
+    for (name, references) in &missing {
+        writeln!(stdout, "{}", name)?;
+        for reference in references {
+            writeln!(stdout, "  {}", reference)?;
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        failed => Err(Unresolved(failed).into()),
+    }
+}
+
+/// Remove the contents of an output directory, keeping the directory itself.
+///
+/// This refuses to clean the root directory, or a directory containing an
+/// input directory.
+fn clean_output_dir(
+    input_dirs: &[String],
+    output_dir: &str,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Missing output directories have nothing to clean
+    let Ok(output) = std::fs::canonicalize(output_dir) else {
+        return Ok(());
+    };
+    if output.parent().is_none() {
+        return Err(format!("refusing to clean root directory {}", output_dir).into());
+    }
+    for input_dir in input_dirs {
+        if std::fs::canonicalize(input_dir)?.starts_with(&output) {
+            return Err(format!(
+                "refusing to clean {}, which contains input directory {}",
+                output_dir, input_dir
+            )
+            .into());
+        }
+    }
+
+    if dry_run {
+        println!("clean {}/", output_dir);
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(&output)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    message!(VERBOSE, "cleaned {}", output_dir);
+    Ok(())
+}
+
+/// Delete output files and directories which don't exist in any input
+/// directory, and are not among the `outputs` of the run.
+///
+/// Files skipped because of exclude or filter patterns still exist in the
+/// input directories, so their outputs are kept, like the state file.
+fn delete_stale_outputs(
+    input_dirs: &[String],
+    output_dir: &str,
+    outputs: &[PathBuf],
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Visit directory contents first, so that emptied directories can be removed
+    for entry in WalkDir::new(output_dir).min_depth(1).contents_first(true) {
+        let entry = entry?;
+        let relative_path = entry.path().strip_prefix(output_dir)?;
+        if relative_path == Path::new(STATE_FILE) {
+            continue;
+        }
+        let sources = || {
+            input_dirs
+                .iter()
+                .map(|dir| Path::new(dir).join(relative_path))
+        };
+
+        if entry.file_type().is_dir() {
+            if !sources().any(|source| source.is_dir())
+                && !outputs.iter().any(|o| o.starts_with(relative_path))
+            {
+                if dry_run {
+                    println!("delete {}/", relative_path.display());
+                } else {
+                    std::fs::remove_dir(entry.path())?;
+                }
+            }
+        } else if !sources().any(|source| source.is_file() || source.is_symlink())
+            && !outputs.iter().any(|o| o == relative_path)
+        {
+            if dry_run {
+                println!("delete {}", relative_path.display());
+            } else {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+    }
+
+    Ok(())
 }
 
 fn parse_env_vars(s: &str) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
@@ -158,12 +4608,1148 @@ fn parse_env_vars(s: &str) -> Result<HashMap<String, String>, Box<dyn std::error
             .ok_or("Missing key in env-vars")?
             .trim()
             .to_string();
-        let value = iter
-            .next()
-            .ok_or("Missing value in env-vars")?
-            .trim()
-            .to_string();
+        let value = iter.next().ok_or("Missing value in env-vars")?.trim();
+        let value = read_value(&key, value)?;
         vars.insert(key, value);
     }
     Ok(vars)
 }
+
+/// Get the value of a command-line variable, reading it from a file if
+/// given as '@path'.
+fn read_value(key: &str, value: &str) -> Result<String, Box<dyn std::error::Error>> {
+    match value.strip_prefix('@') {
+        Some(path) => std::fs::read_to_string(path).map_err(|e| {
+            format!("failed to read value of '{}' from '{}': {}", key, path, e).into()
+        }),
+        None => Ok(value.to_string()),
+    }
+}
+
+/// Parse an archive path, with an extension giving its format.
+fn parse_archive_path(s: &str) -> Result<(String, ArchiveFormat), String> {
+    let format = if s.ends_with(".tar") {
+        ArchiveFormat::Tar
+    } else if s.ends_with(".tar.gz") || s.ends_with(".tgz") {
+        ArchiveFormat::TarGz
+    } else if s.ends_with(".zip") {
+        ArchiveFormat::Zip
+    } else {
+        return Err(format!(
+            "expected a .tar, .tar.gz, .tgz or .zip archive, got '{}'",
+            s
+        ));
+    };
+    Ok((s.to_string(), format))
+}
+
+/// Parse a 'KEY=VALUE' command-line argument.
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected 'KEY=VALUE', got '{}'", s)),
+    }
+}
+
+/// Placeholder syntax of templates, from '--syntax' or '--delims'.
+fn template_syntax(args: &RenderArgs) -> ksubst::Syntax {
+    if let Some(delimiters) = &args.delims {
+        return ksubst::Syntax::Delimiters(delimiters.clone());
+    }
+    match args.syntax {
+        Syntax::Braces => ksubst::Syntax::Braces,
+        Syntax::Dollar => ksubst::Syntax::Dollar,
+        Syntax::Percent => ksubst::Syntax::Percent,
+        Syntax::Mustache => ksubst::Syntax::Mustache,
+    }
+}
+
+/// Parse 'OPEN,CLOSE' placeholder delimiters.
+fn parse_delimiters(s: &str) -> Result<ksubst::Delimiters, String> {
+    let (open, close) = s
+        .split_once(',')
+        .ok_or_else(|| format!("expected 'OPEN,CLOSE', got '{}'", s))?;
+    ksubst::Delimiters::new(open, close).map_err(|e| e.to_string())
+}
+
+/// Filter stdin to stdout as a git smudge filter, or a clean filter
+/// restoring placeholders of the indexed template of `clean` if set.
+fn git_filter(sources: &Sources, clean: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(mut variables) = load_variables(sources, None)? else {
+        return Err("git filters require variables sources, like --env-file".into());
+    };
+    apply_defaults(&mut variables, &sources.defaults);
+
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input)?;
+    let output = match (String::from_utf8(input), clean) {
+        (Ok(input), Some(path)) => match indexed_template(path)? {
+            Some(template) => restore_placeholders(&template, &input, &variables)?.into_bytes(),
+            None => input.into_bytes(),
+        },
+        (Ok(input), None) => substitute(input, &variables)?.into_bytes(),
+        (Err(e), _) => e.into_bytes(),
+    };
+    io::stdout().write_all(&output)?;
+    Ok(())
+}
+
+/// Content of a file in the git index, if it is indexed and UTF-8.
+fn indexed_template(path: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let output = std::process::Command::new("git")
+        .args(["show", &format!(":./{}", path)])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run git: {}", e))?;
+    if !output.status.success() {
+        message!(
+            VERBOSE,
+            "{} is not in the git index, keeping it as-is",
+            path
+        );
+        return Ok(None);
+    }
+    Ok(String::from_utf8(output.stdout).ok())
+}
+
+/// Restore the placeholders of `template` in `text`, a rendering of it
+/// which may have been edited since.
+///
+/// The template is rendered segment by segment, and compared to `text`:
+/// segments whose rendering is unchanged in `text` are restored, and any
+/// other text is kept as-is, so values are never replaced elsewhere.
+fn restore_placeholders(
+    template: &str,
+    text: &str,
+    variables: &HashMap<String, String>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut spans = Vec::new();
+    for segment in ksubst::template::parse(template) {
+        let start = rendered.len();
+        match segment {
+            ksubst::template::Segment::Literal(literal) => rendered.push_str(literal.text),
+            segment => {
+                rendered += &substitute(segment.as_str(), variables)?;
+                spans.push((start..rendered.len(), segment.as_str()));
+            }
+        }
+    }
+    if rendered == text {
+        return Ok(template.to_string());
+    }
+
+    // A deadline only makes the diff less minimal, never wrong
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let ops = similar::capture_diff_slices_deadline(
+        similar::Algorithm::Myers,
+        rendered.as_bytes(),
+        text.as_bytes(),
+        Some(deadline),
+    );
+    let mut output = Vec::with_capacity(text.len());
+    let mut spans = spans.into_iter().peekable();
+    for op in ops {
+        let (tag, old, new) = op.as_tag_tuple();
+        if tag != similar::DiffTag::Equal {
+            output.extend_from_slice(&text.as_bytes()[new]);
+            continue;
+        }
+        // Segments starting in changed text are kept as rendered
+        while spans.next_if(|(span, _)| span.start < old.start).is_some() {}
+        let mut copied = new.start;
+        while let Some((span, source)) = spans
+            .next_if(|(span, _)| span.start < old.end || (span.is_empty() && span.start == old.end))
+        {
+            if span.end <= old.end {
+                let start = new.start + span.start - old.start;
+                output.extend_from_slice(&text.as_bytes()[copied..start]);
+                output.extend_from_slice(source.as_bytes());
+                copied = start + span.len();
+            }
+        }
+        output.extend_from_slice(&text.as_bytes()[copied..new.end]);
+    }
+    Ok(String::from_utf8(output)?)
+}
+
+/// Write the entries of a templated .env file with variables substituted,
+/// failing without output if some are unresolved.
+fn render_env<W: Write>(
+    path: &str,
+    format: EnvFormat,
+    variables: &HashMap<String, String>,
+    output: &mut W,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let content = read_source(path)?;
+    let marked = mark_env_references(&content);
+    let mut entries: Vec<(String, String)> = Vec::new();
+    for entry in dotenvy::from_read_iter(marked.as_bytes()) {
+        let (key, value) =
+            entry.map_err(|e| format!("failed to parse entries of '{}': {}", path, e))?;
+        let shell_name = key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if format == EnvFormat::Export && !shell_name {
+            return Err(
+                format!("'{}' in '{}' is not a valid shell variable name", key, path).into(),
+            );
+        }
+        let value = value.replace(ENV_REFERENCE, "$");
+        match entries.iter_mut().find(|(name, _)| *name == key) {
+            Some(entry) => entry.1 = value,
+            None => entries.push((key, value)),
+        }
+    }
+
+    let mut context = variables.clone();
+    let mut missing = Vec::new();
+    for (key, value) in &mut entries {
+        for segment in ksubst::template::parse(value) {
+            if let ksubst::template::Segment::Placeholder(p) = segment {
+                if !context.contains_key(p.name) && !missing.iter().any(|name| name == p.name) {
+                    missing.push(p.name.to_string());
+                }
+            }
+        }
+        *value = substitute(value.as_str(), &context)?;
+        if !ksubst::is_templated(value.as_str()) {
+            context.insert(key.clone(), value.clone());
+        }
+    }
+    if !missing.is_empty() {
+        eprintln!(
+            "ksubst: {}: unresolved variables {}",
+            path,
+            missing.join(", ")
+        );
+        return Err(Unresolved(1).into());
+    }
+
+    for (key, value) in &entries {
+        let prefix = match format {
+            EnvFormat::Export => "export ",
+            EnvFormat::Dotenv => "",
+        };
+        writeln!(
+            output,
+            "{}{}={}",
+            prefix,
+            key,
+            quote_env_value(value, format)
+        )?;
+    }
+    Ok(())
+}
+
+/// Quote a value in single quotes, for shells or .env files.
+///
+/// Single quotes can't be escaped in single-quoted .env values, so those
+/// values are double-quoted, with escapes, in .env files instead.
+fn quote_env_value(value: &str, format: EnvFormat) -> String {
+    match format {
+        EnvFormat::Export => format!("'{}'", value.replace('\'', "'\\''")),
+        EnvFormat::Dotenv if !value.contains('\'') => format!("'{}'", value),
+        EnvFormat::Dotenv => {
+            let mut quoted = String::with_capacity(value.len() + 2);
+            quoted.push('"');
+            for c in value.chars() {
+                match c {
+                    '\\' | '"' | '$' => {
+                        quoted.push('\\');
+                        quoted.push(c);
+                    }
+                    '\n' => quoted.push_str("\\n"),
+                    _ => quoted.push(c),
+                }
+            }
+            quoted.push('"');
+            quoted
+        }
+    }
+}
+
+/// Run a command with variables substituted in its arguments and environment.
+///
+/// On Unix, the command replaces the current process. Elsewhere, it runs
+/// as a child process, and its exit code is forwarded.
+fn exec(command: &[String], sources: &Sources) -> Result<(), Box<dyn std::error::Error>> {
+    let mut child = exec_command(command, sources)?;
+    let program = child.get_program().to_string_lossy().into_owned();
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let err = child.exec();
+        Err(format!("failed to execute '{}': {}", program, err).into())
+    }
+    #[cfg(not(unix))]
+    {
+        let status = child
+            .status()
+            .map_err(|e| format!("failed to execute '{}': {}", program, e))?;
+        std::process::exit(status.code().unwrap_or(1));
+    }
+}
+
+/// Build the command run by `exec`, with substituted arguments and
+/// environment.
+fn exec_command(
+    command: &[String],
+    sources: &Sources,
+) -> Result<std::process::Command, Box<dyn std::error::Error>> {
+    let mut environment: HashMap<String, String> = env::vars().collect();
+    let (mut variables, loaded) = source_variables(sources)?;
+    if loaded {
+        environment.extend(variables.clone());
+    }
+    apply_defaults(&mut variables, &sources.defaults);
+
+    let args = command
+        .iter()
+        .map(|arg| substitute(arg, &variables))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut child = std::process::Command::new(&args[0]);
+    child.args(&args[1..]).env_clear();
+    for (key, value) in &environment {
+        child.env(key, substitute(value, &variables)?);
+    }
+    Ok(child)
+}
+
+/// Maximum accepted size for HTTP request bodies.
+const MAX_REQUEST_SIZE: u64 = 1024 * 1024;
+
+/// Maximum time allowed to receive a request body.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(serde::Deserialize)]
+struct RenderRequest {
+    template: String,
+    #[serde(default)]
+    variables: HashMap<String, String>,
+}
+
+/// Handled request, with what is logged about it.
+struct Handled {
+    status: u16,
+    body: serde_json::Value,
+    /// Names of the variables substituted, never their values.
+    used: BTreeSet<String>,
+    /// Names of the variables left unresolved.
+    missing: BTreeSet<String>,
+    /// Rejection reason.
+    error: Option<String>,
+}
+
+impl Handled {
+    fn new(status: u16, body: serde_json::Value) -> Self {
+        let error = body.get("error").and_then(|e| e.as_str()).map(String::from);
+        Handled {
+            status,
+            body,
+            used: BTreeSet::new(),
+            missing: BTreeSet::new(),
+            error,
+        }
+    }
+
+    /// Rejected render, whose logged reason has variables values redacted
+    /// since clients may send secrets.
+    fn rejected(status: u16, body: serde_json::Value, variables: &HashMap<String, String>) -> Self {
+        let mut handled = Handled::new(status, body);
+        let mut values: Vec<_> = variables.values().filter(|v| !v.is_empty()).collect();
+        values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+        handled.error = handled.error.map(|mut error| {
+            for value in values {
+                error = error.replace(value.as_str(), REDACTED);
+            }
+            error
+        });
+        handled
+    }
+}
+
+/// Reader failing once a deadline has passed, so that clients sending
+/// their request slowly don't hold a worker for longer.
+struct DeadlineReader<R> {
+    inner: R,
+    deadline: Instant,
+}
+
+impl<R: Read> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if Instant::now() > self.deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "request body not received in time",
+            ));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Serve the rendering endpoints until the process is terminated.
+///
+/// - `POST /render` renders the template and returns `{"output": ...}`.
+/// - `POST /check` validates variables and returns whether the rendered
+///   output would be fully resolved.
+///
+/// Requests are handled by `workers` threads. Every request is logged to
+/// stderr, with the names of the variables used and the rejection reason.
+fn serve(
+    listen: &str,
+    workers: usize,
+    defaults: &HashMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = tiny_http::Server::http(listen).map_err(|e| e.to_string())?;
+    eprintln!("ksubst: listening on http://{}", server.server_addr());
+
+    let server = &server;
+    std::thread::scope(|scope| {
+        for _ in 0..workers.max(1) {
+            scope.spawn(move || {
+                while let Ok(request) = server.recv() {
+                    respond(request, defaults);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn respond(mut request: tiny_http::Request, defaults: &HashMap<String, String>) {
+    let handled = handle_request(&mut request, defaults);
+
+    let remote = request
+        .remote_addr()
+        .map_or_else(|| "-".to_string(), |addr| addr.to_string());
+    let mut line = format!(
+        "ksubst: {} {} {} {}",
+        remote,
+        request.method(),
+        request.url(),
+        handled.status
+    );
+    for (label, names) in [("used", &handled.used), ("missing", &handled.missing)] {
+        if !names.is_empty() {
+            let names: Vec<_> = names.iter().map(String::as_str).collect();
+            line.push_str(&format!(" {}={}", label, names.join(",")));
+        }
+    }
+    if let Some(error) = &handled.error {
+        line.push_str(&format!(" error={:?}", error));
+    }
+    eprintln!("{}", line);
+
+    let header = tiny_http::Header::from_bytes("Content-Type", "application/json").unwrap();
+    let response = tiny_http::Response::from_string(handled.body.to_string())
+        .with_status_code(handled.status)
+        .with_header(header);
+    if let Err(e) = request.respond(response) {
+        eprintln!("ksubst: failed to send response: {}", e);
+    }
+}
+
+fn handle_request(request: &mut tiny_http::Request, defaults: &HashMap<String, String>) -> Handled {
+    use serde_json::json;
+
+    let check = match (request.method(), request.url()) {
+        (tiny_http::Method::Post, "/render") => false,
+        (tiny_http::Method::Post, "/check") => true,
+        (_, "/render") | (_, "/check") => {
+            return Handled::new(405, json!({ "error": "method not allowed" }));
+        }
+        _ => return Handled::new(404, json!({ "error": "not found" })),
+    };
+
+    let mut body = String::new();
+    let mut reader = DeadlineReader {
+        inner: request.as_reader().take(MAX_REQUEST_SIZE + 1),
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    };
+    if let Err(e) = reader.read_to_string(&mut body) {
+        let status = match e.kind() {
+            io::ErrorKind::TimedOut => 408,
+            _ => 400,
+        };
+        return Handled::new(status, json!({ "error": e.to_string() }));
+    }
+    if body.len() as u64 > MAX_REQUEST_SIZE {
+        return Handled::new(413, json!({ "error": "request body too large" }));
+    }
+    let req: RenderRequest = match serde_json::from_str(&body) {
+        Ok(req) => req,
+        Err(e) => return Handled::new(400, json!({ "error": e.to_string() })),
+    };
+
+    let mut variables = defaults.clone();
+    variables.extend(req.variables);
+
+    let rendered = match ksubst::Substituter::new().render(req.template, &variables) {
+        Ok(rendered) => rendered,
+        Err(e) if check => {
+            let body = json!({ "valid": false, "error": e.to_string() });
+            return Handled::rejected(200, body, &variables);
+        }
+        Err(e) => return Handled::rejected(422, json!({ "error": e.to_string() }), &variables),
+    };
+    let body = match check {
+        false => json!({ "output": rendered.output }),
+        true => json!({ "valid": true, "resolved": !ksubst::is_templated(&rendered.output) }),
+    };
+    Handled {
+        used: rendered
+            .report
+            .resolved
+            .into_iter()
+            .map(|r| r.name)
+            .collect(),
+        missing: rendered
+            .report
+            .unresolved
+            .into_iter()
+            .map(|u| u.name)
+            .collect(),
+        ..Handled::new(200, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_round_trip() {
+        let variables =
+            ksubst::context! {"PORT" => "80", "DEBUG" => "1", "HOST" => "localhost", "E" => ""};
+        let template =
+            "workers 1\nserver localhost:8080\nlisten ${HOST}:${PORT}${E}\ndebug ${DEBUG}\n";
+        let smudged = substitute(template, &variables).unwrap();
+        assert_eq!(
+            smudged,
+            "workers 1\nserver localhost:8080\nlisten localhost:80\ndebug 1\n"
+        );
+        let cleaned = restore_placeholders(template, &smudged, &variables).unwrap();
+        assert_eq!(cleaned, template);
+    }
+
+    #[test]
+    fn clean_edited() {
+        let variables = ksubst::context! {"PORT" => "80", "HOST" => "localhost"};
+        let template = "listen ${HOST}:${PORT}\n# end\n";
+        // Edits around values keep their placeholders, edited values are kept
+        let edited = "listen  localhost:9090\nworkers 1\n# end\nhost localhost\n";
+        let cleaned = restore_placeholders(template, edited, &variables).unwrap();
+        assert_eq!(
+            cleaned,
+            "listen  ${HOST}:9090\nworkers 1\n# end\nhost localhost\n"
+        );
+        assert_eq!(substitute(cleaned, &variables).unwrap(), edited);
+
+        for edited in ["", "listen localhost:8080\n", "localhost:80 listen"] {
+            let cleaned = restore_placeholders(template, edited, &variables).unwrap();
+            assert_eq!(substitute(cleaned, &variables).unwrap(), edited);
+        }
+        let cleaned =
+            restore_placeholders("${MISSING} é${HOST}", "${MISSING} à localhost", &variables);
+        assert_eq!(cleaned.unwrap(), "${MISSING} à ${HOST}");
+    }
+
+    #[test]
+    fn serve_rejections() {
+        let variables = ksubst::context! {"TOKEN" => "s3cr$t", "E" => ""};
+        let body = serde_json::json!({ "error": "value 's3cr$t' contains '$'" });
+        let handled = Handled::rejected(422, body.clone(), &variables);
+        assert_eq!(handled.error.unwrap(), "value '***' contains '$'");
+        assert_eq!(handled.body, body);
+
+        let mut reader = DeadlineReader {
+            inner: "body".as_bytes(),
+            deadline: Instant::now() - Duration::from_secs(1),
+        };
+        let err = reader.read_to_string(&mut String::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    /// Create an empty temporary directory for a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ksubst-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write files under a directory, creating their parent directories.
+    fn write_files(dir: &Path, files: &[(&str, &str)]) {
+        for (path, content) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+    }
+
+    /// Paths relative to a directory and contents of the files under it,
+    /// except state files.
+    fn read_files(dir: &Path) -> BTreeMap<String, String> {
+        WalkDir::new(dir)
+            .into_iter()
+            .map(Result::unwrap)
+            .filter(|entry| entry.file_type().is_file() && entry.file_name() != STATE_FILE)
+            .map(|entry| {
+                let path = entry.path().strip_prefix(dir).unwrap();
+                let content = std::fs::read_to_string(entry.path()).unwrap();
+                (path.to_string_lossy().into_owned(), content)
+            })
+            .collect()
+    }
+
+    /// Run a recursive render from input directory `in` to `out` of `dir`.
+    fn render_tree(dir: &Path, flags: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let (input, output) = (dir.join("in"), dir.join("out"));
+        let mut args = vec!["ksubst", "-e", "A=1", "--no-summary", "-r"];
+        args.extend(flags);
+        args.extend([input.to_str().unwrap(), output.to_str().unwrap()]);
+        run(Args::try_parse_from(args)?)
+    }
+
+    #[test]
+    fn clean_refusals() {
+        let dir = temp_dir("clean");
+        write_files(
+            &dir,
+            &[("in/sub/a", "a"), ("out/b", "b"), ("out/sub/c", "c")],
+        );
+        let input = dir.join("in").to_str().unwrap().to_string();
+        let inputs = [input.clone()];
+
+        // Dry runs, so that nothing is removed if refusals regress
+        let parents = [
+            dir.to_str().unwrap().to_string(),
+            format!("{}/sub/../..", input),
+        ];
+        for output in ["/".to_string(), input.clone()].iter().chain(&parents) {
+            let err = clean_output_dir(&inputs, output, true).unwrap_err();
+            assert!(err.to_string().starts_with("refusing to clean"), "{}", err);
+        }
+
+        let output = dir.join("out");
+        clean_output_dir(&inputs, output.to_str().unwrap(), true).unwrap();
+        assert_eq!(read_files(&output).len(), 2);
+        clean_output_dir(&inputs, output.to_str().unwrap(), false).unwrap();
+        assert!(output.is_dir());
+        assert_eq!(std::fs::read_dir(&output).unwrap().count(), 0);
+        assert_eq!(read_files(&dir.join("in")).len(), 1);
+        // Missing output directories have nothing to clean
+        clean_output_dir(&inputs, dir.join("missing").to_str().unwrap(), false).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run() {
+        let dir = temp_dir("dry-run");
+        let files = [
+            ("in/a", "${A}"),
+            ("in/sub/b", "b ${A}"),
+            ("out/a", "edited"),
+            ("out/stale", "stale"),
+            ("out/old/c", "c"),
+        ];
+        write_files(&dir, &files);
+        let output = dir.join("out");
+        let before = read_files(&output);
+
+        for flags in [&["--force", "--delete"][..], &["--clean"], &["--update"]] {
+            render_tree(&dir, &[&["--dry-run"], flags].concat()).unwrap();
+            assert_eq!(read_files(&output), before, "{:?}", flags);
+            assert!(!output.join("sub").exists());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn delete_stale() {
+        let dir = temp_dir("delete");
+        let files = [
+            ("in/a", "${A}"),
+            ("in/skipped.log", "log"),
+            ("in/sub/.ksubstignore", "ignored\n"),
+            ("in/sub/ignored", "ignored"),
+            ("out/skipped.log", "old log"),
+            ("out/sub/ignored", "old"),
+            ("out/stale", "stale"),
+            ("out/old/stale", "stale"),
+        ];
+        write_files(&dir, &files);
+
+        render_tree(&dir, &["--delete", "--exclude", "*.log"]).unwrap();
+        let outputs = read_files(&dir.join("out"));
+        let expected = [
+            ("a", "1"),
+            ("skipped.log", "old log"),
+            ("sub/ignored", "old"),
+        ];
+        let expected: BTreeMap<_, _> = expected
+            .iter()
+            .map(|(path, content)| (path.to_string(), content.to_string()))
+            .collect();
+        assert_eq!(outputs, expected);
+        assert!(!dir.join("out/old").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overwrite_protection() {
+        let dir = temp_dir("overwrite");
+        write_files(&dir, &[("out", "edited")]);
+        let output = dir.join("out");
+        let modified = std::fs::metadata(&output).unwrap().modified().unwrap();
+        let input = |modified| Attributes {
+            permissions: None,
+            accessed: None,
+            modified: Some(modified),
+            owner: None,
+        };
+        let (older, newer) = (
+            input(modified - Duration::from_secs(60)),
+            input(modified + Duration::from_secs(60)),
+        );
+        let args = |flags: &[&str]| {
+            let args = [&["ksubst", "-r"], flags, &["in", "out"]].concat();
+            Args::try_parse_from(args).unwrap().render
+        };
+
+        // Missing, unchanged and never rendered outputs are always written
+        let missing = dir.join("missing");
+        assert!(may_overwrite(&newer, &missing, b"new", &args(&[])).unwrap());
+        assert!(may_overwrite(&older, &output, b"edited", &args(&[])).unwrap());
+        assert!(may_overwrite(&newer, &output, b"new", &args(&[])).unwrap());
+
+        // Outputs edited since they were rendered are protected
+        let rendered = sha256_hex(b"rendered");
+        RENDERED.lock().unwrap().insert(output.clone(), rendered);
+        let err = may_overwrite(&newer, &output, b"new", &args(&[])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        assert!(may_overwrite(&older, &output, b"new", &args(&["--force"])).unwrap());
+        assert!(!may_overwrite(&older, &output, b"new", &args(&["--update"])).unwrap());
+        assert!(may_overwrite(&newer, &output, b"new", &args(&["--update"])).unwrap());
+        assert!(Args::try_parse_from(["ksubst", "-r", "-f", "-u", "in", "out"]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rendered_state() {
+        let dir = temp_dir("rerender");
+        write_files(
+            &dir,
+            &[("in/a", "${A}"), ("in/b", "b${A}"), ("out/a", "old")],
+        );
+        let output = dir.join("out");
+
+        // Outputs rendered with other variables are not edits
+        render_tree(&dir, &[]).unwrap();
+        assert!(output.join(STATE_FILE).is_file());
+        render_tree(&dir, &["-e", "A=2"]).unwrap();
+        assert_eq!(std::fs::read_to_string(output.join("a")).unwrap(), "2");
+
+        std::fs::write(output.join("a"), "edited").unwrap();
+        for _ in 0..2 {
+            let err = render_tree(&dir, &["-e", "A=3"]).unwrap_err();
+            assert!(err.to_string().contains("was edited"), "{}", err);
+            assert_eq!(std::fs::read_to_string(output.join("a")).unwrap(), "edited");
+        }
+        assert_eq!(std::fs::read_to_string(output.join("b")).unwrap(), "b3");
+        render_tree(&dir, &["-e", "A=3", "--force"]).unwrap();
+        render_tree(&dir, &["-e", "A=4", "--delete"]).unwrap();
+        let outputs = read_files(&output);
+        assert_eq!(outputs.keys().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(outputs["a"], "4");
+        assert!(output.join(STATE_FILE).is_file());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn watch_rerender() {
+        let dir = temp_dir("watch");
+        let files = [
+            ("in/a", "${A}"),
+            ("in/.hidden", "${A}"),
+            ("in/sub/.ksubstignore", "ignored\n"),
+            ("in/sub/ignored", "${A}"),
+            ("in/bin", "\0${A}"),
+            ("in/c.tpl", "${A}"),
+            ("env", "A=1\n"),
+        ];
+        write_files(&dir, &files);
+        let (input, output, env) = (dir.join("in"), dir.join("out"), dir.join("env"));
+        let args = Args::try_parse_from([
+            "ksubst",
+            "--env-file",
+            env.to_str().unwrap(),
+            "-r",
+            "--template-ext",
+            "tpl",
+            "--templates-only",
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+        ])
+        .unwrap();
+        let (input_dirs, output_dir) = (&args.render.paths[..1], &args.render.paths[1]);
+        let mut variables = self::variables(&args.sources).unwrap();
+        let changes = |changed: &[&str], variables: &mut HashMap<String, String>| {
+            let root = dir.canonicalize().unwrap();
+            let changed: Vec<_> = changed.iter().map(|path| root.join(path)).collect();
+            let report = RunReport::new(false);
+            rerender(
+                &changed,
+                input_dirs,
+                output_dir,
+                variables,
+                &args.sources,
+                &args.render,
+                &report,
+            )
+        };
+
+        // Changed files are selected like in recursive runs
+        let changed = ["in/a", "in/.hidden", "in/sub/ignored", "in/bin", "in/c.tpl"];
+        changes(&changed, &mut variables).unwrap();
+        let expected = [("a", "${A}"), ("bin", "\0${A}"), ("c", "1")];
+        let expected: BTreeMap<_, _> = expected
+            .iter()
+            .map(|(path, content)| (path.to_string(), content.to_string()))
+            .collect();
+        assert_eq!(read_files(&output), expected);
+
+        // Changed variables render all templates, protecting edited outputs
+        std::fs::write(&env, "A=2\n").unwrap();
+        changes(&["env"], &mut variables).unwrap();
+        assert_eq!(std::fs::read_to_string(output.join("c")).unwrap(), "2");
+        std::fs::write(output.join("c"), "edited").unwrap();
+        std::fs::write(&env, "A=3\n").unwrap();
+        let err = changes(&["env"], &mut variables).unwrap_err();
+        assert!(err.to_string().contains("was edited"), "{}", err);
+        assert_eq!(variables["A"], "3");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    /// Paths and contents of the file entries of an archive.
+    fn archive_files(archive: &Path) -> BTreeMap<String, String> {
+        let (path, format) = parse_archive_path(archive.to_str().unwrap()).unwrap();
+        read_archive(&path, format)
+            .unwrap()
+            .into_iter()
+            .map(|(path, entry)| {
+                let content = String::from_utf8(entry.content).unwrap();
+                (path.to_string_lossy().into_owned(), content)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn output_archive() {
+        let dir = temp_dir("output-archive");
+        write_files(
+            &dir,
+            &[("in/a", "a=${A}"), ("in/sub/b", "b"), ("in2/c", "${A}")],
+        );
+        let (input, input2) = (dir.join("in"), dir.join("in2"));
+        let render = |archive: &Path| {
+            run(Args::try_parse_from([
+                "ksubst",
+                "-e",
+                "A=1",
+                "--no-summary",
+                "-r",
+                "--output-archive",
+                archive.to_str().unwrap(),
+                input.to_str().unwrap(),
+                input2.to_str().unwrap(),
+            ])
+            .unwrap())
+        };
+
+        let expected: BTreeMap<_, _> = [("a", "a=1"), ("c", "1"), ("sub/b", "b")]
+            .iter()
+            .map(|(path, content)| (path.to_string(), content.to_string()))
+            .collect();
+        for name in ["out.tar", "out.tar.gz", "out.zip"] {
+            let archive = dir.join(name);
+            render(&archive).unwrap();
+            assert_eq!(archive_files(&archive), expected, "{}", name);
+
+            // Archives of the same outputs are identical
+            let first = std::fs::read(&archive).unwrap();
+            render(&archive).unwrap();
+            assert_eq!(std::fs::read(&archive).unwrap(), first, "{}", name);
+        }
+        // Only archives are written, without state files
+        let mut names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["in", "in2", "out.tar", "out.tar.gz", "out.zip"]);
+        assert!(!input.join(STATE_FILE).exists());
+
+        // Archives are written only once all outputs are rendered
+        write_files(&dir, &[("in2/d", "${MISSING}")]);
+        let archive = dir.join("strict.tar");
+        let err = run(Args::try_parse_from([
+            "ksubst",
+            "-e",
+            "A=1",
+            "--no-summary",
+            "-r",
+            "--strict",
+            "--output-archive",
+            archive.to_str().unwrap(),
+            input.to_str().unwrap(),
+            input2.to_str().unwrap(),
+        ])
+        .unwrap())
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("unresolved placeholders"),
+            "{}",
+            err
+        );
+        assert!(!archive.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    /// Write an archive of files, in the format given by its extension.
+    fn write_archive_files(archive: &Path, files: &[(&str, &str)]) {
+        let (_, format) = parse_archive_path(archive.to_str().unwrap()).unwrap();
+        let content = match format {
+            ArchiveFormat::Tar | ArchiveFormat::TarGz => {
+                let mut builder = tar::Builder::new(Vec::new());
+                for (path, content) in files {
+                    let mut header = tar::Header::new_gnu();
+                    header.set_size(content.len() as u64);
+                    header.set_mode(0o644);
+                    builder
+                        .append_data(&mut header, path, content.as_bytes())
+                        .unwrap();
+                }
+                let tar = builder.into_inner().unwrap();
+                if format == ArchiveFormat::Tar {
+                    tar
+                } else {
+                    let mut encoder =
+                        flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                    encoder.write_all(&tar).unwrap();
+                    encoder.finish().unwrap()
+                }
+            }
+            ArchiveFormat::Zip => {
+                let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+                for (path, content) in files {
+                    let options = zip::write::SimpleFileOptions::default();
+                    writer.start_file(*path, options).unwrap();
+                    writer.write_all(content.as_bytes()).unwrap();
+                }
+                writer.finish().unwrap().into_inner()
+            }
+        };
+        std::fs::write(archive, content).unwrap();
+    }
+
+    #[test]
+    fn input_archive() {
+        let dir = temp_dir("input-archive");
+        write_files(&dir, &[("in/a", "in ${A}")]);
+        let files = [("a", "${A}"), ("./sub/b", "b ${A}"), (".hidden", "${A}")];
+        let output = dir.join("out");
+
+        for name in ["in.tar", "in.tgz", "in.zip"] {
+            let archive = dir.join(name);
+            write_archive_files(&archive, &files);
+            let archive = archive.to_str().unwrap();
+
+            // Hidden entries are skipped like hidden files
+            run(Args::try_parse_from([
+                "ksubst",
+                "-e",
+                "A=1",
+                "--no-summary",
+                "-r",
+                "--input-archive",
+                archive,
+                output.to_str().unwrap(),
+            ])
+            .unwrap())
+            .unwrap();
+            let expected: BTreeMap<_, _> = [("a", "1"), ("sub/b", "b 1")]
+                .iter()
+                .map(|(path, content)| (path.to_string(), content.to_string()))
+                .collect();
+            assert_eq!(read_files(&output), expected, "{}", name);
+
+            // Files of input directories override entries
+            render_tree(&dir, &["--input-archive", archive]).unwrap();
+            assert_eq!(std::fs::read_to_string(output.join("a")).unwrap(), "in 1");
+            std::fs::remove_dir_all(&output).unwrap();
+        }
+
+        // Entries outside of the archive root are rejected
+        let archive = dir.join("escape.zip");
+        write_archive_files(&archive, &[("a/../../b", "b")]);
+        let err = render_tree(&dir, &["--input-archive", archive.to_str().unwrap()]).unwrap_err();
+        assert!(err.to_string().contains("invalid entry path"), "{}", err);
+        assert!(!dir.join("b").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn k8s_manifests() {
+        let dir = temp_dir("k8s");
+        let manifests = "\
+kind: Secret
+metadata:
+  name: db-${A}
+  annotations:
+    owner: ${A}
+data:
+  password: ${PASSWORD}
+  static: c3RhdGlj
+stringData:
+  user: ${A}
+---
+kind: ConfigMap
+metadata:
+  annotations:
+    owner: ${A}
+data:
+  password: ${PASSWORD}
+";
+        write_files(&dir, &[("in/manifests.yaml", manifests)]);
+        let flags = [
+            "-e",
+            "PASSWORD=s3cr:t",
+            "--k8s",
+            "--k8s-skip",
+            "metadata.annotations",
+        ];
+        render_tree(&dir, &flags).unwrap();
+        let output = std::fs::read_to_string(dir.join("out/manifests.yaml")).unwrap();
+        // Only changed Secret data is encoded, skipped paths are left as-is
+        let expected = manifests
+            .replace("db-${A}", "db-1")
+            .replace("user: ${A}", "user: '1'")
+            .replacen("${PASSWORD}", "czNjcjp0", 1)
+            .replace("${PASSWORD}", "s3cr:t");
+        assert_eq!(output, expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn exec_substitution() {
+        let command = |args: &[&str]| {
+            let args = Args::try_parse_from(args).unwrap();
+            let Some(Command::Exec { command }) = &args.command else {
+                unreachable!()
+            };
+            exec_command(command, &args.sources)
+        };
+
+        let child = command(&[
+            "ksubst",
+            "-e",
+            "NAME=world",
+            "exec",
+            "echo",
+            "hello ${NAME}",
+            "${MISSING}",
+        ])
+        .unwrap();
+        assert_eq!(child.get_program(), "echo");
+        let args: Vec<_> = child.get_args().collect();
+        assert_eq!(args, ["hello world", "${MISSING}"]);
+
+        // Loaded variables are exported, along with the process environment
+        let envs: HashMap<_, _> = child
+            .get_envs()
+            .map(|(key, value)| (key.to_str().unwrap(), value.unwrap().to_str().unwrap()))
+            .collect();
+        assert_eq!(envs["NAME"], "world");
+        assert_eq!(envs["PATH"], env::var("PATH").unwrap());
+
+        command(&["ksubst", "-e", "V=1", "exec", "echo", "${V|pad(x)}"]).unwrap_err();
+    }
+    #[test]
+    fn env_exports() {
+        let dir = temp_dir("env");
+        let template = "BASE=${HOST}\nURL=\"http://${BASE}:${PORT}/it's\"\nPORT=80\n";
+        write_files(
+            &dir,
+            &[
+                (".env.tpl", template),
+                ("missing.tpl", "A=${NOPE}\nB=${NAME}\n"),
+            ],
+        );
+        let variables = ksubst::context! {"HOST" => "example.com", "PORT" => "8080"};
+        let render = |path: &str, format| {
+            let mut output = Vec::new();
+            let path = dir.join(path);
+            render_env(path.to_str().unwrap(), format, &variables, &mut output)
+                .map(|()| String::from_utf8(output).unwrap())
+        };
+
+        // Entries see the variables and earlier entries only
+        assert_eq!(
+            render(".env.tpl", EnvFormat::Export).unwrap(),
+            "export BASE='example.com'\n\
+             export URL='http://example.com:8080/it'\\''s'\n\
+             export PORT='80'\n"
+        );
+        assert_eq!(
+            render(".env.tpl", EnvFormat::Dotenv).unwrap(),
+            "BASE='example.com'\nURL=\"http://example.com:8080/it's\"\nPORT='80'\n"
+        );
+        let err = render("missing.tpl", EnvFormat::Export).unwrap_err();
+        assert!(err.is::<Unresolved>(), "{}", err);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn check_sarif_log() {
+        let templates = [
+            (
+                "dir\\a.conf".to_string(),
+                "${HOST}\n é${MISSING}${}".to_string(),
+            ),
+            ("b.conf".to_string(), "${HOST} ${HOST".to_string()),
+        ];
+        let variables = ksubst::context! {"HOST" => "localhost"};
+        let (log, failed) = sarif_log(&templates, &variables);
+        assert_eq!(failed, 1);
+        assert_eq!(log["version"], "2.1.0");
+        let rules = &log["runs"][0]["tool"]["driver"]["rules"];
+        assert_eq!(rules.as_array().unwrap().len(), SARIF_RULES.len());
+
+        // Results are sorted by position, with code point columns
+        let results: Vec<_> = log["runs"][0]["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| {
+                let location = &result["locations"][0]["physicalLocation"];
+                let region = &location["region"];
+                let position = ["startLine", "startColumn", "endLine", "endColumn"]
+                    .map(|key| region[key].as_u64().unwrap());
+                (
+                    result["ruleId"].as_str().unwrap(),
+                    result["level"].as_str().unwrap(),
+                    location["artifactLocation"]["uri"].as_str().unwrap(),
+                    position,
+                )
+            })
+            .collect();
+        assert_eq!(
+            results,
+            [
+                ("unresolved-variable", "error", "dir/a.conf", [2, 3, 2, 13]),
+                ("empty-name", "warning", "dir/a.conf", [2, 13, 2, 16]),
+                ("unbalanced-braces", "warning", "b.conf", [1, 9, 1, 11]),
+            ]
+        );
+    }
+}
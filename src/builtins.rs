@@ -0,0 +1,357 @@
+//! Built-in template functions.
+
+use crate::{validate, Error};
+#[cfg(feature = "time")]
+use chrono::format::{Item, StrftimeItems};
+#[cfg(feature = "random")]
+use rand::rngs::StdRng;
+#[cfg(feature = "random")]
+use rand::{RngCore, SeedableRng};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Built-in functions available to templates.
+///
+/// Function calls in templates are only evaluated for builtins enabled
+/// through [`Substituter::builtin`](crate::Substituter::builtin), and are
+/// left as-is otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Builtin {
+    /// Current time: `${now()}`, `${now(FORMAT)}` or `${now(FORMAT, local)}`.
+    ///
+    /// The time is formatted with a strftime-style `FORMAT` (RFC 3339 by
+    /// default), in UTC unless `local` is specified. This requires the
+    /// `time` feature.
+    #[cfg(feature = "time")]
+    Now,
+    /// Random UUID (version 4): `${uuid()}`. This requires the `random`
+    /// feature.
+    #[cfg(feature = "random")]
+    Uuid,
+    /// Random hexadecimal string of the given length: `${random_hex(LENGTH)}`.
+    /// This requires the `random` feature.
+    #[cfg(feature = "random")]
+    RandomHex,
+    /// Process environment variable: `${env(NAME)}` or `${env(NAME, DEFAULT)}`.
+    ///
+    /// This reads directly from the process environment, regardless of the
+    /// context used for substitution. Calls for unset variables without a
+    /// default are left as-is.
+    Env,
+    /// File contents: `${file(PATH)}`, or `${file(PATH, render)}` to also
+    /// substitute the included file.
+    ///
+    /// Paths are relative to the root directory configured with
+    /// [`Substituter::file_root`](crate::Substituter::file_root), and
+    /// rendering includes cycles are detected.
+    File,
+    /// Integer arithmetic: `${math(EXPRESSION)}`, or inline expressions such as `${PORT + 1}`.
+    ///
+    /// Expressions are made of integers, variable names, parentheses and
+    /// the `+`, `-`, `*`, `/` and `%` operators. Variables must have
+    /// integer values, and expressions referencing missing variables are
+    /// left as-is.
+    Math,
+    /// Conditional blocks: `${if CONDITION}...${end}`, or
+    /// `${if CONDITION}...${else}...${end}`, which can be nested.
+    ///
+    /// Conditions compare variables and double-quoted strings with `==` and
+    /// `!=`, and combine comparisons with `!`, `&&`, `||` and parentheses,
+    /// e.g. `${if ENV == "prod" && !DEBUG}`. A variable on its own is true
+    /// if it is set and non-empty, and missing variables compare unequal to
+    /// any value. Otherwise, `${else}` and `${end}` are placeholders like
+    /// any other.
+    If,
+    /// Comments: `${# text}`, removed from rendered output.
+    ///
+    /// Comments are left as-is otherwise, and document variables either way,
+    /// see [`variables_metadata`](crate::variables_metadata).
+    Comments,
+}
+
+/// State shared by function calls while rendering a template.
+#[derive(Debug, Default)]
+pub(crate) struct CallState {
+    #[cfg_attr(not(feature = "random"), allow(dead_code))]
+    seed: Option<u64>,
+    #[cfg(feature = "random")]
+    rng: Option<StdRng>,
+    /// Stack of files being rendered through `file()`.
+    pub(crate) includes: Vec<PathBuf>,
+}
+
+impl CallState {
+    /// Create a state for a new render, with an optional seed for random values.
+    pub(crate) fn new(seed: Option<u64>) -> Self {
+        Self {
+            seed,
+            ..Self::default()
+        }
+    }
+
+    /// Random numbers generator, initialized on first use.
+    #[cfg(feature = "random")]
+    fn rng(&mut self) -> &mut StdRng {
+        let seed = self.seed;
+        self.rng.get_or_insert_with(|| match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        })
+    }
+}
+
+impl Builtin {
+    /// Name of this function in templates.
+    pub fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "time")]
+            Builtin::Now => "now",
+            #[cfg(feature = "random")]
+            Builtin::Uuid => "uuid",
+            #[cfg(feature = "random")]
+            Builtin::RandomHex => "random_hex",
+            Builtin::Env => "env",
+            Builtin::File => "file",
+            Builtin::Math => "math",
+            Builtin::If => "if",
+            Builtin::Comments => "#",
+        }
+    }
+
+    /// Look up a builtin by function name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            #[cfg(feature = "time")]
+            "now" => Some(Builtin::Now),
+            #[cfg(feature = "random")]
+            "uuid" => Some(Builtin::Uuid),
+            #[cfg(feature = "random")]
+            "random_hex" => Some(Builtin::RandomHex),
+            "env" => Some(Builtin::Env),
+            "file" => Some(Builtin::File),
+            "math" => Some(Builtin::Math),
+            "if" => Some(Builtin::If),
+            "#" => Some(Builtin::Comments),
+            _ => None,
+        }
+    }
+
+    /// Evaluate a call to this function.
+    ///
+    /// This returns `None` if the call cannot be resolved, in which case it
+    /// is left as-is in the output. Includes and arithmetic are evaluated by
+    /// the substituter itself, as they need rendering or variables lookup.
+    #[cfg_attr(not(feature = "random"), allow(unused_variables))]
+    pub(crate) fn call(
+        self,
+        args: &[Cow<'_, str>],
+        state: &mut CallState,
+    ) -> Result<Option<String>, Error> {
+        match self {
+            #[cfg(feature = "time")]
+            Builtin::Now => now(args).map(Some),
+            #[cfg(feature = "random")]
+            Builtin::Uuid => uuid(args, state.rng()).map(Some),
+            #[cfg(feature = "random")]
+            Builtin::RandomHex => random_hex(args, state.rng()).map(Some),
+            Builtin::Env => env(args),
+            Builtin::File | Builtin::Math | Builtin::If | Builtin::Comments => Err(Error(format!(
+                "{}(): must be evaluated by a substituter",
+                self.name()
+            ))),
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+fn now(args: &[Cow<'_, str>]) -> Result<String, Error> {
+    let (format, local) = match args {
+        [] => ("%+", false),
+        [format] => (format.as_ref(), false),
+        [format, tz] if tz == "utc" => (format.as_ref(), false),
+        [format, tz] if tz == "local" => (format.as_ref(), true),
+        [_, tz] => return Err(Error(format!("now(): unknown timezone '{}'", tz))),
+        _ => return Err(Error("now(): too many arguments".to_string())),
+    };
+
+    let items: Vec<Item<'_>> = StrftimeItems::new(format).collect();
+    if items.contains(&Item::Error) {
+        return Err(Error(format!("now(): invalid format '{}'", format)));
+    }
+
+    let output = if local {
+        chrono::Local::now()
+            .format_with_items(items.into_iter())
+            .to_string()
+    } else {
+        chrono::Utc::now()
+            .format_with_items(items.into_iter())
+            .to_string()
+    };
+    Ok(output)
+}
+
+#[cfg(feature = "random")]
+fn uuid(args: &[Cow<'_, str>], rng: &mut StdRng) -> Result<String, Error> {
+    if !args.is_empty() {
+        return Err(Error("uuid(): too many arguments".to_string()));
+    }
+
+    let mut bytes = [0u8; 16];
+    rng.fill_bytes(&mut bytes);
+    // Set version (4) and variant (RFC 4122) bits
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = to_hex(&bytes);
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+#[cfg(feature = "random")]
+fn random_hex(args: &[Cow<'_, str>], rng: &mut StdRng) -> Result<String, Error> {
+    let len: usize = match args {
+        [len] => len
+            .parse()
+            .map_err(|_| Error(format!("random_hex(): invalid length '{}'", len)))?,
+        _ => {
+            return Err(Error(
+                "random_hex(): expected a length argument".to_string(),
+            ))
+        }
+    };
+
+    let mut bytes = vec![0u8; len.div_ceil(2)];
+    rng.fill_bytes(&mut bytes);
+    let mut hex = to_hex(&bytes);
+    hex.truncate(len);
+    Ok(hex)
+}
+
+fn env(args: &[Cow<'_, str>]) -> Result<Option<String>, Error> {
+    let (name, default) = match args {
+        [name] => (name, None),
+        [name, default] => (name, Some(default)),
+        _ => {
+            return Err(Error(
+                "env(): expected a variable name argument".to_string(),
+            ))
+        }
+    };
+
+    let value = match std::env::var(name.as_ref()) {
+        Ok(value) => value,
+        Err(std::env::VarError::NotPresent) => match default {
+            Some(default) => default.to_string(),
+            None => return Ok(None),
+        },
+        Err(e) => return Err(Error(format!("env(): variable '{}': {}", name, e))),
+    };
+    validate(&value, "value")?;
+    Ok(Some(value))
+}
+
+/// Parse `file()` arguments, returning the path and whether to render it.
+pub(crate) fn include_args<'a>(args: &'a [Cow<'_, str>]) -> Result<(&'a str, bool), Error> {
+    match args {
+        [path] => Ok((path, false)),
+        [path, mode] if mode == "render" => Ok((path, true)),
+        [_, mode] => Err(Error(format!("file(): unknown mode '{}'", mode))),
+        _ => Err(Error("file(): expected a path argument".to_string())),
+    }
+}
+
+/// Resolve `path` relative to `root`, ensuring it doesn't escape it.
+pub(crate) fn sandboxed_path(root: &Path, path: &str) -> Result<PathBuf, Error> {
+    let canonicalize = |p: &Path| {
+        p.canonicalize().map_err(|e| {
+            Error(format!(
+                "file(): failed to resolve '{}': {}",
+                p.display(),
+                e
+            ))
+        })
+    };
+
+    let root = canonicalize(root)?;
+    let resolved = canonicalize(&root.join(path))?;
+    if !resolved.starts_with(&root) {
+        return Err(Error(format!(
+            "file(): path '{}' escapes root directory '{}'",
+            path,
+            root.display()
+        )));
+    }
+    Ok(resolved)
+}
+
+#[cfg(feature = "random")]
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(builtin: Builtin, args: &[&str]) -> Result<Option<String>, Error> {
+        let args: Vec<Cow<'_, str>> = args.iter().map(|a| Cow::Borrowed(*a)).collect();
+        builtin.call(&args, &mut CallState::new(None))
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn builtin_now() {
+        let year = call(Builtin::Now, &["%Y"]).unwrap().unwrap();
+        assert_eq!(year.len(), 4);
+        assert!(year.starts_with("20"));
+
+        chrono::DateTime::parse_from_rfc3339(&call(Builtin::Now, &[]).unwrap().unwrap()).unwrap();
+        call(Builtin::Now, &["%Y", "local"]).unwrap();
+        call(Builtin::Now, &["%Y", "mars"]).unwrap_err();
+        call(Builtin::Now, &["%Q"]).unwrap_err();
+    }
+
+    #[cfg(feature = "random")]
+    #[test]
+    fn builtin_random() {
+        let uuid = call(Builtin::Uuid, &[]).unwrap().unwrap();
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+        assert!(matches!(&uuid[19..20], "8" | "9" | "a" | "b"));
+        assert_ne!(uuid, call(Builtin::Uuid, &[]).unwrap().unwrap());
+
+        let hex = call(Builtin::RandomHex, &["7"]).unwrap().unwrap();
+        assert_eq!(hex.len(), 7);
+        assert!(hex.chars().all(|c| c.is_ascii_hexdigit()));
+        call(Builtin::RandomHex, &[]).unwrap_err();
+        call(Builtin::RandomHex, &["-1"]).unwrap_err();
+
+        let args = [Cow::Borrowed("16")];
+        let mut first = CallState::new(Some(42));
+        let mut second = CallState::new(Some(42));
+        assert_eq!(
+            Builtin::RandomHex.call(&args, &mut first).unwrap(),
+            Builtin::RandomHex.call(&args, &mut second).unwrap()
+        );
+    }
+
+    #[test]
+    fn builtin_env() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(call(Builtin::Env, &["PATH"]).unwrap(), Some(path));
+        assert_eq!(call(Builtin::Env, &["KSUBST_UNSET_VAR"]).unwrap(), None);
+        assert_eq!(
+            call(Builtin::Env, &["KSUBST_UNSET_VAR", "default"]).unwrap(),
+            Some("default".to_string())
+        );
+        call(Builtin::Env, &[]).unwrap_err();
+    }
+}
@@ -0,0 +1,295 @@
+//! Memoization of rendered templates.
+
+use crate::{Error, Lookup, Rendered, Substituter};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Cache of rendered templates, keyed by template and context contents.
+///
+/// Rendering the same template with the same variables returns the cached
+/// result without substituting again. Entries are evicted least recently
+/// used first once the cache is full, and expire after an optional time to
+/// live.
+///
+/// Since cached results are reused as-is, this should not be used with
+/// builtins producing a different output on each render, e.g.
+/// [`Builtin::Uuid`](crate::Builtin::Uuid).
+///
+/// Each call copies and sorts the variables of the context to look it up,
+/// which costs about as much as rendering a short template with them. The
+/// cache pays off for large templates or slow renders, like ones including
+/// files, more than for short templates with environment-sized contexts.
+///
+/// ```rust
+/// use ksubst::{RenderCache, Substituter};
+///
+/// let cache = RenderCache::new(Substituter::new(), 128);
+/// let context = ksubst::context! {"hostname" => "example.com"};
+///
+/// let output = cache.substitute("https://${hostname}", &context).unwrap();
+/// assert_eq!(output, "https://example.com");
+/// assert_eq!(cache.len(), 1);
+/// ```
+#[derive(Debug)]
+pub struct RenderCache {
+    substituter: Substituter,
+    capacity: usize,
+    ttl: Option<Duration>,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<(u64, u64), Entry>,
+    /// Counter used to order entries by last use.
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    template: String,
+    /// Variables of the context, sorted by name.
+    variables: Vec<(String, String)>,
+    rendered: Rendered,
+    created: Instant,
+    last_used: u64,
+}
+
+impl RenderCache {
+    /// Create a cache of up to `capacity` entries, rendering with `substituter`.
+    pub fn new(substituter: Substituter, capacity: usize) -> Self {
+        Self {
+            substituter,
+            capacity,
+            ttl: None,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Expire entries after `ttl`.
+    pub fn ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Render a template, reusing the cached result if any.
+    ///
+    /// Failed renders are not cached.
+    pub fn render<L>(&self, template: &str, variables: &L) -> Result<Rendered, Error>
+    where
+        L: Lookup + ?Sized,
+    {
+        let context = sorted_vars(variables);
+        let key = (hash(template), hash(&context));
+        {
+            let mut state = self.lock();
+            state.tick += 1;
+            let tick = state.tick;
+            if let Some(entry) = state.entries.get_mut(&key) {
+                let expired = self.ttl.is_some_and(|ttl| entry.created.elapsed() >= ttl);
+                if !expired && entry.template == template && entry.variables == context {
+                    entry.last_used = tick;
+                    return Ok(entry.rendered.clone());
+                }
+            }
+        }
+
+        // Other renders can run meanwhile, e.g. while this includes files
+        let rendered = self.substituter.render(template, variables)?;
+        if self.capacity == 0 {
+            return Ok(rendered);
+        }
+        let mut state = self.lock();
+        state.tick += 1;
+        let tick = state.tick;
+        if !state.entries.contains_key(&key) && state.entries.len() >= self.capacity {
+            self.evict(&mut state);
+        }
+        state.entries.insert(
+            key,
+            Entry {
+                template: template.to_string(),
+                variables: context,
+                rendered: rendered.clone(),
+                created: Instant::now(),
+                last_used: tick,
+            },
+        );
+        Ok(rendered)
+    }
+
+    /// Substitute variables in a template, reusing the cached output if any.
+    pub fn substitute<L>(&self, template: &str, variables: &L) -> Result<String, Error>
+    where
+        L: Lookup + ?Sized,
+    {
+        self.render(template, variables).map(|r| r.output)
+    }
+
+    /// Remove the cached renders of a template, for all contexts.
+    pub fn invalidate(&self, template: &str) {
+        let key = hash(template);
+        self.lock().entries.retain(|(k, _), _| *k != key);
+    }
+
+    /// Remove all cached renders.
+    pub fn clear(&self) {
+        self.lock().entries.clear();
+    }
+
+    /// Number of cached renders, including expired ones not evicted yet.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        // Entries are always left consistent, even if a render panicked
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Evict expired entries, or the least recently used one if none.
+    fn evict(&self, state: &mut CacheState) {
+        if let Some(ttl) = self.ttl {
+            state.entries.retain(|_, e| e.created.elapsed() < ttl);
+            if state.entries.len() < self.capacity {
+                return;
+            }
+        }
+        let oldest = state
+            .entries
+            .iter()
+            .min_by_key(|(_, e)| e.last_used)
+            .map(|(k, _)| *k);
+        if let Some(key) = oldest {
+            state.entries.remove(&key);
+        }
+    }
+}
+
+fn hash<H: Hash + ?Sized>(value: &H) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Variables of a context, sorted by name to be compared regardless of
+/// their order.
+fn sorted_vars<L: Lookup + ?Sized>(variables: &L) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = variables
+        .vars()
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    vars.sort_unstable();
+    vars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "random")]
+    use crate::Builtin;
+
+    #[cfg(feature = "random")]
+    #[test]
+    fn render_cache() {
+        // Random values tell cached renders apart from new ones
+        let mut substituter = Substituter::new();
+        substituter.builtin(Builtin::Uuid);
+        let cache = RenderCache::new(substituter, 2);
+        let context = crate::context! {"A" => "a", "B" => "b"};
+        let template = "${A} ${uuid()}";
+
+        let first = cache.substitute(template, &context).unwrap();
+        assert!(first.starts_with("a "));
+        assert_eq!(cache.substitute(template, &context).unwrap(), first);
+        let reordered = crate::context! {"B" => "b", "A" => "a"};
+        assert_eq!(cache.substitute(template, &reordered).unwrap(), first);
+        let changed = crate::context! {"A" => "a", "B" => "c"};
+        assert_ne!(cache.substitute(template, &changed).unwrap(), first);
+        assert_eq!(cache.len(), 2);
+        // Contexts are compared, not only their hashes
+        cache
+            .lock()
+            .entries
+            .values_mut()
+            .for_each(|e| e.variables.clear());
+        assert_ne!(cache.substitute(template, &context).unwrap(), first);
+        let first = cache.substitute(template, &context).unwrap();
+
+        // Using the first entry again makes the second one the least recently used
+        cache.substitute(template, &context).unwrap();
+        cache.substitute("${B}", &context).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.substitute(template, &context).unwrap(), first);
+
+        cache.invalidate(template);
+        assert_eq!(cache.len(), 1);
+        assert_ne!(cache.substitute(template, &context).unwrap(), first);
+        cache.clear();
+        assert!(cache.is_empty());
+
+        cache
+            .substitute("${", &crate::context! {"$" => ""})
+            .unwrap_err();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn concurrent_renders() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Each render waits for the other one to run, failing if serialized
+        static RUNNING: AtomicUsize = AtomicUsize::new(0);
+        let mut substituter = Substituter::new();
+        substituter.register_filter("wait", |value| {
+            RUNNING.fetch_add(1, Ordering::SeqCst);
+            let start = Instant::now();
+            while RUNNING.load(Ordering::SeqCst) < 2 {
+                if start.elapsed() > Duration::from_secs(5) {
+                    return "serialized".to_string();
+                }
+                std::thread::yield_now();
+            }
+            value.to_string()
+        });
+        let cache = RenderCache::new(substituter, 8);
+
+        std::thread::scope(|scope| {
+            let renders: Vec<_> = ["a", "b"]
+                .into_iter()
+                .map(|value| {
+                    let cache = &cache;
+                    scope.spawn(move || {
+                        let context = crate::context! {"A" => value};
+                        cache.substitute("${A|wait}", &context).unwrap()
+                    })
+                })
+                .collect();
+            let outputs: Vec<String> = renders.into_iter().map(|r| r.join().unwrap()).collect();
+            assert_eq!(outputs, ["a", "b"]);
+        });
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[cfg(feature = "random")]
+    #[test]
+    fn render_cache_ttl() {
+        let mut substituter = Substituter::new();
+        substituter.builtin(Builtin::Uuid);
+        let mut cache = RenderCache::new(substituter, 8);
+        cache.ttl(Duration::ZERO);
+        let context = crate::context! {};
+
+        let first = cache.substitute("${uuid()}", &context).unwrap();
+        assert_ne!(cache.substitute("${uuid()}", &context).unwrap(), first);
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -0,0 +1,243 @@
+//! Boolean conditions of conditional blocks.
+
+use crate::Error;
+use std::borrow::Cow;
+
+/// Check whether the body of an `${if ...}` block start looks like a
+/// condition, made of valid tokens.
+pub(crate) fn is_condition(condition: &str) -> bool {
+    tokenize(condition).is_ok_and(|tokens| !tokens.is_empty())
+}
+
+/// Evaluate a condition.
+///
+/// Variables are resolved through `lookup`. A variable on its own is true
+/// if it is set and non-empty, and missing variables compare unequal to
+/// any value.
+pub(crate) fn evaluate<'v, F>(condition: &str, lookup: F) -> Result<bool, Error>
+where
+    F: FnMut(&str) -> Option<Cow<'v, str>>,
+{
+    let tokens = tokenize(condition)?;
+    let mut parser = Parser {
+        condition,
+        tokens: &tokens,
+        pos: 0,
+        lookup,
+    };
+    let value = parser.or()?;
+    if parser.pos != tokens.len() {
+        return Err(parser.error("unexpected token"));
+    }
+    Ok(value)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token<'a> {
+    Name(&'a str),
+    String(String),
+    Not,
+    And,
+    Or,
+    Eq,
+    Ne,
+    Open,
+    Close,
+}
+
+fn tokenize(condition: &str) -> Result<Vec<Token<'_>>, Error> {
+    let bytes = condition.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let b = bytes[pos];
+        let start = pos;
+        pos += 1;
+        let next = bytes.get(pos).copied();
+        match b {
+            b' ' | b'\t' => {}
+            b'(' => tokens.push(Token::Open),
+            b')' => tokens.push(Token::Close),
+            b'!' if next == Some(b'=') => {
+                pos += 1;
+                tokens.push(Token::Ne);
+            }
+            b'!' => tokens.push(Token::Not),
+            b'=' | b'&' | b'|' if next == Some(b) => {
+                pos += 1;
+                tokens.push(match b {
+                    b'=' => Token::Eq,
+                    b'&' => Token::And,
+                    _ => Token::Or,
+                });
+            }
+            b'"' => {
+                let mut value = String::new();
+                let mut chars = condition[pos..].char_indices();
+                let end = loop {
+                    match chars.next() {
+                        Some((i, '"')) => break pos + i,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c @ ('"' | '\\'))) => value.push(c),
+                            _ => return Err(error("invalid escape", condition)),
+                        },
+                        Some((_, c)) => value.push(c),
+                        None => return Err(error("unterminated string", condition)),
+                    }
+                };
+                pos = end + 1;
+                tokens.push(Token::String(value));
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                while pos < bytes.len()
+                    && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_')
+                {
+                    pos += 1;
+                }
+                tokens.push(Token::Name(&condition[start..pos]));
+            }
+            _ => {
+                return Err(Error(format!(
+                    "invalid character '{}' in condition '{}'",
+                    condition[start..].chars().next().unwrap_or_default(),
+                    condition
+                )))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn error(msg: &str, condition: &str) -> Error {
+    Error(format!("{} in condition '{}'", msg, condition))
+}
+
+/// Recursive descent parser, evaluating while parsing.
+struct Parser<'t, 'a, F> {
+    condition: &'a str,
+    tokens: &'t [Token<'a>],
+    pos: usize,
+    lookup: F,
+}
+
+impl<'t, 'a, 'v, F> Parser<'t, 'a, F>
+where
+    F: FnMut(&str) -> Option<Cow<'v, str>>,
+{
+    fn error(&self, msg: &str) -> Error {
+        error(msg, self.condition)
+    }
+
+    fn next(&mut self) -> Option<&'t Token<'a>> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self, token: &Token<'_>) -> bool {
+        self.tokens.get(self.pos) == Some(token)
+    }
+
+    /// `or := and ('||' and)*`
+    fn or(&mut self) -> Result<bool, Error> {
+        let mut value = self.and()?;
+        while self.peek(&Token::Or) {
+            self.pos += 1;
+            value |= self.and()?;
+        }
+        Ok(value)
+    }
+
+    /// `and := not ('&&' not)*`
+    fn and(&mut self) -> Result<bool, Error> {
+        let mut value = self.not()?;
+        while self.peek(&Token::And) {
+            self.pos += 1;
+            value &= self.not()?;
+        }
+        Ok(value)
+    }
+
+    /// `not := '!' not | '(' or ')' | operand (('==' | '!=') operand)?`
+    fn not(&mut self) -> Result<bool, Error> {
+        if self.peek(&Token::Not) {
+            self.pos += 1;
+            return Ok(!self.not()?);
+        }
+        if self.peek(&Token::Open) {
+            self.pos += 1;
+            let value = self.or()?;
+            return match self.next() {
+                Some(Token::Close) => Ok(value),
+                _ => Err(self.error("missing closing parenthesis")),
+            };
+        }
+
+        let lhs = self.operand()?;
+        let equal = match self.tokens.get(self.pos) {
+            Some(Token::Eq) => true,
+            Some(Token::Ne) => false,
+            _ => return Ok(lhs.is_some_and(|value| !value.is_empty())),
+        };
+        self.pos += 1;
+        let rhs = self.operand()?;
+        let matches = lhs.is_some() && lhs == rhs;
+        Ok(matches == equal)
+    }
+
+    /// `operand := NAME | STRING`
+    fn operand(&mut self) -> Result<Option<Cow<'v, str>>, Error> {
+        match self.next().cloned() {
+            Some(Token::Name(name)) => Ok((self.lookup)(name)),
+            Some(Token::String(value)) => Ok(Some(Cow::Owned(value))),
+            Some(_) => Err(self.error("unexpected token")),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(condition: &str) -> Result<bool, Error> {
+        evaluate(condition, |name| match name {
+            "env" => Some(Cow::Borrowed("prod")),
+            "debug" => Some(Cow::Borrowed("1")),
+            "empty" => Some(Cow::Borrowed("")),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn conditions() {
+        assert!(is_condition(r#"env == "prod""#));
+        assert!(!is_condition(""));
+        assert!(!is_condition("env = prod"));
+
+        assert!(eval(r#"env == "prod""#).unwrap());
+        assert!(!eval(r#"env != "prod""#).unwrap());
+        assert!(eval(r#"env == "prod" && debug"#).unwrap());
+        assert!(eval(r#"!debug || env == "pr\"od" || !empty"#).unwrap());
+        assert!(!eval(r#"!(debug && env == "prod")"#).unwrap());
+        assert!(eval(r#""x" == "x" && !missing"#).unwrap());
+        // Missing variables are unset, and equal to nothing
+        assert!(!eval(r#"missing == """#).unwrap());
+        assert!(eval(r#"missing != "prod""#).unwrap());
+        assert!(!eval("missing == other").unwrap());
+        // `&&` binds tighter than `||`
+        assert!(eval("debug || missing && empty").unwrap());
+
+        for invalid in [
+            "(debug",
+            "debug &&",
+            "debug env",
+            r#""open"#,
+            "== debug",
+            r#""\n""#,
+        ] {
+            eval(invalid).unwrap_err();
+        }
+    }
+}
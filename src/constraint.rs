@@ -0,0 +1,116 @@
+//! Validation constraints on variables values.
+
+use crate::Error;
+use regex::Regex;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// Constraint on the value of a variable, checked when rendering.
+///
+/// Constraints are attached to variables through
+/// [`Substituter::constraint`](crate::Substituter::constraint).
+///
+/// ```rust
+/// use ksubst::{Constraint, Substituter};
+///
+/// let mut substituter = Substituter::new();
+/// substituter.constraint("ENVIRONMENT", Constraint::one_of(["dev", "prod"]));
+///
+/// let context = ksubst::context! {"ENVIRONMENT" => "prodd"};
+/// let err = substituter.substitute("env: ${ENVIRONMENT}", &context).unwrap_err();
+/// assert!(err.to_string().contains("must be one of"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Constraint(Kind);
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Pattern(Regex),
+    OneOf(Vec<String>),
+    Range(RangeInclusive<i64>),
+}
+
+impl Constraint {
+    /// Require values to fully match a regular expression.
+    pub fn pattern(pattern: &str) -> Result<Self, Error> {
+        let regex = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| Error(format!("invalid constraint pattern: {}", e)))?;
+        Ok(Self(Kind::Pattern(regex)))
+    }
+
+    /// Require values to be one of the given values.
+    pub fn one_of<I, S>(values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self(Kind::OneOf(values.into_iter().map(Into::into).collect()))
+    }
+
+    /// Require values to be integers within the given range.
+    pub fn range(range: RangeInclusive<i64>) -> Self {
+        Self(Kind::Range(range))
+    }
+
+    /// Check the value of the variable `name`.
+    pub(crate) fn check(&self, name: &str, value: &str) -> Result<(), Error> {
+        let valid = match &self.0 {
+            Kind::Pattern(regex) => regex.is_match(value),
+            Kind::OneOf(values) => values.iter().any(|v| v == value),
+            Kind::Range(range) => value.parse().is_ok_and(|n| range.contains(&n)),
+        };
+        if valid {
+            return Ok(());
+        }
+        Err(Error(format!(
+            "variable '{}' has invalid value '{}': {}",
+            name, value, self
+        )))
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Kind::Pattern(regex) => {
+                let pattern = regex.as_str();
+                // Strip the anchors added by `Constraint::pattern`
+                let pattern = &pattern[4..pattern.len() - 2];
+                write!(f, "must match '{}'", pattern)
+            }
+            Kind::OneOf(values) => write!(f, "must be one of {}", values.join(", ")),
+            Kind::Range(range) => write!(
+                f,
+                "must be an integer between {} and {}",
+                range.start(),
+                range.end()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constraints() {
+        let pattern = Constraint::pattern("[a-z]+|[0-9]+").unwrap();
+        pattern.check("V", "abc").unwrap();
+        pattern.check("V", "42").unwrap();
+        assert_eq!(
+            pattern.check("V", "abc42").unwrap_err().to_string(),
+            "ksubst error: variable 'V' has invalid value 'abc42': must match '[a-z]+|[0-9]+'"
+        );
+        Constraint::pattern("(").unwrap_err();
+
+        let one_of = Constraint::one_of(["dev", "prod"]);
+        one_of.check("ENV", "prod").unwrap();
+        one_of.check("ENV", "prodd").unwrap_err();
+
+        let range = Constraint::range(1..=65535);
+        range.check("PORT", "8080").unwrap();
+        range.check("PORT", "0").unwrap_err();
+        range.check("PORT", "http").unwrap_err();
+    }
+}
@@ -0,0 +1,236 @@
+//! Structured contexts, with deep-merge support.
+
+use crate::lookup::{Lookup, Vars};
+use crate::Error;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+
+/// Strategy for merging lists present in both contexts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ListMerge {
+    /// Replace the list with the merged one.
+    #[default]
+    Replace,
+    /// Append the items of the merged list.
+    Append,
+}
+
+/// Context of structured values, e.g. loaded from JSON, YAML or TOML files.
+///
+/// Nested maps are exposed as variables named after their path, joined by
+/// underscores: `{"db": {"host": "localhost"}}` defines `${db_host}`.
+/// Strings are used as-is, while numbers and booleans are formatted, null
+/// values are empty and lists of scalars are joined by commas.
+///
+/// ```rust
+/// use ksubst::{Context, ListMerge};
+///
+/// let mut context = Context::from_json(serde_json::json!({
+///     "db": {"host": "localhost", "port": 5432},
+///     "tags": ["a"],
+/// }))
+/// .unwrap();
+/// context.merge(
+///     Context::from_json(serde_json::json!({"db": {"host": "db"}, "tags": ["b"]})).unwrap(),
+///     ListMerge::Append,
+/// );
+///
+/// let output = ksubst::substitute_with("${db_host}:${db_port} ${tags}", &context).unwrap();
+/// assert_eq!(output, "db:5432 a,b");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Context {
+    values: Map<String, Value>,
+}
+
+impl Context {
+    /// Create an empty context.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a context from a JSON object.
+    pub fn from_json(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Object(values) => Ok(Self { values }),
+            value => Err(Error(format!("context must be an object, got '{}'", value))),
+        }
+    }
+
+    /// Set a top-level value.
+    pub fn insert<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: Into<String>,
+        V: Into<Value>,
+    {
+        self.values.insert(key.into(), value.into());
+        self
+    }
+
+    /// Deep-merge another context into this one.
+    ///
+    /// Maps present in both contexts are merged recursively, lists are
+    /// merged according to `lists`, and any other value from `other`
+    /// replaces the existing one.
+    pub fn merge(&mut self, other: Context, lists: ListMerge) -> &mut Self {
+        merge_maps(&mut self.values, other.values, lists);
+        self
+    }
+
+    /// Structured values of this context.
+    pub fn as_json(&self) -> &Map<String, Value> {
+        &self.values
+    }
+}
+
+fn merge_maps(target: &mut Map<String, Value>, source: Map<String, Value>, lists: ListMerge) {
+    for (key, value) in source {
+        match (target.get_mut(&key), value) {
+            (Some(Value::Object(target)), Value::Object(source)) => {
+                merge_maps(target, source, lists)
+            }
+            (Some(Value::Array(target)), Value::Array(source)) if lists == ListMerge::Append => {
+                target.extend(source)
+            }
+            (_, value) => {
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Format a value as a variable value, if it is not a map.
+fn format_value(value: &Value) -> Option<Cow<'_, str>> {
+    match value {
+        Value::Null => Some(Cow::Borrowed("")),
+        Value::String(s) => Some(Cow::Borrowed(s)),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                Value::Array(_) | Value::Object(_) => None,
+                item => format_value(item),
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|items| Cow::Owned(items.join(","))),
+        Value::Object(_) => None,
+        value => Some(Cow::Owned(value.to_string())),
+    }
+}
+
+/// Look up a flattened variable name in nested maps.
+fn find<'a>(values: &'a Map<String, Value>, name: &str) -> Option<Cow<'a, str>> {
+    if let Some(value) = values.get(name) {
+        if let Some(value) = format_value(value) {
+            return Some(value);
+        }
+    }
+    values.iter().find_map(|(key, value)| {
+        let rest = name.strip_prefix(key.as_str())?.strip_prefix('_')?;
+        match value {
+            Value::Object(nested) => find(nested, rest),
+            _ => None,
+        }
+    })
+}
+
+/// Flatten nested maps into variables.
+fn flatten<'a>(
+    prefix: &str,
+    values: &'a Map<String, Value>,
+    vars: &mut Vec<(Cow<'a, str>, Cow<'a, str>)>,
+) {
+    for (key, value) in values {
+        let name = if prefix.is_empty() {
+            Cow::Borrowed(key.as_str())
+        } else {
+            Cow::Owned(format!("{}_{}", prefix, key))
+        };
+        match value {
+            Value::Object(nested) => flatten(&name, nested, vars),
+            value => {
+                if let Some(value) = format_value(value) {
+                    vars.push((name, value));
+                }
+            }
+        }
+    }
+}
+
+impl Lookup for Context {
+    fn get(&self, name: &str) -> Option<Cow<'_, str>> {
+        find(&self.values, name)
+    }
+
+    fn vars(&self) -> Vars<'_> {
+        let mut vars = Vec::new();
+        flatten("", &self.values, &mut vars);
+        Box::new(vars.into_iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn context(value: Value) -> Context {
+        Context::from_json(value).unwrap()
+    }
+
+    #[test]
+    fn merge_contexts() {
+        let mut base = context(json!({
+            "app": {"name": "web", "replicas": 1, "labels": {"tier": "front"}},
+            "tags": ["a"],
+            "debug": true,
+        }));
+        let overlay = context(json!({
+            "app": {"replicas": 3, "labels": {"team": "core"}},
+            "tags": ["b"],
+            "debug": null,
+        }));
+
+        let mut replaced = base.clone();
+        replaced.merge(overlay.clone(), ListMerge::Replace);
+        assert_eq!(replaced.as_json()["tags"], json!(["b"]));
+
+        base.merge(overlay, ListMerge::Append);
+        assert_eq!(
+            Value::Object(base.as_json().clone()),
+            json!({
+                "app": {"name": "web", "replicas": 3, "labels": {"tier": "front", "team": "core"}},
+                "tags": ["a", "b"],
+                "debug": null,
+            })
+        );
+
+        Context::from_json(json!(["not", "an", "object"])).unwrap_err();
+    }
+
+    #[test]
+    fn structured_lookup() {
+        let mut context = context(json!({
+            "app": {"name": "web", "labels": {"tier": "front"}},
+            "app_name": {"nested": "shadowed"},
+            "ports": [80, 443],
+            "matrix": [[1]],
+            "empty": null,
+        }));
+        context.insert("ratio", 0.5);
+
+        assert_eq!(context.get("app_name").unwrap(), "web");
+        assert_eq!(context.get("app_labels_tier").unwrap(), "front");
+        assert_eq!(context.get("app_name_nested").unwrap(), "shadowed");
+        assert_eq!(context.get("ports").unwrap(), "80,443");
+        assert_eq!(context.get("empty").unwrap(), "");
+        assert_eq!(context.get("ratio").unwrap(), "0.5");
+        assert_eq!(context.get("app"), None);
+        assert_eq!(context.get("matrix"), None);
+
+        let mut vars: Vec<_> = context.vars().collect();
+        vars.sort();
+        assert_eq!(vars.len(), 6);
+        assert_eq!(vars[0], (Cow::from("app_labels_tier"), Cow::from("front")));
+    }
+}
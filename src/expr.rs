@@ -0,0 +1,252 @@
+//! Integer arithmetic expressions.
+
+use crate::Error;
+use std::borrow::Cow;
+
+/// Check whether a placeholder body looks like an arithmetic expression.
+///
+/// Expressions are made of integers, variable names, parentheses and the
+/// `+`, `-`, `*`, `/` and `%` operators, and must contain at least one
+/// operator.
+pub(crate) fn is_expression(body: &str) -> bool {
+    body.bytes()
+        .any(|b| matches!(b, b'+' | b'-' | b'*' | b'/' | b'%'))
+        && body.bytes().all(|b| {
+            b.is_ascii_alphanumeric()
+                || matches!(
+                    b,
+                    b'_' | b' ' | b'\t' | b'+' | b'-' | b'*' | b'/' | b'%' | b'(' | b')'
+                )
+        })
+}
+
+/// Evaluate an arithmetic expression.
+///
+/// Variables are resolved through `lookup`, and must have integer values.
+/// If any variable cannot be resolved, this returns `Ok(Err(name))` with
+/// the name of the first missing one.
+pub(crate) fn evaluate<'v, F>(expression: &str, lookup: F) -> Result<Result<i64, String>, Error>
+where
+    F: FnMut(&str) -> Option<Cow<'v, str>>,
+{
+    let tokens = tokenize(expression)?;
+    let mut parser = Parser {
+        expression,
+        tokens: &tokens,
+        pos: 0,
+        lookup,
+        missing: None,
+    };
+    let value = parser.sum()?;
+    if parser.pos != tokens.len() {
+        return Err(parser.error("unexpected token"));
+    }
+    match parser.missing {
+        Some(name) => Ok(Err(name)),
+        None => Ok(Ok(value)),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token<'a> {
+    Number(i64),
+    Name(&'a str),
+    Op(u8),
+    Open,
+    Close,
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token<'_>>, Error> {
+    let bytes = expression.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let b = bytes[pos];
+        let start = pos;
+        pos += 1;
+        match b {
+            b' ' | b'\t' => {}
+            b'(' => tokens.push(Token::Open),
+            b')' => tokens.push(Token::Close),
+            b'+' | b'-' | b'*' | b'/' | b'%' => tokens.push(Token::Op(b)),
+            b'0'..=b'9' => {
+                while pos < bytes.len() && bytes[pos].is_ascii_alphanumeric() {
+                    pos += 1;
+                }
+                let literal = &expression[start..pos];
+                let number = literal.parse().map_err(|_| {
+                    Error(format!(
+                        "invalid integer '{}' in expression '{}'",
+                        literal, expression
+                    ))
+                })?;
+                tokens.push(Token::Number(number));
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                while pos < bytes.len()
+                    && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_')
+                {
+                    pos += 1;
+                }
+                tokens.push(Token::Name(&expression[start..pos]));
+            }
+            _ => {
+                return Err(Error(format!(
+                    "invalid character '{}' in expression '{}'",
+                    expression[start..].chars().next().unwrap_or_default(),
+                    expression
+                )))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser, evaluating while parsing.
+struct Parser<'t, 'a, F> {
+    expression: &'a str,
+    tokens: &'t [Token<'a>],
+    pos: usize,
+    lookup: F,
+    missing: Option<String>,
+}
+
+impl<'t, 'a, 'v, F> Parser<'t, 'a, F>
+where
+    F: FnMut(&str) -> Option<Cow<'v, str>>,
+{
+    fn error(&self, msg: &str) -> Error {
+        Error(format!("{} in expression '{}'", msg, self.expression))
+    }
+
+    fn next(&mut self) -> Option<&'t Token<'a>> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn peek_op(&self, ops: &[u8]) -> Option<u8> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) if ops.contains(op) => Some(*op),
+            _ => None,
+        }
+    }
+
+    /// `sum := product (('+' | '-') product)*`
+    fn sum(&mut self) -> Result<i64, Error> {
+        let mut value = self.product()?;
+        while let Some(op) = self.peek_op(b"+-") {
+            self.pos += 1;
+            let rhs = self.product()?;
+            let result = match op {
+                b'+' => value.checked_add(rhs),
+                _ => value.checked_sub(rhs),
+            };
+            value = result.ok_or_else(|| self.error("integer overflow"))?;
+        }
+        Ok(value)
+    }
+
+    /// `product := unary (('*' | '/' | '%') unary)*`
+    fn product(&mut self) -> Result<i64, Error> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.peek_op(b"*/%") {
+            self.pos += 1;
+            let rhs = self.unary()?;
+            if op != b'*' && rhs == 0 && self.missing.is_none() {
+                return Err(self.error("division by zero"));
+            }
+            let result = match op {
+                b'*' => value.checked_mul(rhs),
+                b'/' => value.checked_div(rhs),
+                _ => value.checked_rem(rhs),
+            };
+            // Missing variables evaluate to zero, the result is discarded anyway
+            value = match result {
+                Some(value) => value,
+                None if self.missing.is_some() => 0,
+                None => return Err(self.error("integer overflow")),
+            };
+        }
+        Ok(value)
+    }
+
+    /// `unary := '-' unary | atom`
+    fn unary(&mut self) -> Result<i64, Error> {
+        if self.peek_op(b"-").is_some() {
+            self.pos += 1;
+            let value = self.unary()?;
+            return value
+                .checked_neg()
+                .ok_or_else(|| self.error("integer overflow"));
+        }
+        self.atom()
+    }
+
+    /// `atom := NUMBER | NAME | '(' sum ')'`
+    fn atom(&mut self) -> Result<i64, Error> {
+        match self.next().cloned() {
+            Some(Token::Number(n)) => Ok(n),
+            Some(Token::Name(name)) => match (self.lookup)(name) {
+                Some(value) => value.trim().parse().map_err(|_| {
+                    Error(format!(
+                        "variable '{}' has non-integer value '{}' in expression '{}'",
+                        name, value, self.expression
+                    ))
+                }),
+                None => {
+                    self.missing.get_or_insert_with(|| name.to_string());
+                    Ok(0)
+                }
+            },
+            Some(Token::Open) => {
+                let value = self.sum()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(value),
+                    _ => Err(self.error("missing closing parenthesis")),
+                }
+            }
+            Some(_) => Err(self.error("unexpected token")),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> Result<Result<i64, String>, Error> {
+        evaluate(expression, |name| match name {
+            "port" => Some(Cow::Borrowed("8080")),
+            "text" => Some(Cow::Borrowed("foo")),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert!(is_expression("port + 1"));
+        assert!(!is_expression("port"));
+        assert!(!is_expression(r#"port + "1""#));
+
+        assert_eq!(eval("port + 1").unwrap(), Ok(8081));
+        assert_eq!(eval("2 + 3 * 4 - 10 / 3 % 2").unwrap(), Ok(13));
+        assert_eq!(eval("-(port - 80) * 2").unwrap(), Ok(-16000));
+        assert_eq!(eval("missing + 1").unwrap(), Err("missing".to_string()));
+        assert_eq!(eval("1 / missing").unwrap(), Err("missing".to_string()));
+
+        for invalid in [
+            "text + 1",
+            "1 / 0",
+            "(1 + 2",
+            "1 +",
+            "1 2",
+            "9223372036854775807 + 1",
+            "1x + 1",
+        ] {
+            eval(invalid).unwrap_err();
+        }
+    }
+}
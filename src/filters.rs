@@ -0,0 +1,178 @@
+//! Filters applied to placeholder values.
+
+use crate::template::Filter;
+use crate::Error;
+use std::borrow::Cow;
+#[cfg(feature = "unicode")]
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Whether a filter is builtin, placeholders with other filters being left
+/// as-is unless registered.
+pub(crate) fn is_builtin(name: &str) -> bool {
+    matches!(name, "pad" | "hex" | "precision") || (cfg!(feature = "unicode") && name == "truncate")
+}
+
+/// Apply a filter to a value.
+pub(crate) fn apply(filter: &Filter<'_>, value: &str) -> Result<String, Error> {
+    match filter.name {
+        "pad" => pad(&filter.args, value),
+        "hex" => hex(&filter.args, value),
+        "precision" => precision(&filter.args, value),
+        #[cfg(feature = "unicode")]
+        "truncate" => truncate(&filter.args, value),
+        name => Err(Error(format!("unknown filter '{}'", name))),
+    }
+}
+
+/// Zero-pad an integer to the given width: `pad(WIDTH)`.
+fn pad(args: &[Cow<'_, str>], value: &str) -> Result<String, Error> {
+    let width = usize_arg("pad", args)?;
+    let n: i64 = parse_number("pad", value)?;
+    Ok(format!("{:0width$}", n, width = width))
+}
+
+/// Format a non-negative integer as lowercase hexadecimal: `hex`.
+fn hex(args: &[Cow<'_, str>], value: &str) -> Result<String, Error> {
+    if !args.is_empty() {
+        return Err(Error("hex(): too many arguments".to_string()));
+    }
+    let n: u64 = parse_number("hex", value)?;
+    Ok(format!("{:x}", n))
+}
+
+/// Format a number with the given count of decimals: `precision(DIGITS)`.
+fn precision(args: &[Cow<'_, str>], value: &str) -> Result<String, Error> {
+    let digits = usize_arg("precision", args)?;
+    let n: f64 = parse_number("precision", value)?;
+    Ok(format!("{:.digits$}", n, digits = digits))
+}
+
+/// Shorten a value to a count of grapheme clusters, ellipsis included:
+/// `truncate(LENGTH)` or `truncate(LENGTH, ELLIPSIS)`.
+#[cfg(feature = "unicode")]
+fn truncate(args: &[Cow<'_, str>], value: &str) -> Result<String, Error> {
+    let (len, ellipsis) = match args {
+        [len] => (len, ""),
+        [len, ellipsis] => (len, ellipsis.as_ref()),
+        _ => return Err(Error("truncate(): expected a length argument".to_string())),
+    };
+    let len: usize = len
+        .parse()
+        .map_err(|_| Error(format!("truncate(): invalid length '{}'", len)))?;
+
+    if value.graphemes(true).nth(len).is_none() {
+        return Ok(value.to_string());
+    }
+    let keep = len.saturating_sub(ellipsis.graphemes(true).count());
+    let end = value
+        .grapheme_indices(true)
+        .nth(keep)
+        .map_or(value.len(), |(pos, _)| pos);
+    Ok(format!("{}{}", &value[..end], ellipsis))
+}
+
+fn usize_arg(filter: &str, args: &[Cow<'_, str>]) -> Result<usize, Error> {
+    match args {
+        [arg] => arg
+            .parse()
+            .map_err(|_| Error(format!("{}(): invalid argument '{}'", filter, arg))),
+        _ => Err(Error(format!("{}(): expected a single argument", filter))),
+    }
+}
+
+fn parse_number<N: std::str::FromStr>(filter: &str, value: &str) -> Result<N, Error> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| Error(format!("{}(): invalid number '{}'", filter, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::{parse, Segment};
+
+    fn filter(template: &str, value: &str) -> Result<String, Error> {
+        match &parse(template)[0] {
+            Segment::Placeholder(p) => p
+                .filters
+                .iter()
+                .try_fold(value.to_string(), |value, f| apply(f, &value)),
+            _ => unreachable!(),
+        }
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn truncate_filter() {
+        assert_eq!(filter("${S|truncate(5)}", "hello").unwrap(), "hello");
+        assert_eq!(filter("${S|truncate(4)}", "hello").unwrap(), "hell");
+        assert_eq!(
+            filter(r#"${S|truncate(4, "...")}"#, "hello").unwrap(),
+            "h..."
+        );
+        assert_eq!(filter("${S|truncate(2, …)}", "héllo").unwrap(), "h…");
+        // Combining characters and emoji sequences are kept whole
+        assert_eq!(
+            filter("${S|truncate(2)}", "e\u{301}e\u{301}e").unwrap(),
+            "e\u{301}e\u{301}"
+        );
+        assert_eq!(filter("${S|truncate(1)}", "👨‍👩‍👧x").unwrap(), "👨‍👩‍👧");
+        filter("${S|truncate(x)}", "hello").unwrap_err();
+    }
+
+    #[test]
+    fn number_filters() {
+        assert_eq!(filter("${ID|pad(5)}", "42").unwrap(), "00042");
+        assert_eq!(filter("${ID|pad(5)}", "-42").unwrap(), "-0042");
+        assert_eq!(filter("${ID|pad(1)}", "123").unwrap(), "123");
+        assert_eq!(filter("${ID|hex}", "255").unwrap(), "ff");
+        assert_eq!(
+            filter("${ID|hex|pad(4)}", "255").unwrap_err().to_string(),
+            "ksubst error: pad(): invalid number 'ff'"
+        );
+        assert_eq!(
+            filter("${SIZE|precision(2)}", "1.005e3").unwrap(),
+            "1005.00"
+        );
+        assert_eq!(filter("${SIZE|precision(0)}", "2.5").unwrap(), "2");
+
+        filter("${ID|pad}", "42").unwrap_err();
+        filter("${ID|truncate}", "42").unwrap_err();
+        filter("${ID|pad(x)}", "42").unwrap_err();
+        filter("${ID|hex}", "-1").unwrap_err();
+        filter("${ID|hex(2)}", "1").unwrap_err();
+        filter("${ID|precision(2)}", "abc").unwrap_err();
+        filter("${ID|unknown}", "1").unwrap_err();
+    }
+
+    #[test]
+    fn unknown_filters() {
+        let context = crate::context! {"HOST" => "host", "ID" => 7};
+        let template = "${HOST|nofilter} ${ID|pad(2)|upper} ${HOST|tr a-z A-Z} ${ID|pad(2)}";
+        let output = crate::substitute(template, &context).unwrap();
+        assert_eq!(
+            output,
+            "${HOST|nofilter} ${ID|pad(2)|upper} ${HOST|tr a-z A-Z} 07"
+        );
+        let matcher_context: std::collections::HashMap<String, String> = (0..200)
+            .map(|i| (format!("VAR{}", i), String::new()))
+            .chain([
+                ("HOST".to_string(), "host".to_string()),
+                ("ID".to_string(), "7".to_string()),
+            ])
+            .collect();
+        let matcher = crate::Matcher::new(&matcher_context).unwrap();
+        assert_eq!(matcher.substitute(template).unwrap(), output);
+        let segments: String = crate::substitute_iter(template, &context)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(segments, output);
+
+        let output = crate::Substituter::new()
+            .register_filter("upper", |s| s.to_uppercase())
+            .substitute(template, &context)
+            .unwrap();
+        assert_eq!(output, "${HOST|nofilter} 07 ${HOST|tr a-z A-Z} 07");
+    }
+}
@@ -0,0 +1,198 @@
+//! File-local variables declared in template front matter.
+
+use crate::{Error, Lookup};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Front matter block of a template, declaring file-local variables.
+///
+/// Front matter is a YAML block at the very top of a template, delimited by
+/// `---` lines, with optional `defaults` values and `required` variables.
+/// It is removed from the template body. This requires the `yaml` feature.
+///
+/// ```rust
+/// use ksubst::FrontMatter;
+///
+/// let template = "---\ndefaults:\n  PORT: 8080\nrequired: [HOST]\n---\nlisten ${HOST}:${PORT}\n";
+/// let (front_matter, body) = FrontMatter::split(template).unwrap();
+/// let front_matter = front_matter.unwrap();
+/// assert_eq!(body, "listen ${HOST}:${PORT}\n");
+///
+/// let context = front_matter.context(&ksubst::context! {"HOST" => "example.com"}).unwrap();
+/// let output = ksubst::substitute(body, &context).unwrap();
+/// assert_eq!(output, "listen example.com:8080\n");
+///
+/// assert!(front_matter.context(&ksubst::context! {}).is_err());
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FrontMatter {
+    /// Default values, used for variables missing from the context.
+    pub defaults: HashMap<String, String>,
+    /// Variables which must be defined, by the context or defaults.
+    pub required: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Declarations {
+    #[serde(default)]
+    defaults: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default)]
+    required: Vec<String>,
+}
+
+impl FrontMatter {
+    /// Split a template into its front matter, if any, and its body.
+    ///
+    /// Templates not starting with a `---` line, or without a closing `---`
+    /// line, have no front matter. This fails if the front matter is not a
+    /// valid declarations block.
+    pub fn split(template: &str) -> Result<(Option<Self>, &str), Error> {
+        let Some(rest) = strip_delimiter(template) else {
+            return Ok((None, template));
+        };
+        let mut offset = 0;
+        let (block, body) = loop {
+            let line_end = match rest[offset..].find('\n') {
+                Some(end) => offset + end + 1,
+                None => rest.len(),
+            };
+            if let Some(body) = strip_delimiter(&rest[offset..]) {
+                break (&rest[..offset], body);
+            }
+            if line_end == rest.len() {
+                return Ok((None, template));
+            }
+            offset = line_end;
+        };
+
+        let declarations: Option<Declarations> = serde_yaml::from_str(block)
+            .map_err(|e| Error(format!("invalid front matter: {}", e)))?;
+        let Some(declarations) = declarations else {
+            return Ok((Some(Self::default()), body));
+        };
+        let defaults = declarations
+            .defaults
+            .into_iter()
+            .map(|(name, value)| Ok((name.clone(), format_value(&name, value)?)))
+            .collect::<Result<_, Error>>()?;
+        let front_matter = Self {
+            defaults,
+            required: declarations.required,
+        };
+        Ok((Some(front_matter), body))
+    }
+
+    /// Context of the template, with defaults beneath `variables`.
+    ///
+    /// This fails if some required variables are defined neither by
+    /// `variables` nor by defaults.
+    pub fn context<L>(&self, variables: &L) -> Result<HashMap<String, String>, Error>
+    where
+        L: Lookup + ?Sized,
+    {
+        let mut context: HashMap<String, String> = variables
+            .vars()
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        for (name, value) in &self.defaults {
+            context.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+
+        let missing: Vec<&str> = self
+            .required
+            .iter()
+            .filter(|name| !context.contains_key(name.as_str()))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error(format!(
+                "required variables are missing: {}",
+                missing.join(", ")
+            )));
+        }
+        Ok(context)
+    }
+}
+
+/// Strip a `---` delimiter line from the start of `text`.
+fn strip_delimiter(text: &str) -> Option<&str> {
+    let rest = text.strip_prefix("---")?;
+    let rest = rest.strip_prefix('\r').unwrap_or(rest);
+    match rest.strip_prefix('\n') {
+        Some(rest) => Some(rest),
+        None if rest.is_empty() => Some(rest),
+        None => None,
+    }
+}
+
+/// Format a default value declared in front matter.
+fn format_value(name: &str, value: serde_yaml::Value) -> Result<String, Error> {
+    match value {
+        serde_yaml::Value::Null => Ok(String::new()),
+        serde_yaml::Value::Bool(b) => Ok(b.to_string()),
+        serde_yaml::Value::Number(n) => Ok(n.to_string()),
+        serde_yaml::Value::String(s) => Ok(s),
+        _ => Err(Error(format!(
+            "front matter default of '{}' must be a scalar",
+            name
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context;
+
+    #[test]
+    fn split_front_matter() {
+        let template = "---\r\ndefaults:\n  A: a\n  N: 1\n  E: ~\n---\r\nbody ${A}";
+        let (front_matter, body) = FrontMatter::split(template).unwrap();
+        let front_matter = front_matter.unwrap();
+        assert_eq!(body, "body ${A}");
+        assert_eq!(
+            front_matter.defaults,
+            context! {"A" => "a", "N" => "1", "E" => ""}
+        );
+        assert!(front_matter.required.is_empty());
+
+        let (front_matter, body) = FrontMatter::split("---\n---\n").unwrap();
+        assert_eq!(front_matter, Some(FrontMatter::default()));
+        assert_eq!(body, "");
+
+        for template in [
+            "body",
+            "---\nunclosed: true\n",
+            "--- \n---\n",
+            "\n---\n---\n",
+        ] {
+            assert_eq!(FrontMatter::split(template).unwrap(), (None, template));
+        }
+
+        FrontMatter::split("---\nunknown: 1\n---\n").unwrap_err();
+        FrontMatter::split("---\ndefaults:\n  A: [1]\n---\n").unwrap_err();
+    }
+
+    #[test]
+    fn front_matter_context() {
+        let front_matter = FrontMatter {
+            defaults: context! {"A" => "default", "B" => "default"},
+            required: vec!["B".to_string(), "C".to_string()],
+        };
+        let context = front_matter
+            .context(&context! {"A" => "global", "C" => ""})
+            .unwrap();
+        assert_eq!(
+            context,
+            context! {"A" => "global", "B" => "default", "C" => ""}
+        );
+
+        let err = front_matter.context(&context! {}).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ksubst error: required variables are missing: C"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Streaming substitution for [futures] streams.
+//!
+//! This module is available with the `futures` feature enabled. Placeholders
+//! split across chunks are handled transparently.
+//!
+//! [futures]: https://docs.rs/futures
+
+use crate::stream::Streamer;
+use crate::Error;
+use futures_core::Stream;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+/// Stream of substituted chunks, mapped from a stream of input chunks.
+///
+/// Input chunks can be any bytes container, e.g. `Vec<u8>`, `String` or
+/// `Bytes`. Output chunks are emitted as soon as they can no longer be part
+/// of a placeholder, so they don't match input chunks one to one. The
+/// stream ends after the first substitution error.
+///
+/// ```rust
+/// use futures::stream::{self, StreamExt};
+/// use ksubst::futures::SubstStream;
+///
+/// let context = ksubst::context! {"hostname" => "example.com"};
+/// let chunks = stream::iter(["https://${host", "name}/"]);
+///
+/// let output: Vec<String> = futures::executor::block_on(
+///     SubstStream::new(chunks, context).map(Result::unwrap).collect(),
+/// );
+/// assert_eq!(output.concat(), "https://example.com/");
+/// ```
+#[derive(Debug)]
+pub struct SubstStream<S> {
+    inner: S,
+    variables: HashMap<String, String>,
+    streamer: Streamer,
+    finished: bool,
+}
+
+impl<S> SubstStream<S> {
+    /// Wrap `inner`, substituting placeholders with values from `variables`.
+    pub fn new(inner: S, variables: HashMap<String, String>) -> Self {
+        Self {
+            inner,
+            variables,
+            streamer: Streamer::default(),
+            finished: false,
+        }
+    }
+
+    /// Consume this adapter, returning the inner stream.
+    ///
+    /// Any data not yet emitted is lost.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, T> Stream for SubstStream<S>
+where
+    S: Stream<Item = T> + Unpin,
+    T: AsRef<[u8]>,
+{
+    type Item = Result<String, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        while !this.finished {
+            let result = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(chunk) => this.streamer.feed(chunk.as_ref(), &this.variables),
+                None => {
+                    this.finished = true;
+                    this.streamer.finish(&this.variables)
+                }
+            };
+            match result {
+                // Input may be entirely held back
+                Ok(output) if output.is_empty() => {}
+                Ok(output) => return Poll::Ready(Some(Ok(output))),
+                Err(e) => {
+                    this.finished = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::stream::{self, StreamExt};
+
+    #[test]
+    fn chunks_stream() {
+        let context = crate::context! {"VAR" => "var"};
+        let input = "foo ${VAR} ${VAR.}bar ${MISSING}";
+
+        for size in 1..input.len() {
+            let chunks = stream::iter(input.as_bytes().chunks(size));
+            let output: Vec<String> = block_on(
+                SubstStream::new(chunks, context.clone())
+                    .map(Result::unwrap)
+                    .collect(),
+            );
+            assert!(output.iter().all(|chunk| !chunk.is_empty()));
+            assert_eq!(output.concat(), "foo var var.bar ${MISSING}");
+        }
+
+        let chunks = stream::iter([vec![b'f', 0xff], vec![b'o']]);
+        let output: Vec<_> = block_on(SubstStream::new(chunks, context).collect());
+        assert_eq!(output.len(), 1);
+        assert!(output[0].is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//! Inclusion of templates into other templates.
+
+use crate::template::{self, Segment};
+use crate::Error;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+
+/// Maximum nesting depth of included templates.
+const MAX_DEPTH: usize = 64;
+
+/// Source of the templates included by `${include "path"}` directives.
+///
+/// This is implemented for maps of paths to templates, and can be
+/// implemented to load templates from files or any other storage.
+pub trait Resolver {
+    /// Content of the template included as `path`.
+    fn resolve(&self, path: &str) -> io::Result<String>;
+}
+
+impl<S: BuildHasher> Resolver for HashMap<String, String, S> {
+    fn resolve(&self, path: &str) -> io::Result<String> {
+        self.get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "template not found"))
+    }
+}
+
+/// Replace `${include "path"}` directives with the templates they include.
+///
+/// Included templates are expanded recursively, and are all resolved from
+/// the same `resolver`. The path is a double-quoted string, supporting `\"`
+/// and `\\` escapes. This fails if a template can't be resolved, or if it
+/// includes itself, directly or not.
+///
+/// ```rust
+/// let partials = ksubst::context! {
+///     "header.conf" => "# ${APP} configuration\n${include \"footer.conf\"}",
+///     "footer.conf" => "# generated",
+/// };
+/// let template = ksubst::expand_includes("${include \"header.conf\"}\nport = 80", &partials).unwrap();
+/// assert_eq!(template, "# ${APP} configuration\n# generated\nport = 80");
+///
+/// let cycle = ksubst::context! {"a" => "${include \"b\"}", "b" => "${include \"a\"}"};
+/// assert!(ksubst::expand_includes("${include \"a\"}", &cycle).is_err());
+/// ```
+pub fn expand_includes<R>(template: &str, resolver: &R) -> Result<String, Error>
+where
+    R: Resolver + ?Sized,
+{
+    let mut output = String::with_capacity(template.len());
+    expand(template, resolver, &mut Vec::new(), &mut output)?;
+    Ok(output)
+}
+
+/// Expand the includes of a template, given the stack of including templates.
+fn expand<R>(
+    template: &str,
+    resolver: &R,
+    stack: &mut Vec<String>,
+    output: &mut String,
+) -> Result<(), Error>
+where
+    R: Resolver + ?Sized,
+{
+    for segment in template::segments(template) {
+        // Directives don't parse as any other segment
+        let Segment::Literal(literal) = segment else {
+            output.push_str(segment.as_str());
+            continue;
+        };
+        let mut rest = literal.text;
+        while let Some(start) = rest.find("${include") {
+            let Some((path, len)) = parse_include(&rest[start..]) else {
+                output.push_str(&rest[..start + 2]);
+                rest = &rest[start + 2..];
+                continue;
+            };
+            output.push_str(&rest[..start]);
+            rest = &rest[start + len..];
+
+            if stack.iter().any(|including| *including == path) {
+                let cycle: Vec<&str> = stack.iter().map(String::as_str).collect();
+                return Err(Error(format!(
+                    "include cycle: {} -> {}",
+                    cycle.join(" -> "),
+                    path
+                )));
+            }
+            if stack.len() >= MAX_DEPTH {
+                return Err(Error(format!(
+                    "includes nested more than {} levels deep",
+                    MAX_DEPTH
+                )));
+            }
+            let included = resolver
+                .resolve(&path)
+                .map_err(|e| Error(format!("failed to include '{}': {}", path, e)))?;
+            stack.push(path.into_owned());
+            expand(&included, resolver, stack, output)?;
+            stack.pop();
+        }
+        output.push_str(rest);
+    }
+    Ok(())
+}
+
+/// Parse an include directive at the start of `input`, returning its path
+/// and length.
+fn parse_include(input: &str) -> Option<(Cow<'_, str>, usize)> {
+    let rest = input.strip_prefix("${include")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let (path, rest) = template::parse_quoted(rest.trim_start().strip_prefix('"')?)?;
+    let rest = rest.trim_start().strip_prefix('}')?;
+    Some((path, input.len() - rest.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context;
+
+    #[test]
+    fn nested_includes() {
+        let partials = context! {
+            "a" => "a(${include \"b\"}, ${include \"b\"})",
+            "b" => "b${B}",
+            "quoted \"c\"" => "c",
+        };
+        let template = "${include  \"a\" } ${include \"quoted \\\"c\\\"\"} ${include}\
+            ${include \"a\"${# ${include \"a\"}} ${include a}";
+        assert_eq!(
+            expand_includes(template, &partials).unwrap(),
+            "a(b${B}, b${B}) c ${include}${include \"a\"${# ${include \"a\"}} ${include a}"
+        );
+
+        let err = expand_includes("${include \"missing\"}", &partials).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ksubst error: failed to include 'missing': template not found"
+        );
+    }
+
+    #[test]
+    fn include_cycles() {
+        let partials = context! {
+            "a" => "${include \"b\"}",
+            "b" => "${include \"c\"}",
+            "c" => "${include \"a\"}",
+            "self" => "${include \"self\"}",
+        };
+        let err = expand_includes("${include \"a\"}", &partials).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ksubst error: include cycle: a -> b -> c -> a"
+        );
+        expand_includes("${include \"self\"}", &partials).unwrap_err();
+
+        let deep: HashMap<String, String> = (0..=MAX_DEPTH)
+            .map(|i| (i.to_string(), format!("${{include \"{}\"}}", i + 1)))
+            .collect();
+        let err = expand_includes("${include \"0\"}", &deep).unwrap_err();
+        assert!(err.to_string().contains("nested"));
+    }
+}
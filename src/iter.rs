@@ -0,0 +1,147 @@
+//! Substitution as an iterator over output segments.
+
+use crate::template::{self, Segment, Segments};
+use crate::{filters, validate_vars, Error};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Iterator over the segments of a substituted template.
+///
+/// This is created by [`substitute_iter`]. Concatenating all items gives the
+/// same output as [`substitute`](crate::substitute).
+#[derive(Debug)]
+pub struct SubstIter<'a> {
+    segments: Segments<'a>,
+    variables: &'a HashMap<String, String>,
+    /// Suffix to yield after a substituted value.
+    suffix: Option<&'a str>,
+    validated: bool,
+    failed: bool,
+}
+
+/// Lazily substitute variables in a template, segment by segment.
+///
+/// Items borrow literal text from the template and values from the context,
+/// so the output can be written to a sink without building a `String`.
+/// Only filtered values are allocated. Invalid variables and filter errors
+/// are reported as an error item, which ends the iteration.
+///
+/// ```rust
+/// use std::io::Write;
+///
+/// let context = ksubst::context! {"hostname" => "example.com"};
+/// let mut output = Vec::new();
+/// for segment in ksubst::substitute_iter("https://${hostname}/", &context) {
+///     output.write_all(segment.unwrap().as_bytes()).unwrap();
+/// }
+/// assert_eq!(output, b"https://example.com/");
+/// ```
+pub fn substitute_iter<'a>(
+    template: &'a str,
+    variables: &'a HashMap<String, String>,
+) -> SubstIter<'a> {
+    SubstIter {
+        segments: template::segments(template),
+        variables,
+        suffix: None,
+        validated: false,
+        failed: false,
+    }
+}
+
+impl<'a> Iterator for SubstIter<'a> {
+    type Item = Result<Cow<'a, str>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if !self.validated {
+            self.validated = true;
+            if let Err(e) = validate_vars(self.variables) {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+        if let Some(suffix) = self.suffix.take() {
+            return Some(Ok(Cow::Borrowed(suffix)));
+        }
+
+        loop {
+            let segment = self.segments.next()?;
+            let Segment::Placeholder(p) = segment else {
+                return Some(Ok(Cow::Borrowed(segment.as_str())));
+            };
+            // If variable is not found, leave the placeholder as is
+            let Some(value) = self.variables.get(p.name) else {
+                return Some(Ok(Cow::Borrowed(p.text)));
+            };
+            if !p.filters.iter().all(|f| filters::is_builtin(f.name)) {
+                return Some(Ok(Cow::Borrowed(p.text)));
+            }
+
+            let mut value = Cow::Borrowed(value.as_str());
+            for filter in &p.filters {
+                match filters::apply(filter, &value) {
+                    Ok(filtered) => value = Cow::Owned(filtered),
+                    Err(e) => {
+                        self.failed = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+            // Empty values drop their suffix, and produce no segment
+            if !value.is_empty() {
+                self.suffix = p.suffix.filter(|s| !s.is_empty());
+                return Some(Ok(value));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{context, substitute};
+
+    #[test]
+    fn segments_iter() {
+        let template = "a ${VAR} ${VAR.}b ${EMPTY-}c${# comment} ${MISSING} ${NUM|pad(3)} ${now()}";
+        let context = context! {"VAR" => "var", "EMPTY" => "", "NUM" => 7};
+
+        let segments: Vec<Cow<'_, str>> = substitute_iter(template, &context)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            segments,
+            vec![
+                "a ",
+                "var",
+                " ",
+                "var",
+                ".",
+                "b ",
+                "c",
+                "${# comment}",
+                " ",
+                "${MISSING}",
+                " ",
+                "007",
+                " ",
+                "${now()}"
+            ]
+        );
+        assert!(matches!(segments[1], Cow::Borrowed(_)));
+        assert!(matches!(segments[11], Cow::Owned(_)));
+        assert_eq!(segments.concat(), substitute(template, &context).unwrap());
+
+        let mut iter = substitute_iter("${VAR|hex}", &context);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+
+        let invalid = context! {"VAR" => "${VAR}"};
+        let mut iter = substitute_iter("foo", &invalid);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+}
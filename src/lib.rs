@@ -16,10 +16,11 @@
 //! let base_url = "${protocol}://${hostname}/${endpoint}";
 //! assert!(ksubst::is_templated(base_url));
 //!
-//! let mut context = std::collections::HashMap::new();
-//! context.insert("protocol".to_string(), "https".to_string());
-//! context.insert("hostname".to_string(), "example.com".to_string());
-//! context.insert("endpoint".to_string(), "login".to_string());
+//! let context = ksubst::context! {
+//!     "protocol" => "https",
+//!     "hostname" => "example.com",
+//!     "endpoint" => "login",
+//! };
 //! assert!(ksubst::validate_vars(&context).is_ok());
 //!
 //! let final_url = ksubst::substitute(base_url, &context).unwrap();
@@ -29,52 +30,210 @@
 
 #![allow(clippy::implicit_hasher)]
 
-use regex::Regex;
 use std::collections::HashMap;
+use template::Segment;
+
+mod substituter;
+pub use substituter::{Change, OutputCheck, Rendered, Report, Resolved, Substituter, Unresolved};
+
+mod cond;
+mod expr;
+mod filters;
+
+mod cache;
+pub use cache::RenderCache;
+
+mod builtins;
+pub use builtins::Builtin;
+
+mod constraint;
+pub use constraint::Constraint;
+
+mod context;
+pub use context::{Context, ListMerge};
+
+mod lookup;
+pub use lookup::{Layered, Lookup, Prefixed, Vars};
+
+mod iter;
+pub use iter::{substitute_iter, SubstIter};
+
+mod matcher;
+pub use matcher::Matcher;
+
+mod lines;
+pub use lines::{substitute_lines, SubstLines};
+
+mod lint;
+pub use lint::{lint, Diagnostic, Lint};
+
+mod metadata;
+pub use metadata::{json_schema, variables_metadata, VariableInfo};
+
+#[cfg(feature = "yaml")]
+mod front_matter;
+#[cfg(feature = "yaml")]
+pub use front_matter::FrontMatter;
+
+mod include;
+pub use include::{expand_includes, Resolver};
+
+mod structured;
+pub use structured::substitute_json;
+#[cfg(feature = "yaml")]
+pub use structured::substitute_yaml;
+
+mod syntax;
+pub use syntax::{Delimiters, Syntax};
+
+mod stats;
+pub use stats::UsageStats;
+
+mod stream;
+
+mod writer;
+pub use writer::SubstWriter;
+
+#[cfg(feature = "tokio")]
+pub mod tokio;
+
+#[cfg(feature = "futures")]
+pub mod futures;
+
+pub mod template;
 
 /// Library errors.
 #[derive(thiserror::Error, Debug)]
 #[error("ksubst error: {0}")]
 pub struct Error(String);
 
+/// Convert a substitution error to an I/O error, for I/O adapters.
+pub(crate) fn invalid_data(err: Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
+/// Build a context from `name => value` pairs.
+///
+/// This creates a `HashMap<String, String>`, converting names and values
+/// with their `Display` implementation, so values of different types can be
+/// mixed.
+///
+/// ```rust
+/// let context = ksubst::context! {"host" => "example.com", "port" => 8080};
+/// assert_eq!(context["port"], "8080");
+///
+/// let output = ksubst::substitute("${host}:${port}", &context).unwrap();
+/// assert_eq!(output, "example.com:8080");
+/// ```
+#[macro_export]
+macro_rules! context {
+    ($($name:expr => $value:expr),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut context = ::std::collections::HashMap::<::std::string::String, ::std::string::String>::new();
+        $(
+            context.insert(::std::string::ToString::to_string(&$name), ::std::string::ToString::to_string(&$value));
+        )*
+        context
+    }};
+}
+
 /// Substitute variables in a template string with optional suffix handling.
 ///
 /// This function replaces tokens of the form `${VAR}`, `${VAR.}`, `${VAR-}` in the template string.
 /// - If the variable `VAR` has a non-empty value, it replaces the placeholder with `value + suffix`.
 /// - If the variable `VAR` has an empty value (`""`), it replaces the entire placeholder (including the suffix) with an empty string.
+///
+/// See [`substitute_with`] for other kinds of contexts.
 pub fn substitute<T>(template: T, variables: &HashMap<String, String>) -> Result<String, Error>
 where
     T: Into<String>,
 {
-    let mut output = template.into();
-    if variables.is_empty() {
-        return Ok(output);
-    }
-
-    validate_vars(variables)?;
-
-    // Regular expression to match placeholders like ${VAR}, ${VAR.}, ${VAR-}
-    let re = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)([\.\-][^}]*)?\}").unwrap();
-
-    output = re
-        .replace_all(&output, |caps: &regex::Captures| {
-            let var_name = caps.get(1).map_or("", |m| m.as_str());
-            let suffix = caps.get(2).map_or("", |m| m.as_str());
-
-            if let Some(value) = variables.get(var_name) {
-                if !value.is_empty() {
-                    format!("{}{}", value, suffix)
-                } else {
-                    "".to_string()
-                }
-            } else {
-                // If variable is not found, leave the placeholder as is
-                caps.get(0).unwrap().as_str().to_string()
-            }
-        })
-        .to_string();
+    Substituter::new().substitute(template, variables)
+}
+
+/// Substitute variables from any [`Lookup`] context in a template string.
+///
+/// This is like [`substitute`], for contexts other than maps of strings,
+/// e.g. a `HashMap` with values of any type implementing `Display`.
+///
+/// ```rust
+/// let mut context: std::collections::HashMap<&str, u16> = std::collections::HashMap::new();
+/// context.insert("port", 8080);
+///
+/// let output = ksubst::substitute_with("localhost:${port}", &context).unwrap();
+/// assert_eq!(output, "localhost:8080");
+/// ```
+pub fn substitute_with<T, L>(template: T, variables: &L) -> Result<String, Error>
+where
+    T: Into<String>,
+    L: Lookup + ?Sized,
+{
+    Substituter::new().substitute(template, variables)
+}
+
+/// Substitute variables in a template string, requiring some variables to be set.
+///
+/// This fails unless every variable listed in `required` is present in the
+/// context with a non-empty value, regardless of the placeholders used by
+/// the template, which helps enforcing deployment contracts.
+///
+/// ```rust
+/// let context = ksubst::context! {"HOST" => "example.com", "TOKEN" => ""};
+///
+/// let output = ksubst::substitute_requiring("${HOST}", &context, &["HOST"]).unwrap();
+/// assert_eq!(output, "example.com");
+/// assert!(ksubst::substitute_requiring("${HOST}", &context, &["TOKEN", "USER"]).is_err());
+/// ```
+pub fn substitute_requiring<T, L>(
+    template: T,
+    variables: &L,
+    required: &[&str],
+) -> Result<String, Error>
+where
+    T: Into<String>,
+    L: Lookup + ?Sized,
+{
+    let missing: Vec<&str> = required
+        .iter()
+        .copied()
+        .filter(|name| variables.get(name).is_none_or(|value| value.is_empty()))
+        .collect();
+    if !missing.is_empty() {
+        return Err(Error(format!(
+            "required variables are missing or empty: {}",
+            missing.join(", ")
+        )));
+    }
+    substitute_with(template, variables)
+}
+
+/// Compute the changes substitution would make to a template.
+///
+/// This returns one [`Change`] per substituted placeholder, with its
+/// position in the template, its text and its replacement, so that callers
+/// can preview a substitution without rendering and diffing strings.
+/// Unresolved placeholders are left unchanged, and thus not included.
+pub fn preview<S, L>(template: S, variables: &L) -> Result<Vec<Change>, Error>
+where
+    S: AsRef<str>,
+    L: Lookup + ?Sized,
+{
+    Substituter::new().preview(template, variables)
+}
 
-    Ok(output)
+/// Check that substituting an already rendered template is a no-op.
+///
+/// This renders `template` twice, and fails if the second pass changes the
+/// output of the first one, e.g. because substituted values combine with
+/// surrounding text into new placeholders. Idempotent renders can safely be
+/// fed back through substitution, which makes this a cheap safety check
+/// for CI pipelines.
+pub fn verify_idempotent<S, L>(template: S, variables: &L) -> Result<(), Error>
+where
+    S: AsRef<str>,
+    L: Lookup + ?Sized,
+{
+    Substituter::new().verify_idempotent(template, variables)
 }
 
 /// Check whether input string contains templated variables.
@@ -82,8 +241,33 @@ pub fn is_templated<S>(input: S) -> bool
 where
     S: AsRef<str>,
 {
-    let re = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)([\.\-][^}]*)?\}").unwrap();
-    re.is_match(input.as_ref())
+    template::parse(input.as_ref())
+        .iter()
+        .any(|segment| matches!(segment, Segment::Placeholder(_)))
+}
+
+/// Check whether input string contains placeholders for variables of a context.
+///
+/// Unlike [`is_templated`], this ignores placeholders whose variable is not
+/// defined in `variables`, e.g. unrelated `${}` syntax meant for another
+/// tool. A set of names can be checked with a context of empty values.
+///
+/// ```rust
+/// let context = ksubst::context! {"hostname" => "example.com"};
+/// assert!(ksubst::is_templated_by("https://${hostname}", &context));
+/// assert!(!ksubst::is_templated_by("echo ${HOME}", &context));
+/// ```
+pub fn is_templated_by<S, L>(input: S, variables: &L) -> bool
+where
+    S: AsRef<str>,
+    L: Lookup + ?Sized,
+{
+    template::parse(input.as_ref())
+        .iter()
+        .any(|segment| match segment {
+            Segment::Placeholder(p) => variables.get(p.name).is_some(),
+            _ => false,
+        })
 }
 
 /// Validate variables for substitution.
@@ -92,7 +276,15 @@ where
 /// substitution deterministic, the following characters are not allowed
 /// within variables names nor values: `$`, `{`, `}`.
 pub fn validate_vars(variables: &HashMap<String, String>) -> Result<(), Error> {
-    for (k, v) in variables {
+    validate_lookup(variables)
+}
+
+/// Validate all variables of a context for substitution.
+pub(crate) fn validate_lookup<L>(variables: &L) -> Result<(), Error>
+where
+    L: Lookup + ?Sized,
+{
+    for (k, v) in variables.vars() {
         validate(k, "key")?;
         validate(v, "value")?;
     }
@@ -100,7 +292,7 @@ pub fn validate_vars(variables: &HashMap<String, String>) -> Result<(), Error> {
 }
 
 /// Check whether `value` contains invalid characters.
-fn validate<S>(value: S, kind: &str) -> Result<(), Error>
+pub(crate) fn validate<S>(value: S, kind: &str) -> Result<(), Error>
 where
     S: AsRef<str>,
 {
@@ -150,6 +342,15 @@ mod tests {
         assert!(!is_templated(ending));
     }
 
+    #[test]
+    fn template_check_by_context() {
+        let context = context! {"VAR" => "", "OTHER" => "value"};
+        assert!(is_templated_by("foo ${VAR} bar", &context));
+        assert!(is_templated_by("${UNRELATED} ${OTHER.}", &context));
+        assert!(!is_templated_by("foo ${UNRELATED} bar", &context));
+        assert!(!is_templated_by("foo ${VAR bar", &context));
+    }
+
     #[test]
     fn basic_empty_vars() {
         let template = "foo ${VAR} bar";
@@ -221,4 +422,69 @@ mod tests {
         let result = substitute(template, &variables).unwrap();
         assert_eq!(result, "value.suffix value-extra");
     }
+
+    #[test]
+    fn shell_lengths() {
+        let template = "n=${#arr[@]} len=${#HOST} args=${#} ${# comment}";
+        let context = context! {"HOST" => "localhost", "arr" => "a"};
+        // Comments are left as-is too, unless enabled
+        let result = substitute(template, &context).unwrap();
+        assert_eq!(result, template);
+        let result = Substituter::new()
+            .builtin(Builtin::Comments)
+            .substitute(template, &context)
+            .unwrap();
+        assert_eq!(result, "n=${#arr[@]} len=${#HOST} args=${#} ");
+
+        let mut context = context;
+        context.extend((0..200).map(|i| (format!("VAR{}", i), String::new())));
+        let result = Matcher::new(&context)
+            .unwrap()
+            .substitute(template)
+            .unwrap();
+        assert_eq!(result, template);
+    }
+
+    #[test]
+    fn required_vars() {
+        let context = context! {"VAR" => "var", "EMPTY" => ""};
+        assert_eq!(
+            substitute_requiring("${VAR} ${EMPTY}", &context, &[]).unwrap(),
+            "var "
+        );
+        assert_eq!(
+            substitute_requiring("plain", &context, &["VAR"]).unwrap(),
+            "plain"
+        );
+        let err =
+            substitute_requiring("${VAR}", &context, &["VAR", "EMPTY", "MISSING"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ksubst error: required variables are missing or empty: EMPTY, MISSING"
+        );
+    }
+
+    #[test]
+    fn context_macro() {
+        let empty = context! {};
+        assert!(empty.is_empty());
+
+        let name = String::from("NAME");
+        let context = context! {name => "app", "REPLICAS" => 3, "DEBUG" => true,};
+        assert_eq!(context.len(), 3);
+        let result = substitute("${NAME}:${REPLICAS}:${DEBUG}", &context).unwrap();
+        assert_eq!(result, "app:3:true");
+    }
+
+    #[test]
+    fn test_substitute_with_typed_values() {
+        let template = "${PORT}:${DEBUG}:${RATIO}";
+        let mut variables: HashMap<&str, Box<dyn std::fmt::Display>> = HashMap::new();
+        variables.insert("PORT", Box::new(8080));
+        variables.insert("DEBUG", Box::new(false));
+        variables.insert("RATIO", Box::new(0.5));
+
+        let result = substitute_with(template, &variables).unwrap();
+        assert_eq!(result, "8080:false:0.5");
+    }
 }
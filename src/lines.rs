@@ -0,0 +1,75 @@
+//! Line-oriented substitution over buffered readers.
+
+use crate::substitute;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+
+/// Iterator over substituted lines of a buffered reader.
+///
+/// This is created by [`substitute_lines`]. Each item is a line of input
+/// (without its trailing newline, as in [`BufRead::lines`]) after variables
+/// substitution. Substitution errors are reported as [`io::ErrorKind::InvalidData`].
+#[derive(Debug)]
+pub struct SubstLines<'a, B> {
+    lines: io::Lines<B>,
+    variables: &'a HashMap<String, String>,
+}
+
+/// Lazily substitute variables in each line of `reader`.
+///
+/// Lines are read and substituted one at a time, so the whole input never
+/// needs to be buffered. Placeholders spanning multiple lines are not
+/// substituted.
+pub fn substitute_lines<B>(reader: B, variables: &HashMap<String, String>) -> SubstLines<'_, B>
+where
+    B: BufRead,
+{
+    SubstLines {
+        lines: reader.lines(),
+        variables,
+    }
+}
+
+impl<B: BufRead> Iterator for SubstLines<'_, B> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(
+            substitute(line, self.variables)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic_lines() {
+        let input = "foo ${VAR}\n${VAR.}bar\r\nbaz";
+        let mut env = HashMap::new();
+        env.insert("VAR".to_string(), "var".to_string());
+
+        let lines: Vec<String> = substitute_lines(input.as_bytes(), &env)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(lines, vec!["foo var", "var.bar", "baz"]);
+    }
+
+    #[test]
+    fn invalid_vars_lines() {
+        let mut env = HashMap::new();
+        env.insert("VAR".to_string(), "${VAR}".to_string());
+
+        let err = substitute_lines("foo".as_bytes(), &env)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
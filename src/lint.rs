@@ -0,0 +1,178 @@
+//! Template linting.
+
+use crate::template::{self, Segment};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Kind of likely mistake detected by [`lint`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Lint {
+    /// Variable reference without braces, e.g. `$VAR`.
+    BareVariable,
+    /// Placeholder without a variable name, i.e. `${}`.
+    EmptyName,
+    /// Placeholder opening `${` without a closing `}`.
+    UnbalancedBraces,
+    /// Variable name differing only in case from another one, e.g. `${Host}` and `${HOST}`.
+    MixedCase,
+    /// Placeholder inside another placeholder, e.g. `${A${B}}`.
+    NestedPlaceholder,
+}
+
+/// Diagnostic produced by [`lint`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Diagnostic {
+    /// Kind of the detected mistake.
+    pub lint: Lint,
+    /// Byte range in the template.
+    pub span: Range<usize>,
+    /// Human-readable description.
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(lint: Lint, span: Range<usize>, message: String) -> Self {
+        Self {
+            lint,
+            span,
+            message,
+        }
+    }
+}
+
+/// Check a template for likely mistakes.
+///
+/// This never fails; templates with diagnostics can still be substituted,
+/// but they probably don't render the way their authors expect.
+/// Diagnostics are sorted by their position in the template.
+pub fn lint(template: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    check_braces(template, &mut diagnostics);
+    check_bare_variables(template, &mut diagnostics);
+    check_mixed_case(template, &mut diagnostics);
+    diagnostics.sort_by_key(|d| (d.span.start, d.span.end));
+    diagnostics
+}
+
+/// Check placeholders delimiters for empty, unbalanced and nested placeholders.
+fn check_braces(template: &str, diagnostics: &mut Vec<Diagnostic>) {
+    let bytes = template.as_bytes();
+    // Start offsets of currently open placeholders, and whether they contain another one
+    let mut open: Vec<(usize, bool)> = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        if bytes[pos..].starts_with(b"${") {
+            if let Some(outer) = open.last_mut() {
+                outer.1 = true;
+            }
+            open.push((pos, false));
+            pos += 2;
+            continue;
+        }
+        if bytes[pos] == b'}' {
+            if let Some((start, nested)) = open.pop() {
+                let span = start..pos + 1;
+                if nested {
+                    let msg = format!(
+                        "placeholder `{}` contains another placeholder",
+                        &template[span.clone()]
+                    );
+                    diagnostics.push(Diagnostic::new(Lint::NestedPlaceholder, span, msg));
+                } else if span.len() == 3 {
+                    let msg = "placeholder has an empty variable name".to_string();
+                    diagnostics.push(Diagnostic::new(Lint::EmptyName, span, msg));
+                }
+            }
+        }
+        pos += 1;
+    }
+
+    for (start, _) in open {
+        let msg = "placeholder is missing a closing `}`".to_string();
+        diagnostics.push(Diagnostic::new(
+            Lint::UnbalancedBraces,
+            start..start + 2,
+            msg,
+        ));
+    }
+}
+
+/// Check for `$VAR` references, which are not substituted.
+fn check_bare_variables(template: &str, diagnostics: &mut Vec<Diagnostic>) {
+    let bytes = template.as_bytes();
+    for (pos, _) in template.match_indices('$') {
+        // `$$` is a common escape sequence
+        if pos > 0 && bytes[pos - 1] == b'$' {
+            continue;
+        }
+        let rest = &bytes[pos + 1..];
+        match rest.first() {
+            Some(b) if b.is_ascii_alphabetic() || *b == b'_' => {}
+            _ => continue,
+        }
+        let len = rest
+            .iter()
+            .position(|b| !(b.is_ascii_alphanumeric() || *b == b'_'))
+            .unwrap_or(rest.len());
+        let name = &template[pos + 1..pos + 1 + len];
+        let msg = format!(
+            "variable `${}` is not braces-delimited, use `${{{}}}`",
+            name, name
+        );
+        diagnostics.push(Diagnostic::new(Lint::BareVariable, pos..pos + 1 + len, msg));
+    }
+}
+
+/// Check for variable names which only differ in case.
+fn check_mixed_case(template: &str, diagnostics: &mut Vec<Diagnostic>) {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    let mut reported = Vec::new();
+
+    for segment in template::parse(template) {
+        if let Segment::Placeholder(p) = segment {
+            let first = *seen.entry(p.name.to_ascii_lowercase()).or_insert(p.name);
+            if first != p.name && !reported.contains(&p.name) {
+                reported.push(p.name);
+                let msg = format!(
+                    "variable `{}` differs only in case from `{}`",
+                    p.name, first
+                );
+                diagnostics.push(Diagnostic::new(Lint::MixedCase, p.span, msg));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lints(template: &str) -> Vec<(Lint, Range<usize>)> {
+        lint(template)
+            .into_iter()
+            .map(|d| (d.lint, d.span))
+            .collect()
+    }
+
+    #[test]
+    fn lint_clean() {
+        assert!(lint("foo ${VAR} ${VAR.} $$HOME {}").is_empty());
+    }
+
+    #[test]
+    fn lint_mistakes() {
+        assert_eq!(
+            lints("$VAR ${}"),
+            vec![(Lint::BareVariable, 0..4), (Lint::EmptyName, 5..8)]
+        );
+        assert_eq!(lints("${VAR"), vec![(Lint::UnbalancedBraces, 0..2)]);
+        assert_eq!(
+            lints("${Host} ${HOST} ${Host} ${HOST}"),
+            vec![(Lint::MixedCase, 8..15)]
+        );
+        assert_eq!(lints("${A${B}}"), vec![(Lint::NestedPlaceholder, 0..8)]);
+    }
+}
@@ -0,0 +1,269 @@
+//! Variables lookup from contexts.
+
+use std::borrow::{Borrow, Cow};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::{BuildHasher, Hash};
+
+/// Iterator over the variables of a context, as `(name, value)` pairs.
+pub type Vars<'a> = Box<dyn Iterator<Item = (Cow<'a, str>, Cow<'a, str>)> + 'a>;
+
+/// Context providing variables values for substitution.
+///
+/// This is implemented for maps of string-like keys and values of any type
+/// implementing [`Display`], e.g. strings, numbers or booleans, which are
+/// converted when looked up. It can also be implemented by adapters exposing
+/// a different view on another context, e.g. [`Prefixed`].
+///
+/// ```rust
+/// let mut context = std::collections::HashMap::new();
+/// context.insert("PORT", 8080);
+/// context.insert("REPLICAS", 3);
+///
+/// let output = ksubst::substitute_with("${PORT}/${REPLICAS}", &context).unwrap();
+/// assert_eq!(output, "8080/3");
+/// ```
+pub trait Lookup {
+    /// Value of the variable `name`, if any.
+    fn get(&self, name: &str) -> Option<Cow<'_, str>>;
+
+    /// All variables of this context.
+    fn vars(&self) -> Vars<'_>;
+
+    /// Name of the source providing the variable `name`, for contexts made of several sources.
+    ///
+    /// This is reported by [`Substituter::render`](crate::Substituter::render)
+    /// for each variable used, and returns `None` by default.
+    fn source(&self, name: &str) -> Option<Cow<'_, str>> {
+        let _ = name;
+        None
+    }
+}
+
+impl<K, V, S> Lookup for HashMap<K, V, S>
+where
+    K: Borrow<str> + Hash + Eq,
+    V: Display,
+    S: BuildHasher,
+{
+    fn get(&self, name: &str) -> Option<Cow<'_, str>> {
+        HashMap::get(self, name).map(|v| Cow::Owned(v.to_string()))
+    }
+
+    fn vars(&self) -> Vars<'_> {
+        Box::new(
+            self.iter()
+                .map(|(k, v)| (Cow::Borrowed(k.borrow()), Cow::Owned(v.to_string()))),
+        )
+    }
+}
+
+impl<L: Lookup + ?Sized> Lookup for &L {
+    fn get(&self, name: &str) -> Option<Cow<'_, str>> {
+        (**self).get(name)
+    }
+
+    fn vars(&self) -> Vars<'_> {
+        (**self).vars()
+    }
+
+    fn source(&self, name: &str) -> Option<Cow<'_, str>> {
+        (**self).source(name)
+    }
+}
+
+/// Context adapter exposing only the variables with a given prefix.
+///
+/// The prefix is stripped from variables names, so that e.g. `MYAPP_PORT`
+/// is available to templates as `${PORT}`, while unrelated variables are
+/// not visible at all.
+///
+/// ```rust
+/// use ksubst::{Prefixed, Substituter};
+///
+/// let mut env = std::collections::HashMap::new();
+/// env.insert("MYAPP_PORT".to_string(), "8080".to_string());
+/// env.insert("PORT".to_string(), "22".to_string());
+///
+/// let context = Prefixed::new(&env, "MYAPP_");
+/// let output = Substituter::new().substitute("${PORT} ${MYAPP_PORT}", &context).unwrap();
+/// assert_eq!(output, "8080 ${MYAPP_PORT}");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Prefixed<L> {
+    inner: L,
+    prefix: String,
+}
+
+impl<L: Lookup> Prefixed<L> {
+    /// Expose variables of `inner` starting with `prefix`.
+    pub fn new<P: Into<String>>(inner: L, prefix: P) -> Self {
+        Self {
+            inner,
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl<L: Lookup> Lookup for Prefixed<L> {
+    fn get(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.inner.get(&format!("{}{}", self.prefix, name))
+    }
+
+    fn vars(&self) -> Vars<'_> {
+        Box::new(self.inner.vars().filter_map(|(k, v)| {
+            let name = k.strip_prefix(self.prefix.as_str())?;
+            if name.is_empty() {
+                return None;
+            }
+            Some((Cow::Owned(name.to_string()), v))
+        }))
+    }
+
+    fn source(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.inner.source(&format!("{}{}", self.prefix, name))
+    }
+}
+
+/// Context made of stacked named layers, where upper layers take precedence.
+///
+/// Each variable is looked up from the topmost layer defining it, whose
+/// name is reported as the variable source, which helps debugging
+/// precedence issues.
+///
+/// ```rust
+/// use ksubst::{Layered, Substituter};
+///
+/// let defaults = ksubst::context! {"HOST" => "localhost", "PORT" => 8080};
+/// let overrides = ksubst::context! {"HOST" => "example.com"};
+///
+/// let mut context = Layered::new();
+/// context.push("defaults", &defaults).push("overrides", &overrides);
+///
+/// let rendered = Substituter::new().render("${HOST}:${PORT}", &context).unwrap();
+/// assert_eq!(rendered.output, "example.com:8080");
+/// assert_eq!(
+///     rendered.report.sources,
+///     vec![
+///         ("HOST".to_string(), "overrides".to_string()),
+///         ("PORT".to_string(), "defaults".to_string()),
+///     ]
+/// );
+/// ```
+#[derive(Default)]
+pub struct Layered<'a> {
+    layers: Vec<(String, Box<dyn Lookup + 'a>)>,
+}
+
+impl<'a> Layered<'a> {
+    /// Create a context without layers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a layer on top of the existing ones.
+    pub fn push<N, L>(&mut self, name: N, layer: L) -> &mut Self
+    where
+        N: Into<String>,
+        L: Lookup + 'a,
+    {
+        self.layers.push((name.into(), Box::new(layer)));
+        self
+    }
+
+    /// Topmost layer defining the variable `name`.
+    fn layer(&self, name: &str) -> Option<&(String, Box<dyn Lookup + 'a>)> {
+        self.layers
+            .iter()
+            .rev()
+            .find(|(_, layer)| layer.get(name).is_some())
+    }
+}
+
+impl std::fmt::Debug for Layered<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.layers.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+impl Lookup for Layered<'_> {
+    fn get(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.layers
+            .iter()
+            .rev()
+            .find_map(|(_, layer)| layer.get(name))
+    }
+
+    fn vars(&self) -> Vars<'_> {
+        let mut vars = HashMap::new();
+        for (_, layer) in &self.layers {
+            vars.extend(layer.vars());
+        }
+        Box::new(vars.into_iter())
+    }
+
+    fn source(&self, name: &str) -> Option<Cow<'_, str>> {
+        let (source, layer) = self.layer(name)?;
+        // Nested layered contexts report their own layers
+        Some(match layer.source(name) {
+            Some(inner) => Cow::Owned(format!("{}/{}", source, inner)),
+            None => Cow::Borrowed(source),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layered() {
+        let base = crate::context! {"A" => "base", "B" => "base"};
+        let env = crate::context! {"B" => "env", "C" => "env"};
+        let mut inner = Layered::new();
+        inner.push("cli", crate::context! {"C" => "cli"});
+
+        let mut context = Layered::new();
+        context
+            .push("base", &base)
+            .push("env", &env)
+            .push("args", inner);
+        assert_eq!(context.get("A").unwrap(), "base");
+        assert_eq!(context.get("B").unwrap(), "env");
+        assert_eq!(context.get("C").unwrap(), "cli");
+        assert_eq!(context.get("D"), None);
+        assert_eq!(context.source("B").unwrap(), "env");
+        assert_eq!(context.source("C").unwrap(), "args/cli");
+        assert_eq!(context.source("D"), None);
+
+        let mut vars: Vec<_> = context.vars().collect();
+        vars.sort();
+        assert_eq!(
+            vars,
+            vec![
+                (Cow::from("A"), Cow::from("base")),
+                (Cow::from("B"), Cow::from("env")),
+                (Cow::from("C"), Cow::from("cli")),
+            ]
+        );
+    }
+
+    #[test]
+    fn prefixed() {
+        let mut env = HashMap::new();
+        env.insert("MYAPP_PORT", "8080");
+        env.insert("MYAPP_", "empty name");
+        env.insert("OTHER", "${invalid}");
+
+        let context = Prefixed::new(&env, "MYAPP_");
+        assert_eq!(context.get("PORT").unwrap(), "8080");
+        assert_eq!(context.get("OTHER"), None);
+        assert_eq!(context.get("MYAPP_PORT"), None);
+
+        let mut vars: Vec<_> = context.vars().collect();
+        vars.sort();
+        assert_eq!(vars, vec![(Cow::from("PORT"), Cow::from("8080"))]);
+    }
+}
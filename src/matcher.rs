@@ -0,0 +1,188 @@
+//! Substitution of many templates with the same context.
+
+#[cfg(feature = "aho-corasick")]
+use crate::filters;
+#[cfg(feature = "aho-corasick")]
+use crate::template::{self, Segment};
+use crate::{substitute, validate_vars, Error};
+#[cfg(feature = "aho-corasick")]
+use aho_corasick::{AhoCorasick, MatchKind};
+use std::collections::HashMap;
+
+/// Minimum count of variables for which an automaton is built.
+#[cfg_attr(not(feature = "aho-corasick"), allow(dead_code))]
+const AUTOMATON_THRESHOLD: usize = 128;
+
+/// Substitution engine reusable across templates rendered with the same context.
+///
+/// Variables are validated once, instead of on every substitution. For
+/// large contexts, e.g. whole process environments, this also builds an
+/// [Aho-Corasick] automaton over `${NAME` prefixes with the `aho-corasick`
+/// feature, which finds the placeholders of defined variables in a single
+/// pass over templates. The output is the same as with [`substitute`].
+///
+/// [Aho-Corasick]: https://en.wikipedia.org/wiki/Aho%E2%80%93Corasick_algorithm
+///
+/// ```rust
+/// let context: std::collections::HashMap<String, String> = (0..1000)
+///     .map(|i| (format!("VAR_{}", i), i.to_string()))
+///     .collect();
+/// let matcher = ksubst::Matcher::new(&context).unwrap();
+///
+/// let output = matcher.substitute("${VAR_1}, ${VAR_999} ${VAR_1000}").unwrap();
+/// assert_eq!(output, "1, 999 ${VAR_1000}");
+/// ```
+#[derive(Debug)]
+pub struct Matcher<'a> {
+    variables: &'a HashMap<String, String>,
+    #[cfg(feature = "aho-corasick")]
+    automaton: Option<AhoCorasick>,
+}
+
+impl<'a> Matcher<'a> {
+    /// Create an engine substituting values from `variables`.
+    ///
+    /// This fails if variables are invalid, see [`validate_vars`].
+    pub fn new(variables: &'a HashMap<String, String>) -> Result<Self, Error> {
+        validate_vars(variables)?;
+        #[cfg(feature = "aho-corasick")]
+        let automaton = if variables.len() >= AUTOMATON_THRESHOLD {
+            let prefixes = variables.keys().map(|name| format!("${{{}", name));
+            let automaton = AhoCorasick::builder()
+                .match_kind(MatchKind::LeftmostLongest)
+                .build(prefixes)
+                .map_err(|e| Error(format!("failed to build variables automaton: {}", e)))?;
+            Some(automaton)
+        } else {
+            None
+        };
+        Ok(Self {
+            variables,
+            #[cfg(feature = "aho-corasick")]
+            automaton,
+        })
+    }
+
+    /// Substitute variables in a template string.
+    ///
+    /// See [`substitute`] for details about substitution rules.
+    pub fn substitute<S: AsRef<str>>(&self, template: S) -> Result<String, Error> {
+        let template = template.as_ref();
+        #[cfg(feature = "aho-corasick")]
+        if let Some(automaton) = &self.automaton {
+            return self.substitute_automaton(template, automaton);
+        }
+        substitute(template, self.variables)
+    }
+
+    /// Substitute variables at the placeholders found by the automaton.
+    #[cfg(feature = "aho-corasick")]
+    fn substitute_automaton(
+        &self,
+        template: &str,
+        automaton: &AhoCorasick,
+    ) -> Result<String, Error> {
+        let mut output = String::with_capacity(template.len());
+        // End of the template copied to the output
+        let mut copied = 0;
+        // End of the template already parsed
+        let mut parsed = 0;
+        for m in automaton.find_iter(template) {
+            let start = m.start();
+            if start < parsed {
+                continue;
+            }
+            // Placeholders are parsed from left to right, so an earlier one
+            // may span over this match
+            if let Some(end) = covering_segment(template, parsed, start) {
+                parsed = end;
+                continue;
+            }
+
+            match template::parse_segment(template, start) {
+                Some(Segment::Placeholder(p)) => {
+                    parsed = p.span.end;
+                    let Some(value) = self.variables.get(p.name) else {
+                        continue;
+                    };
+                    if !p.filters.iter().all(|f| filters::is_builtin(f.name)) {
+                        continue;
+                    }
+                    output.push_str(&template[copied..start]);
+                    copied = p.span.end;
+
+                    let mut value = value.clone();
+                    for filter in &p.filters {
+                        value = filters::apply(filter, &value)?;
+                    }
+                    if !value.is_empty() {
+                        output.push_str(&value);
+                        output.push_str(p.suffix.unwrap_or_default());
+                    }
+                }
+                // Other segments are left as-is
+                Some(segment) => parsed = segment.span().end,
+                None => {}
+            }
+        }
+        output.push_str(&template[copied..]);
+        Ok(output)
+    }
+}
+
+/// End of the segment spanning over `pos`, starting after `from`, if any.
+#[cfg(feature = "aho-corasick")]
+fn covering_segment(template: &str, from: usize, pos: usize) -> Option<usize> {
+    // Segments end at the first closing brace
+    let from = template[from..pos]
+        .rfind('}')
+        .map_or(from, |offset| from + offset + 1);
+    template[from..pos]
+        .match_indices("${")
+        .find_map(|(offset, _)| template::parse_segment(template, from + offset))
+        .map(|segment| segment.span().end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn automaton_matcher() {
+        let mut context: HashMap<String, String> = (0..AUTOMATON_THRESHOLD)
+            .map(|i| (format!("VAR{}", i), format!("value{}", i)))
+            .collect();
+        context.insert("V".to_string(), "v".to_string());
+        context.insert("EMPTY".to_string(), "".to_string());
+        context.insert("NUM".to_string(), "42".to_string());
+        let matcher = Matcher::new(&context).unwrap();
+        #[cfg(feature = "aho-corasick")]
+        assert!(matcher.automaton.is_some());
+
+        for template in [
+            "plain",
+            "${V} ${VAR1} ${VAR12}x ${VAR999} ${VAR1.}${EMPTY-}end",
+            "${NUM|pad(4)} ${NUM|hex}",
+            "${MISSING.${V}} ${V",
+            "${f(a, ${V}} ${V + 1} ${VAR3 - 1}",
+            "$${V}{V}} ${${V}}",
+            "${ ${V}",
+            "${# ${V}} ${#${V}",
+        ] {
+            assert_eq!(
+                matcher.substitute(template).unwrap(),
+                substitute(template, &context).unwrap(),
+                "{}",
+                template
+            );
+        }
+        matcher.substitute("${V|pad(2)}").unwrap_err();
+
+        let small = crate::context! {"V" => "v"};
+        let matcher = Matcher::new(&small).unwrap();
+        #[cfg(feature = "aho-corasick")]
+        assert!(matcher.automaton.is_none());
+        assert_eq!(matcher.substitute("${V}").unwrap(), "v");
+        Matcher::new(&crate::context! {"V" => "$"}).unwrap_err();
+    }
+}
@@ -0,0 +1,158 @@
+//! Variables documentation extracted from template comments.
+
+use crate::template::{self, Segment};
+use serde_json::{json, Map, Value};
+
+/// Documentation of a variable, declared in a template comment.
+///
+/// Variables are documented with `${# @var NAME: description}` comments,
+/// optionally ending with `, default VALUE`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct VariableInfo {
+    /// Variable name.
+    pub name: String,
+    /// Variable description, if any.
+    pub description: Option<String>,
+    /// Documented default value, if any.
+    pub default: Option<String>,
+}
+
+/// Extract variables documentation from template comments.
+///
+/// Comments not starting with `@var` are ignored. Variables documented
+/// several times are returned several times, in template order.
+///
+/// ```rust
+/// let template = "${# @var PORT: listening port, default 8080}\nlisten ${PORT}";
+/// let vars = ksubst::variables_metadata(template);
+///
+/// assert_eq!(vars[0].name, "PORT");
+/// assert_eq!(vars[0].description.as_deref(), Some("listening port"));
+/// assert_eq!(vars[0].default.as_deref(), Some("8080"));
+/// ```
+pub fn variables_metadata(template: &str) -> Vec<VariableInfo> {
+    template::parse(template)
+        .iter()
+        .filter_map(|segment| match segment {
+            Segment::Comment(c) => parse_declaration(c.content),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parse a `@var NAME: description, default VALUE` declaration.
+fn parse_declaration(content: &str) -> Option<VariableInfo> {
+    let declaration = content.strip_prefix("@var")?;
+    if !declaration.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let declaration = declaration.trim_start();
+    let name_end = declaration
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(declaration.len());
+    let (name, rest) = declaration.split_at(name_end);
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let rest = rest.trim_start();
+    let description = match rest.strip_prefix(':') {
+        Some(description) => description.trim(),
+        None if rest.is_empty() => "",
+        None => return None,
+    };
+    let (description, default) = match description.rsplit_once(", default ") {
+        Some((description, default)) => (description.trim(), Some(default.trim())),
+        None => match description.strip_prefix("default ") {
+            Some(default) => ("", Some(default.trim())),
+            None => (description, None),
+        },
+    };
+
+    Some(VariableInfo {
+        name: name.to_string(),
+        description: Some(description)
+            .filter(|d| !d.is_empty())
+            .map(str::to_string),
+        default: default.map(str::to_string),
+    })
+}
+
+/// Generate a JSON Schema describing a context with the given variables.
+///
+/// All variables are strings, and variables without a default value are
+/// required. Later declarations of a variable override earlier ones.
+pub fn json_schema(variables: &[VariableInfo]) -> Value {
+    let mut properties = Map::new();
+    for var in variables {
+        let mut property = Map::new();
+        property.insert("type".to_string(), json!("string"));
+        if let Some(description) = &var.description {
+            property.insert("description".to_string(), json!(description));
+        }
+        if let Some(default) = &var.default {
+            property.insert("default".to_string(), json!(default));
+        }
+        properties.insert(var.name.clone(), Value::Object(property));
+    }
+    let required: Vec<&String> = properties
+        .iter()
+        .filter(|(_, property)| property.get("default").is_none())
+        .map(|(name, _)| name)
+        .collect();
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(name: &str, description: Option<&str>, default: Option<&str>) -> VariableInfo {
+        VariableInfo {
+            name: name.to_string(),
+            description: description.map(str::to_string),
+            default: default.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn metadata_comments() {
+        let template = "${# @var HOST: server host, name or IP}
+${# @var PORT : listening port, default 8080 }
+${# @var DEBUG}${# @var LEVEL: default info}
+${# just a comment}${# @variable X}${# @var 1X}${# @var X Y}
+${HOST}:${PORT}";
+        assert_eq!(
+            variables_metadata(template),
+            vec![
+                info("HOST", Some("server host, name or IP"), None),
+                info("PORT", Some("listening port"), Some("8080")),
+                info("DEBUG", None, None),
+                info("LEVEL", None, Some("info")),
+            ]
+        );
+    }
+
+    #[test]
+    fn schema() {
+        let schema = json_schema(&[
+            info("HOST", Some("server host"), None),
+            info("PORT", None, Some("8080")),
+        ]);
+        assert_eq!(
+            schema["properties"],
+            json!({
+                "HOST": {"type": "string", "description": "server host"},
+                "PORT": {"type": "string", "default": "8080"},
+            })
+        );
+        assert_eq!(schema["required"], json!(["HOST"]));
+    }
+}
@@ -0,0 +1,105 @@
+//! Variables usage statistics across templates.
+
+use crate::template::{self, Segment};
+use std::collections::BTreeMap;
+
+/// Aggregated variables usage across many templates.
+///
+/// Templates are identified by a caller-provided name (e.g. their path),
+/// and each added template records how many times it references each
+/// variable. This is meant for auditing large template repositories,
+/// e.g. to find unused variables or to plan renames.
+///
+/// ```rust
+/// let mut stats = ksubst::UsageStats::new();
+/// stats.add("a.yaml", "${HOST}:${PORT}");
+/// stats.add("b.yaml", "${HOST}/${HOST.}");
+///
+/// assert_eq!(stats.frequency()["HOST"], 3);
+/// assert_eq!(stats.users("PORT"), vec!["a.yaml"]);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UsageStats {
+    matrix: BTreeMap<String, BTreeMap<String, usize>>,
+}
+
+impl UsageStats {
+    /// Create empty statistics.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record variables referenced by a template.
+    ///
+    /// Adding several templates with the same name accumulates their usage.
+    pub fn add<N, S>(&mut self, name: N, template: S) -> &mut Self
+    where
+        N: Into<String>,
+        S: AsRef<str>,
+    {
+        let usage = self.matrix.entry(name.into()).or_default();
+        for segment in template::parse(template.as_ref()) {
+            if let Segment::Placeholder(p) = segment {
+                *usage.entry(p.name.to_string()).or_default() += 1;
+            }
+        }
+        self
+    }
+
+    /// Usage matrix, mapping each template name to its referenced variables and their count.
+    pub fn matrix(&self) -> &BTreeMap<String, BTreeMap<String, usize>> {
+        &self.matrix
+    }
+
+    /// Variables referenced by a template, with their count.
+    pub fn variables(&self, name: &str) -> Option<&BTreeMap<String, usize>> {
+        self.matrix.get(name)
+    }
+
+    /// Number of references to each variable, across all templates.
+    pub fn frequency(&self) -> BTreeMap<&str, usize> {
+        let mut frequency = BTreeMap::new();
+        for usage in self.matrix.values() {
+            for (variable, count) in usage {
+                *frequency.entry(variable.as_str()).or_default() += count;
+            }
+        }
+        frequency
+    }
+
+    /// Names of the templates referencing a variable.
+    pub fn users(&self, variable: &str) -> Vec<&str> {
+        self.matrix
+            .iter()
+            .filter(|(_, usage)| usage.contains_key(variable))
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_stats() {
+        let mut stats = UsageStats::new();
+        stats
+            .add("a", "${HOST} ${PORT} ${HOST-}")
+            .add("b", "${PORT}")
+            .add("a", "${USER}")
+            .add("c", "plain");
+
+        assert_eq!(stats.matrix().len(), 3);
+        let a = stats.variables("a").unwrap();
+        assert_eq!(a["HOST"], 2);
+        assert_eq!(a["USER"], 1);
+        assert!(stats.variables("c").unwrap().is_empty());
+
+        let frequency = stats.frequency();
+        assert_eq!(frequency.len(), 3);
+        assert_eq!(frequency["PORT"], 2);
+        assert_eq!(stats.users("PORT"), vec!["a", "b"]);
+        assert!(stats.users("MISSING").is_empty());
+    }
+}
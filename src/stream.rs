@@ -0,0 +1,105 @@
+//! Incremental substitution over chunked input.
+
+use crate::{substitute, Error};
+use std::collections::HashMap;
+
+/// Substitution state carried across input chunks.
+///
+/// Input is buffered only as long as it may still be part of a placeholder,
+/// i.e. from the first `${` not followed by a closing `}` (or from a
+/// trailing `$`). Everything before that point is substituted and emitted
+/// right away, so the result is the same as substituting the whole input
+/// at once.
+#[derive(Debug, Default)]
+pub(crate) struct Streamer {
+    pending: Vec<u8>,
+}
+
+impl Streamer {
+    /// Feed a chunk of input, returning the output which is ready to be emitted.
+    pub(crate) fn feed(
+        &mut self,
+        chunk: &[u8],
+        variables: &HashMap<String, String>,
+    ) -> Result<String, Error> {
+        self.pending.extend_from_slice(chunk);
+
+        let mut cut = split_point(&self.pending);
+        let ready = match std::str::from_utf8(&self.pending[..cut]) {
+            Ok(ready) => ready,
+            // A multi-byte character may be split across chunks
+            Err(e) if e.error_len().is_none() => {
+                cut = e.valid_up_to();
+                std::str::from_utf8(&self.pending[..cut]).unwrap()
+            }
+            Err(e) => return Err(Error(format!("invalid UTF-8 input: {}", e))),
+        };
+        let output = substitute(ready, variables)?;
+
+        self.pending.drain(..cut);
+        Ok(output)
+    }
+
+    /// Signal the end of input, returning the remaining output.
+    pub(crate) fn finish(&mut self, variables: &HashMap<String, String>) -> Result<String, Error> {
+        let rest = std::mem::take(&mut self.pending);
+        let rest =
+            String::from_utf8(rest).map_err(|e| Error(format!("invalid UTF-8 input: {}", e)))?;
+        substitute(rest, variables)
+    }
+}
+
+/// Find the position up to which buffered input can be safely substituted.
+fn split_point(buf: &[u8]) -> usize {
+    let start = buf
+        .iter()
+        .rposition(|&b| b == b'}')
+        .map_or(0, |pos| pos + 1);
+    let tail = &buf[start..];
+
+    if let Some(pos) = tail.windows(2).position(|w| w == b"${") {
+        return start + pos;
+    }
+    if tail.last() == Some(&b'$') {
+        return buf.len() - 1;
+    }
+    buf.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_chunked(input: &str, size: usize, env: &HashMap<String, String>) -> String {
+        let mut streamer = Streamer::default();
+        let mut output = String::new();
+        for chunk in input.as_bytes().chunks(size) {
+            output.push_str(&streamer.feed(chunk, env).unwrap());
+        }
+        output.push_str(&streamer.finish(env).unwrap());
+        output
+    }
+
+    #[test]
+    fn split_placeholders() {
+        let template = "foo ${VAR} ${VAR.}bär $ ${ ${VAR-}ünïcode ${MISSING} $";
+        let mut env = HashMap::new();
+        env.insert("VAR".to_string(), "välue".to_string());
+
+        let expected = substitute(template, &env).unwrap();
+        for size in 1..=template.len() {
+            assert_eq!(run_chunked(template, size, &env), expected, "size {}", size);
+        }
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let env = HashMap::new();
+        let mut streamer = Streamer::default();
+        streamer.feed(b"foo \xff bar", &env).unwrap_err();
+
+        let mut streamer = Streamer::default();
+        assert_eq!(streamer.feed(b"foo \xc3", &env).unwrap(), "foo ");
+        streamer.finish(&env).unwrap_err();
+    }
+}
@@ -0,0 +1,168 @@
+//! Substitution inside parsed structured documents.
+
+use crate::{substitute_with, Error, Lookup};
+use serde_json::{Map, Value};
+
+/// Substitute variables in the string values of a JSON document.
+///
+/// Numbers, booleans, nulls and the structure of the document are never
+/// changed, and object keys are only substituted if `keys` is set. As
+/// values are substituted after parsing, the document stays valid JSON
+/// whatever the values contain, once serialized again.
+///
+/// ```rust
+/// let mut document = serde_json::json!({"${KEY}": "${HOST}", "port": 80});
+/// let context = ksubst::context! {"HOST" => "\"quoted\"\nhost", "KEY" => "host"};
+/// ksubst::substitute_json(&mut document, &context, false).unwrap();
+///
+/// assert_eq!(document["${KEY}"], "\"quoted\"\nhost");
+/// assert_eq!(document.to_string(), r#"{"${KEY}":"\"quoted\"\nhost","port":80}"#);
+/// ```
+pub fn substitute_json<L>(document: &mut Value, variables: &L, keys: bool) -> Result<(), Error>
+where
+    L: Lookup + ?Sized,
+{
+    match document {
+        Value::String(s) => *s = substitute_with(s.as_str(), variables)?,
+        Value::Array(items) => {
+            for item in items {
+                substitute_json(item, variables, keys)?;
+            }
+        }
+        Value::Object(map) if keys => {
+            let mut substituted = Map::new();
+            for (key, mut value) in std::mem::take(map) {
+                substitute_json(&mut value, variables, keys)?;
+                let key = substitute_with(key, variables)?;
+                if substituted.contains_key(&key) {
+                    return Err(Error(format!("duplicate key '{}' after substitution", key)));
+                }
+                substituted.insert(key, value);
+            }
+            *map = substituted;
+        }
+        Value::Object(map) => {
+            for value in map.values_mut() {
+                substitute_json(value, variables, keys)?;
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+/// Substitute variables in the string scalars of a YAML document.
+///
+/// Like [`substitute_json`], other scalars and the structure of the
+/// document are never changed, and mapping keys are only substituted if
+/// `keys` is set. Substituted values are quoted as needed when serialized,
+/// so values containing `: ` or ` #` can't change the document. This
+/// requires the `yaml` feature.
+///
+/// ```rust
+/// let mut document: serde_yaml::Value = serde_yaml::from_str("url: ${URL}\nport: 80\n").unwrap();
+/// let context = ksubst::context! {"URL" => "http://host # comment"};
+/// ksubst::substitute_yaml(&mut document, &context, false).unwrap();
+///
+/// let output = serde_yaml::to_string(&document).unwrap();
+/// assert_eq!(output, "url: 'http://host # comment'\nport: 80\n");
+/// ```
+#[cfg(feature = "yaml")]
+pub fn substitute_yaml<L>(
+    document: &mut serde_yaml::Value,
+    variables: &L,
+    keys: bool,
+) -> Result<(), Error>
+where
+    L: Lookup + ?Sized,
+{
+    use serde_yaml::{Mapping, Value};
+
+    match document {
+        Value::String(s) => *s = substitute_with(s.as_str(), variables)?,
+        Value::Sequence(items) => {
+            for item in items {
+                substitute_yaml(item, variables, keys)?;
+            }
+        }
+        Value::Mapping(map) if keys => {
+            let mut substituted = Mapping::new();
+            for (mut key, mut value) in std::mem::take(map) {
+                substitute_yaml(&mut key, variables, keys)?;
+                substitute_yaml(&mut value, variables, keys)?;
+                if substituted.contains_key(&key) {
+                    let key = serde_yaml::to_string(&key).unwrap_or_default();
+                    return Err(Error(format!(
+                        "duplicate key '{}' after substitution",
+                        key.trim_end()
+                    )));
+                }
+                substituted.insert(key, value);
+            }
+            *map = substituted;
+        }
+        Value::Mapping(map) => {
+            for (_, value) in map.iter_mut() {
+                substitute_yaml(value, variables, keys)?;
+            }
+        }
+        Value::Tagged(tagged) => substitute_yaml(&mut tagged.value, variables, keys)?,
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context;
+    use serde_json::json;
+
+    #[test]
+    fn json_strings() {
+        let context = context! {"A" => "a\"", "K" => "k", "EMPTY" => ""};
+        let mut document = json!({
+            "${K}": ["${A}", 1, true, null, {"nested": "${A} ${MISSING}"}],
+            "z": "${EMPTY}",
+        });
+        substitute_json(&mut document, &context, false).unwrap();
+        assert_eq!(
+            document,
+            json!({
+                "${K}": ["a\"", 1, true, null, {"nested": "a\" ${MISSING}"}],
+                "z": "",
+            })
+        );
+
+        substitute_json(&mut document, &context, true).unwrap();
+        let keys: Vec<&String> = document.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["k", "z"]);
+
+        let mut duplicate = json!({"${K}": 1, "k": 2});
+        let err = substitute_json(&mut duplicate, &context, true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ksubst error: duplicate key 'k' after substitution"
+        );
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_scalars() {
+        let context = context! {"A" => "a: b # c", "K" => "k", "N" => "1"};
+        let template = "${K}:\n- ${A}\n- 1\n- '${N}'\n- !tag '${N}'\nz:\n  ${K}: ~\n";
+        let mut document: serde_yaml::Value = serde_yaml::from_str(template).unwrap();
+        substitute_yaml(&mut document, &context, false).unwrap();
+        assert_eq!(
+            serde_yaml::to_string(&document).unwrap(),
+            "${K}:\n- 'a: b # c'\n- 1\n- '1'\n- !tag '1'\nz:\n  ${K}: null\n"
+        );
+
+        substitute_yaml(&mut document, &context, true).unwrap();
+        assert_eq!(document["k"][1], serde_yaml::Value::from(1));
+        assert!(document["z"].get("k").is_some());
+
+        let mut duplicate: serde_yaml::Value = serde_yaml::from_str("${K}: 1\nk: 2").unwrap();
+        substitute_yaml(&mut duplicate, &context, true).unwrap_err();
+    }
+}
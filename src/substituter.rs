@@ -0,0 +1,1105 @@
+//! Configurable substitution engine.
+
+use crate::builtins::{self, CallState};
+use crate::template::{self, Segment};
+use crate::{
+    cond, expr, filters, is_templated, validate_lookup, Builtin, Constraint, Error, Lookup,
+};
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Policy for rendered outputs which still contain placeholders.
+///
+/// Placeholders may be left in the output because their variables are
+/// missing from the context, or because substituted values introduced
+/// new ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputCheck {
+    /// Don't check the rendered output.
+    #[default]
+    Ignore,
+    /// Record a warning in the [`Report`].
+    Warn,
+    /// Fail the substitution.
+    Deny,
+}
+
+/// Configurable substitution engine.
+///
+/// Options are set through chainable methods, and the same engine can then
+/// be used to render any number of templates.
+///
+/// ```rust
+/// use ksubst::{OutputCheck, Substituter};
+///
+/// let mut context = std::collections::HashMap::new();
+/// context.insert("hostname".to_string(), "example.com".to_string());
+///
+/// let mut substituter = Substituter::new();
+/// substituter.output_check(OutputCheck::Deny);
+/// assert!(substituter.substitute("https://${hostname}", &context).is_ok());
+/// assert!(substituter.substitute("https://${hostname}/${endpoint}", &context).is_err());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Substituter {
+    output_check: OutputCheck,
+    aliases: HashMap<String, String>,
+    normalizer: Option<Normalizer>,
+    builtins: HashSet<Builtin>,
+    seed: Option<u64>,
+    file_root: Option<PathBuf>,
+    pattern: Option<Regex>,
+    filters: HashMap<String, CustomFilter>,
+    constraints: Vec<(String, Constraint)>,
+}
+
+/// Key transformation applied to placeholder names.
+#[derive(Clone)]
+struct Normalizer(Arc<dyn Fn(&str) -> String + Send + Sync>);
+
+impl fmt::Debug for Normalizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Normalizer")
+    }
+}
+
+/// User-defined filter applied to placeholder values.
+#[derive(Clone)]
+struct CustomFilter(Arc<dyn Fn(&str) -> String + Send + Sync>);
+
+impl fmt::Debug for CustomFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomFilter")
+    }
+}
+
+/// Line and column (starting from 1) of a byte offset in a template.
+#[cfg(feature = "log")]
+fn position(template: &str, offset: usize) -> (usize, usize) {
+    let before = &template[..offset];
+    let line_start = before.rfind('\n').map_or(0, |pos| pos + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Record the source of a context key in the report, if tracked.
+fn record_source<L: Lookup + ?Sized>(key: &str, variables: &L, report: &mut Report) {
+    if let Some(source) = variables.source(key) {
+        let source = (key.to_string(), source.into_owned());
+        if !report.sources.contains(&source) {
+            report.sources.push(source);
+        }
+    }
+}
+
+/// Conditional block open while rendering.
+struct Block<'a> {
+    /// Block start text.
+    text: &'a str,
+    /// Whether the enclosing block is rendered.
+    parent: bool,
+    /// Whether the condition is true.
+    matched: bool,
+    /// Whether the current branch is rendered.
+    rendered: bool,
+    /// Whether the `${else}` branch was reached.
+    alternative: bool,
+}
+
+/// Whether a placeholder is an `${else}` or `${end}` block delimiter.
+fn is_block_delimiter(placeholder: &template::Placeholder<'_>) -> bool {
+    matches!(placeholder.name, "else" | "end")
+        && placeholder.suffix.is_none()
+        && placeholder.filters.is_empty()
+}
+
+/// Rendered template, along with a report about its substitution.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Rendered {
+    /// Rendered output.
+    pub output: String,
+    /// Substitution report.
+    pub report: Report,
+}
+
+/// Report about a template substitution.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Report {
+    /// Placeholders substituted with their variable value.
+    pub resolved: Vec<Resolved>,
+    /// Placeholders left as-is because their variable is missing.
+    pub unresolved: Vec<Unresolved>,
+    /// Non-fatal issues detected while rendering.
+    pub warnings: Vec<String>,
+    /// Aliases used to resolve placeholders, as `(placeholder name, context key)` pairs.
+    pub aliases: Vec<(String, String)>,
+    /// Sources of the values used, as `(context key, source name)` pairs, for contexts tracking them.
+    ///
+    /// See [`Lookup::source`].
+    pub sources: Vec<(String, String)>,
+}
+
+/// Placeholder substituted in a rendered template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Resolved {
+    /// Variable name.
+    pub name: String,
+    /// Byte range of the placeholder in the template.
+    pub span: Range<usize>,
+    /// Byte range of the replacement in the rendered output.
+    pub output_span: Range<usize>,
+}
+
+/// Change between a template and its rendered output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Change {
+    /// Byte range of the placeholder in the template.
+    pub span: Range<usize>,
+    /// Placeholder text in the template.
+    pub old: String,
+    /// Replacement text in the rendered output.
+    pub new: String,
+}
+
+/// Placeholder left unresolved in a rendered template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Unresolved {
+    /// Variable name.
+    pub name: String,
+    /// Byte range of the placeholder in the template.
+    pub span: Range<usize>,
+}
+
+impl Substituter {
+    /// Create an engine with default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the policy for rendered outputs which still contain placeholders.
+    pub fn output_check(&mut self, check: OutputCheck) -> &mut Self {
+        self.output_check = check;
+        self
+    }
+
+    /// Resolve placeholders named `name` from the context key `key`.
+    ///
+    /// Aliases only apply when the context has no variable named `name`,
+    /// which eases migrations to new variable names: old templates keep
+    /// working against renamed context keys. Aliases used during
+    /// substitution are listed in the [`Report`].
+    pub fn alias<N, K>(&mut self, name: N, key: K) -> &mut Self
+    where
+        N: Into<String>,
+        K: Into<String>,
+    {
+        self.aliases.insert(name.into(), key.into());
+        self
+    }
+
+    /// Enable a built-in function.
+    ///
+    /// Builtins are opt-in, and calls to disabled ones are left as-is.
+    ///
+    /// ```rust
+    /// use ksubst::{Builtin, Substituter};
+    ///
+    /// let context = std::collections::HashMap::<String, String>::new();
+    /// let output = Substituter::new()
+    ///     .builtin(Builtin::Math)
+    ///     .substitute("port ${math(8000 + 80)}", &context)
+    ///     .unwrap();
+    /// assert_eq!(output, "port 8080");
+    /// ```
+    pub fn builtin(&mut self, builtin: Builtin) -> &mut Self {
+        self.builtins.insert(builtin);
+        self
+    }
+
+    /// Seed random values generated by builtins.
+    ///
+    /// Seeded renders are reproducible: the same template always produces
+    /// the same values. They are not suitable for secrets and nonces.
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Set the directory files can be included from with the `file()` builtin.
+    ///
+    /// Included paths are resolved relative to this root, and cannot escape
+    /// it, even through symbolic links.
+    pub fn file_root<P: Into<PathBuf>>(&mut self, root: P) -> &mut Self {
+        self.file_root = Some(root.into());
+        self
+    }
+
+    /// Transform placeholder names before looking them up.
+    ///
+    /// This lets templates and contexts with differing naming conventions
+    /// interoperate. The transformed name is also used for aliases lookup,
+    /// while reports keep the name found in the template.
+    ///
+    /// ```rust
+    /// let mut context = std::collections::HashMap::new();
+    /// context.insert("HOSTNAME".to_string(), "example.com".to_string());
+    ///
+    /// let output = ksubst::Substituter::new()
+    ///     .normalize_keys(|name| name.to_ascii_uppercase())
+    ///     .substitute("https://${hostname}", &context)
+    ///     .unwrap();
+    /// assert_eq!(output, "https://example.com");
+    /// ```
+    pub fn normalize_keys<F>(&mut self, normalizer: F) -> &mut Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.normalizer = Some(Normalizer(Arc::new(normalizer)));
+        self
+    }
+
+    /// Register a filter, available to placeholders as `${VAR|name}`.
+    ///
+    /// Registered filters take no arguments, and take precedence over
+    /// builtin filters with the same name.
+    ///
+    /// ```rust
+    /// let context = ksubst::context! {"secret" => "hello"};
+    ///
+    /// let output = ksubst::Substituter::new()
+    ///     .register_filter("reverse", |s| s.chars().rev().collect())
+    ///     .substitute("${secret|reverse}", &context)
+    ///     .unwrap();
+    /// assert_eq!(output, "olleh");
+    /// ```
+    pub fn register_filter<N, F>(&mut self, name: N, filter: F) -> &mut Self
+    where
+        N: Into<String>,
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.filters
+            .insert(name.into(), CustomFilter(Arc::new(filter)));
+        self
+    }
+
+    /// Attach a constraint to the variable `name`.
+    ///
+    /// Renders fail if the context defines the variable with a value not
+    /// satisfying its constraints, whether or not the template uses it.
+    pub fn constraint<N: Into<String>>(&mut self, name: N, constraint: Constraint) -> &mut Self {
+        self.constraints.push((name.into(), constraint));
+        self
+    }
+
+    /// Match placeholders with a custom regular expression.
+    ///
+    /// This replaces the default `${VAR}` grammar, for templates using
+    /// another placeholder format. Each match is a placeholder, and its
+    /// `name` capture group is the variable name. Suffixes, filters, function
+    /// calls and expressions are not supported with custom patterns.
+    ///
+    /// This fails if the pattern is invalid, or has no `name` group.
+    ///
+    /// ```rust
+    /// let context = ksubst::context! {"hostname" => "example.com"};
+    ///
+    /// let output = ksubst::Substituter::new()
+    ///     .pattern(r"@(?<name>[a-z]+)@")
+    ///     .unwrap()
+    ///     .substitute("https://@hostname@/${path}", &context)
+    ///     .unwrap();
+    /// assert_eq!(output, "https://example.com/${path}");
+    /// ```
+    pub fn pattern(&mut self, pattern: &str) -> Result<&mut Self, Error> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| Error(format!("invalid placeholder pattern: {}", e)))?;
+        if !regex.capture_names().any(|n| n == Some("name")) {
+            return Err(Error(format!(
+                "placeholder pattern '{}' has no 'name' capture group",
+                pattern
+            )));
+        }
+        self.pattern = Some(regex);
+        Ok(self)
+    }
+
+    /// Substitute variables in a template string.
+    ///
+    /// See [`substitute`](crate::substitute) for details about substitution rules.
+    pub fn substitute<T, L>(&self, template: T, variables: &L) -> Result<String, Error>
+    where
+        T: Into<String>,
+        L: Lookup + ?Sized,
+    {
+        self.render(template, variables)
+            .map(|rendered| rendered.output)
+    }
+
+    /// Substitute variables in a template string, reporting about the substitution.
+    pub fn render<T, L>(&self, template: T, variables: &L) -> Result<Rendered, Error>
+    where
+        T: Into<String>,
+        L: Lookup + ?Sized,
+    {
+        let template = template.into();
+        validate_lookup(variables)?;
+        for (name, constraint) in &self.constraints {
+            if let Some(value) = variables.get(name) {
+                constraint.check(name, &value)?;
+            }
+        }
+
+        let mut state = CallState::new(self.seed);
+        let (output, mut report) = self.render_segments(&template, variables, &mut state)?;
+
+        let placeholders = match (self.output_check, &self.pattern) {
+            (OutputCheck::Ignore, _) => Vec::new(),
+            (_, Some(pattern)) => pattern.find_iter(&output).map(|m| m.as_str()).collect(),
+            (_, None) if is_templated(&output) => template::parse(&output)
+                .into_iter()
+                .filter(|s| matches!(s, Segment::Placeholder(_)))
+                .map(|s| s.as_str())
+                .collect(),
+            (_, None) => Vec::new(),
+        };
+        if !placeholders.is_empty() {
+            let msg = format!(
+                "rendered output still contains placeholders: {}",
+                placeholders.join(", ")
+            );
+            if self.output_check == OutputCheck::Deny {
+                return Err(Error(msg));
+            }
+            report.warnings.push(msg);
+        }
+
+        #[cfg(feature = "log")]
+        for unresolved in &report.unresolved {
+            let (line, column) = position(&template, unresolved.span.start);
+            log::warn!(
+                target: "ksubst",
+                "unresolved placeholder for variable '{}' at line {}, column {}",
+                unresolved.name,
+                line,
+                column
+            );
+        }
+
+        Ok(Rendered { output, report })
+    }
+
+    /// Render template segments, without output checks.
+    fn render_segments<L>(
+        &self,
+        template: &str,
+        variables: &L,
+        state: &mut CallState,
+    ) -> Result<(String, Report), Error>
+    where
+        L: Lookup + ?Sized,
+    {
+        if let Some(pattern) = &self.pattern {
+            return Ok(self.render_pattern(pattern, template, variables));
+        }
+
+        let mut output = String::with_capacity(template.len());
+        let mut report = Report::default();
+        let conditionals = self.builtins.contains(&Builtin::If);
+        // Open conditional blocks, innermost last
+        let mut blocks: Vec<Block<'_>> = Vec::new();
+        for segment in template::parse(template) {
+            let rendered = blocks.last().is_none_or(|block| block.rendered);
+            match segment {
+                Segment::Conditional(c) if conditionals => {
+                    let matched = rendered
+                        && cond::evaluate(c.condition, |name| {
+                            self.lookup(name, variables, &mut report)
+                        })?;
+                    blocks.push(Block {
+                        text: c.text,
+                        parent: rendered,
+                        matched,
+                        rendered: matched,
+                        alternative: false,
+                    });
+                }
+                Segment::Placeholder(p) if is_block_delimiter(&p) && !blocks.is_empty() => {
+                    let block = blocks.last_mut().unwrap();
+                    match p.name {
+                        "end" => drop(blocks.pop()),
+                        _ if block.alternative => {
+                            return Err(Error(format!(
+                                "duplicate ${{else}} in conditional block '{}'",
+                                block.text
+                            )))
+                        }
+                        _ => {
+                            block.alternative = true;
+                            block.rendered = block.parent && !block.matched;
+                        }
+                    }
+                }
+                _ if !rendered => {}
+                // Placeholders with unknown filters belong to other syntaxes
+                Segment::Placeholder(p) if !p.filters.iter().all(|f| self.has_filter(f.name)) => {
+                    output.push_str(p.text);
+                }
+                Segment::Placeholder(p) => match self.lookup(p.name, variables, &mut report) {
+                    Some(mut value) => {
+                        for filter in &p.filters {
+                            value = Cow::Owned(self.apply_filter(filter, &value)?);
+                        }
+                        let start = output.len();
+                        if !value.is_empty() {
+                            output.push_str(&value);
+                            output.push_str(p.suffix.unwrap_or_default());
+                        }
+                        report.resolved.push(Resolved {
+                            name: p.name.to_string(),
+                            span: p.span,
+                            output_span: start..output.len(),
+                        });
+                    }
+                    // If variable is not found, leave the placeholder as is
+                    None => {
+                        output.push_str(p.text);
+                        report.unresolved.push(Unresolved {
+                            name: p.name.to_string(),
+                            span: p.span,
+                        });
+                    }
+                },
+                Segment::Call(c) => {
+                    let value = match Builtin::from_name(c.name) {
+                        Some(builtin) if !self.builtins.contains(&builtin) => None,
+                        Some(Builtin::If | Builtin::Comments) => None,
+                        Some(Builtin::File) => Some(self.include_file(&c.args, variables, state)?),
+                        Some(Builtin::Math) => match &c.args[..] {
+                            [expression] => {
+                                self.evaluate(expression, c.span, variables, &mut report)?
+                            }
+                            _ => {
+                                return Err(Error(
+                                    "math(): expected an expression argument".to_string(),
+                                ))
+                            }
+                        },
+                        Some(builtin) => builtin.call(&c.args, state)?,
+                        None => None,
+                    };
+                    output.push_str(value.as_deref().unwrap_or(c.text));
+                }
+                Segment::Expression(e) if self.builtins.contains(&Builtin::Math) => {
+                    let value = self.evaluate(e.expression, e.span, variables, &mut report)?;
+                    output.push_str(value.as_deref().unwrap_or(e.text));
+                }
+                Segment::Comment(_) if self.builtins.contains(&Builtin::Comments) => {}
+                segment => output.push_str(segment.as_str()),
+            }
+        }
+        if let Some(block) = blocks.first() {
+            return Err(Error(format!(
+                "unclosed conditional block '{}'",
+                block.text
+            )));
+        }
+        Ok((output, report))
+    }
+
+    /// Render a template with placeholders matching a custom pattern.
+    fn render_pattern<L>(&self, pattern: &Regex, template: &str, variables: &L) -> (String, Report)
+    where
+        L: Lookup + ?Sized,
+    {
+        let mut output = String::with_capacity(template.len());
+        let mut report = Report::default();
+        let mut last = 0;
+        for captures in pattern.captures_iter(template) {
+            let (Some(placeholder), Some(name)) = (captures.get(0), captures.name("name")) else {
+                continue;
+            };
+            output.push_str(&template[last..placeholder.start()]);
+            last = placeholder.end();
+
+            let span = placeholder.range();
+            match self.lookup(name.as_str(), variables, &mut report) {
+                Some(value) => {
+                    let start = output.len();
+                    output.push_str(&value);
+                    report.resolved.push(Resolved {
+                        name: name.as_str().to_string(),
+                        span,
+                        output_span: start..output.len(),
+                    });
+                }
+                None => {
+                    output.push_str(placeholder.as_str());
+                    report.unresolved.push(Unresolved {
+                        name: name.as_str().to_string(),
+                        span,
+                    });
+                }
+            }
+        }
+        output.push_str(&template[last..]);
+        (output, report)
+    }
+
+    /// Evaluate a `file()` call, rendering the included file if requested.
+    fn include_file<L>(
+        &self,
+        args: &[Cow<'_, str>],
+        variables: &L,
+        state: &mut CallState,
+    ) -> Result<String, Error>
+    where
+        L: Lookup + ?Sized,
+    {
+        let (path, render) = builtins::include_args(args)?;
+        let root = self
+            .file_root
+            .as_deref()
+            .ok_or_else(|| Error("file(): no root directory configured".to_string()))?;
+        let path = builtins::sandboxed_path(root, path)?;
+
+        if state.includes.contains(&path) {
+            let cycle: Vec<String> = state
+                .includes
+                .iter()
+                .chain(std::iter::once(&path))
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(Error(format!(
+                "file(): include cycle: {}",
+                cycle.join(" -> ")
+            )));
+        }
+
+        let content = std::fs::read_to_string(&path).map_err(|e| {
+            Error(format!(
+                "file(): failed to read '{}': {}",
+                path.display(),
+                e
+            ))
+        })?;
+        if !render {
+            return Ok(content);
+        }
+
+        state.includes.push(path);
+        let result = self.render_segments(&content, variables, state);
+        state.includes.pop();
+        result.map(|(output, _)| output)
+    }
+
+    /// Whether a filter is registered or builtin.
+    fn has_filter(&self, name: &str) -> bool {
+        self.filters.contains_key(name) || filters::is_builtin(name)
+    }
+
+    /// Apply a registered or builtin filter to a value.
+    fn apply_filter(&self, filter: &template::Filter<'_>, value: &str) -> Result<String, Error> {
+        match self.filters.get(filter.name) {
+            Some(_) if !filter.args.is_empty() => {
+                Err(Error(format!("{}(): too many arguments", filter.name)))
+            }
+            Some(custom) => Ok((custom.0)(value)),
+            None => filters::apply(filter, value),
+        }
+    }
+
+    /// Evaluate an arithmetic expression, returning `None` if a variable is missing.
+    fn evaluate<L>(
+        &self,
+        expression: &str,
+        span: Range<usize>,
+        variables: &L,
+        report: &mut Report,
+    ) -> Result<Option<String>, Error>
+    where
+        L: Lookup + ?Sized,
+    {
+        match expr::evaluate(expression, |name| self.lookup(name, variables, report))? {
+            Ok(value) => Ok(Some(value.to_string())),
+            Err(name) => {
+                report.unresolved.push(Unresolved { name, span });
+                Ok(None)
+            }
+        }
+    }
+
+    /// Look up the value of a variable, following aliases.
+    fn lookup<'v, L>(
+        &self,
+        name: &str,
+        variables: &'v L,
+        report: &mut Report,
+    ) -> Option<Cow<'v, str>>
+    where
+        L: Lookup + ?Sized,
+    {
+        let normalized;
+        let name = match &self.normalizer {
+            Some(normalizer) => {
+                normalized = (normalizer.0)(name);
+                normalized.as_str()
+            }
+            None => name,
+        };
+
+        if let Some(value) = variables.get(name) {
+            record_source(name, variables, report);
+            return Some(value);
+        }
+
+        let key = self.aliases.get(name)?;
+        let value = variables.get(key)?;
+        let alias = (name.to_string(), key.clone());
+        if !report.aliases.contains(&alias) {
+            report.aliases.push(alias);
+        }
+        record_source(key, variables, report);
+        Some(value)
+    }
+
+    /// Compute the changes substitution would make to a template.
+    ///
+    /// See [`preview`](crate::preview) for details.
+    pub fn preview<S, L>(&self, template: S, variables: &L) -> Result<Vec<Change>, Error>
+    where
+        S: AsRef<str>,
+        L: Lookup + ?Sized,
+    {
+        let template = template.as_ref();
+        let rendered = self.render(template, variables)?;
+        let changes = rendered
+            .report
+            .resolved
+            .into_iter()
+            .map(|r| Change {
+                old: template[r.span.clone()].to_string(),
+                new: rendered.output[r.output_span].to_string(),
+                span: r.span,
+            })
+            .collect();
+        Ok(changes)
+    }
+
+    /// Check that substituting an already rendered template is a no-op.
+    ///
+    /// See [`verify_idempotent`](crate::verify_idempotent) for details.
+    pub fn verify_idempotent<S, L>(&self, template: S, variables: &L) -> Result<(), Error>
+    where
+        S: AsRef<str>,
+        L: Lookup + ?Sized,
+    {
+        let first = self.substitute(template.as_ref(), variables)?;
+        let second = self.substitute(first.as_str(), variables)?;
+        if first == second {
+            return Ok(());
+        }
+
+        let pos = first
+            .char_indices()
+            .zip(second.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(first.len().min(second.len()), |((pos, _), _)| pos);
+        Err(Error(format!(
+            "substitution is not idempotent, second pass differs at byte {}",
+            pos
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_report() {
+        let template = "${VAR} ${MISSING.}";
+        let mut env = HashMap::new();
+        env.insert("VAR".to_string(), "var".to_string());
+
+        let rendered = Substituter::new().render(template, &env).unwrap();
+        assert_eq!(rendered.output, "var ${MISSING.}");
+        assert_eq!(
+            rendered.report.resolved,
+            vec![Resolved {
+                name: "VAR".to_string(),
+                span: 0..6,
+                output_span: 0..3
+            }]
+        );
+        assert_eq!(
+            rendered.report.unresolved,
+            vec![Unresolved {
+                name: "MISSING".to_string(),
+                span: 7..18
+            }]
+        );
+        assert!(rendered.report.warnings.is_empty());
+    }
+
+    #[test]
+    fn output_check() {
+        let template = "${VAR} ${MISSING}";
+        let mut env = HashMap::new();
+        env.insert("VAR".to_string(), "var".to_string());
+
+        let rendered = Substituter::new()
+            .output_check(OutputCheck::Warn)
+            .render(template, &env)
+            .unwrap();
+        assert_eq!(rendered.output, "var ${MISSING}");
+        assert_eq!(rendered.report.warnings.len(), 1);
+
+        Substituter::new()
+            .output_check(OutputCheck::Deny)
+            .render(template, &env)
+            .unwrap_err();
+
+        env.insert("MISSING".to_string(), "".to_string());
+        Substituter::new()
+            .output_check(OutputCheck::Deny)
+            .render(template, &env)
+            .unwrap();
+    }
+
+    #[test]
+    fn aliases() {
+        let mut env = HashMap::new();
+        env.insert("DATABASE_HOST".to_string(), "db".to_string());
+        env.insert("PORT".to_string(), "5432".to_string());
+        env.insert("DB_PORT".to_string(), "5433".to_string());
+
+        let rendered = Substituter::new()
+            .alias("DB_HOST", "DATABASE_HOST")
+            .alias("DB_PORT", "PORT")
+            .alias("DB_USER", "DATABASE_USER")
+            .render("${DB_HOST}:${DB_PORT} ${DB_HOST} ${DB_USER}", &env)
+            .unwrap();
+        assert_eq!(rendered.output, "db:5433 db ${DB_USER}");
+        assert_eq!(
+            rendered.report.aliases,
+            vec![("DB_HOST".to_string(), "DATABASE_HOST".to_string())]
+        );
+    }
+
+    #[test]
+    fn normalize_keys() {
+        let mut env = HashMap::new();
+        env.insert("DB_HOST".to_string(), "db".to_string());
+        env.insert("DB_PORT".to_string(), "5432".to_string());
+
+        let rendered = Substituter::new()
+            .normalize_keys(|name| name.to_ascii_uppercase())
+            .alias("DB_ADDRESS", "DB_HOST")
+            .render("${db_host}:${Db_Port} ${db_address} ${db_user}", &env)
+            .unwrap();
+        assert_eq!(rendered.output, "db:5432 db ${db_user}");
+        assert_eq!(rendered.report.unresolved[0].name, "db_user");
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn builtins() {
+        let env: HashMap<String, String> = HashMap::new();
+        let template = r#"${now("%Y")} ${uuid()} ${now(}"#;
+
+        let output = Substituter::new().substitute(template, &env).unwrap();
+        assert_eq!(output, template);
+
+        let output = Substituter::new()
+            .builtin(Builtin::Now)
+            .substitute(template, &env)
+            .unwrap();
+        assert!(output.starts_with("20"));
+        assert!(output.ends_with(" ${uuid()} ${now(}"));
+
+        Substituter::new()
+            .builtin(Builtin::Now)
+            .substitute("${now(%Q)}", &env)
+            .unwrap_err();
+    }
+
+    #[cfg(feature = "random")]
+    #[test]
+    fn random_builtins() {
+        let env: HashMap<String, String> = HashMap::new();
+        let template = "${uuid()} ${random_hex(12)} ${uuid()}";
+        let mut substituter = Substituter::new();
+        substituter
+            .builtin(Builtin::Uuid)
+            .builtin(Builtin::RandomHex)
+            .seed(7);
+        let output = substituter.substitute(template, &env).unwrap();
+        assert_eq!(output, substituter.substitute(template, &env).unwrap());
+        let uuids: Vec<&str> = output.split(' ').collect();
+        assert_ne!(uuids[0], uuids[2]);
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn log_unresolved() {
+        use std::sync::Mutex;
+
+        static MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        struct Logger;
+        impl log::Log for Logger {
+            fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record<'_>) {
+                let msg = format!("{} {}: {}", record.level(), record.target(), record.args());
+                MESSAGES.lock().unwrap().push(msg);
+            }
+            fn flush(&self) {}
+        }
+        log::set_logger(&Logger).unwrap();
+        log::set_max_level(log::LevelFilter::Warn);
+
+        let context = crate::context! {"VAR" => "var"};
+        Substituter::new()
+            .substitute("${VAR}\n  ${LOG_MISSING}", &context)
+            .unwrap();
+        assert!(MESSAGES.lock().unwrap().contains(
+            &"WARN ksubst: unresolved placeholder for variable 'LOG_MISSING' at line 2, column 3"
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn constraints() {
+        let mut substituter = Substituter::new();
+        substituter
+            .constraint("ENVIRONMENT", Constraint::one_of(["dev", "prod"]))
+            .constraint("PORT", Constraint::range(1..=65535))
+            .constraint("PORT", Constraint::pattern("[0-9]{4}").unwrap());
+
+        let context = crate::context! {"ENVIRONMENT" => "prod", "PORT" => 8080};
+        let output = substituter
+            .substitute("${ENVIRONMENT}:${PORT}", &context)
+            .unwrap();
+        assert_eq!(output, "prod:8080");
+        substituter
+            .substitute("${MISSING}", &crate::context! {})
+            .unwrap();
+
+        for invalid in [
+            crate::context! {"ENVIRONMENT" => "prodd"},
+            crate::context! {"PORT" => 80},
+            crate::context! {"PORT" => 99999},
+        ] {
+            substituter.substitute("unused", &invalid).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn custom_pattern() {
+        let context = crate::context! {"HOST" => "example.com", "PORT" => 80};
+        let mut substituter = Substituter::new();
+        substituter
+            .pattern(r"\{\{\s*(?<name>\w+)\s*\}\}")
+            .unwrap()
+            .output_check(OutputCheck::Warn);
+
+        let rendered = substituter
+            .render("{{HOST}}:{{ PORT }} {{ MISSING }} ${HOST}", &context)
+            .unwrap();
+        assert_eq!(rendered.output, "example.com:80 {{ MISSING }} ${HOST}");
+        assert_eq!(rendered.report.resolved[1].span, 9..19);
+        assert_eq!(rendered.report.unresolved[0].name, "MISSING");
+        assert_eq!(rendered.report.warnings.len(), 1);
+
+        Substituter::new().pattern("(").unwrap_err();
+        Substituter::new().pattern(r"\$(\w+)").unwrap_err();
+    }
+
+    #[test]
+    fn filters() {
+        let mut env = HashMap::new();
+        env.insert("ID".to_string(), "42".to_string());
+        let template = "id-${ID|pad(6)} 0x${ID|hex} ${ID|precision(1)} ${MISSING|pad(2)}";
+
+        let output = Substituter::new().substitute(template, &env).unwrap();
+        assert_eq!(output, "id-000042 0x2a 42.0 ${MISSING|pad(2)}");
+        let output = Substituter::new().substitute("${ID|unknown}", &env);
+        assert_eq!(output.unwrap(), "${ID|unknown}");
+
+        let mut substituter = Substituter::new();
+        substituter
+            .register_filter("rot13", |s| {
+                s.chars()
+                    .map(|c| match c {
+                        'a'..='m' | 'A'..='M' => (c as u8 + 13) as char,
+                        'n'..='z' | 'N'..='Z' => (c as u8 - 13) as char,
+                        c => c,
+                    })
+                    .collect()
+            })
+            .register_filter("hex", |s| format!("hex:{}", s));
+        env.insert("NAME".to_string(), "Hello".to_string());
+        let output = substituter
+            .substitute("${NAME|rot13} ${ID|hex} ${ID|pad(3)}", &env)
+            .unwrap();
+        assert_eq!(output, "Uryyb hex:42 042");
+        substituter
+            .substitute("${NAME|rot13(1)}", &env)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn builtin_math() {
+        let mut env = HashMap::new();
+        env.insert("PORT".to_string(), "8080".to_string());
+        env.insert("NAME".to_string(), "app".to_string());
+        let template = "${PORT + 1} ${math(PORT * 2)} ${PORT-1} ${MISSING + 1}";
+
+        let output = Substituter::new().substitute(template, &env).unwrap();
+        assert_eq!(
+            output,
+            "${PORT + 1} ${math(PORT * 2)} 8080-1 ${MISSING + 1}"
+        );
+
+        let mut substituter = Substituter::new();
+        substituter.builtin(Builtin::Math);
+        let rendered = substituter.render(template, &env).unwrap();
+        assert_eq!(rendered.output, "8081 16160 8080-1 ${MISSING + 1}");
+        assert_eq!(rendered.report.unresolved[0].name, "MISSING");
+
+        substituter.substitute("${NAME + 1}", &env).unwrap_err();
+        substituter.substitute("${PORT / 0}", &env).unwrap_err();
+        substituter.substitute("${math(1, 2)}", &env).unwrap_err();
+    }
+
+    #[test]
+    fn builtin_if() {
+        let mut env = HashMap::new();
+        env.insert("ENV".to_string(), "prod".to_string());
+        env.insert("DEBUG".to_string(), "".to_string());
+        env.insert("end".to_string(), "fin".to_string());
+        let template =
+            r#"${if ENV == "prod" && !DEBUG}a${if MISSING}b${else}c${end}${else}d${end} ${end}"#;
+
+        let output = Substituter::new().substitute(template, &env).unwrap();
+        assert_eq!(
+            output,
+            r#"${if ENV == "prod" && !DEBUG}a${if MISSING}b${else}c${end}${else}d${end} ${end}"#
+                .replace("${end}", "fin")
+        );
+
+        let mut substituter = Substituter::new();
+        substituter.builtin(Builtin::If);
+        let rendered = substituter.render(template, &env).unwrap();
+        // `${end}` outside of blocks is a placeholder
+        assert_eq!(rendered.output, "ac fin");
+        let output = substituter.substitute(
+            r#"${if ENV != "prod" || DEBUG}${MISSING} ${uuid()}${else}${ENV}${end}"#,
+            &env,
+        );
+        assert_eq!(output.unwrap(), "prod");
+
+        for invalid in [
+            "${if ENV}",
+            "${if ENV}${else}${else}${end}",
+            "${if ENV &&}${end}",
+            "${if (ENV}${end}",
+        ] {
+            substituter.substitute(invalid, &env).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn builtin_file() {
+        let root = std::env::temp_dir().join(format!("ksubst-file-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("nested")).unwrap();
+        std::fs::write(root.join("cert.pem"), "${VAR}").unwrap();
+        std::fs::write(root.join("nested/a"), r#"a ${file(nested/b, render)}"#).unwrap();
+        std::fs::write(root.join("nested/b"), r#"${VAR} ${file(nested/a, render)}"#).unwrap();
+
+        let mut env = HashMap::new();
+        env.insert("VAR".to_string(), "var".to_string());
+        let mut substituter = Substituter::new();
+        substituter.builtin(Builtin::File);
+
+        // No root directory configured
+        substituter
+            .substitute("${file(cert.pem)}", &env)
+            .unwrap_err();
+
+        substituter.file_root(&root);
+        let output = substituter
+            .substitute("${file(cert.pem)} ${file(cert.pem, render)}", &env)
+            .unwrap();
+        assert_eq!(output, "${VAR} var");
+
+        let err = substituter
+            .substitute("${file(nested/a, render)}", &env)
+            .unwrap_err();
+        assert!(err.to_string().contains("include cycle"));
+        substituter.substitute("${file(../x)}", &env).unwrap_err();
+        substituter
+            .substitute("${file(/etc/hostname)}", &env)
+            .unwrap_err();
+        substituter
+            .substitute("${file(missing)}", &env)
+            .unwrap_err();
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn preview_changes() {
+        let mut env = HashMap::new();
+        env.insert("VAR".to_string(), "var".to_string());
+        env.insert("EMPTY".to_string(), "".to_string());
+
+        let changes = Substituter::new()
+            .preview("a ${VAR.} ${MISSING} ${EMPTY-}", &env)
+            .unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                Change {
+                    span: 2..9,
+                    old: "${VAR.}".to_string(),
+                    new: "var.".to_string()
+                },
+                Change {
+                    span: 21..30,
+                    old: "${EMPTY-}".to_string(),
+                    new: "".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn idempotent() {
+        let mut env = HashMap::new();
+        env.insert("VAR".to_string(), "var".to_string());
+        env.insert("EMPTY".to_string(), "".to_string());
+
+        let substituter = Substituter::new();
+        substituter
+            .verify_idempotent("${VAR} ${MISSING}", &env)
+            .unwrap();
+        // The second pass resolves the placeholder built by the first one
+        substituter
+            .verify_idempotent("$${EMPTY}{VAR}", &env)
+            .unwrap_err();
+    }
+}
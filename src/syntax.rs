@@ -0,0 +1,365 @@
+//! Placeholder syntaxes other than `${...}`.
+
+use crate::template::{self, Segment};
+use crate::{substitute_with, Error, Lookup};
+
+/// Stand-in for `$` characters of translated templates, a noncharacter
+/// never found in text.
+const DOLLAR: char = '\u{fdd1}';
+/// Delimiter of the whitespace before a translated placeholder body.
+const LEAD: char = '\u{fdd2}';
+/// Delimiter of the whitespace after a translated placeholder body.
+const TRAIL: char = '\u{fdd3}';
+/// Marker of placeholders translated from `$NAME`.
+const BARE: char = '\u{fdd4}';
+
+/// Syntax of the placeholders of templates.
+///
+/// Templates are translated to the `${...}` syntax, where `${` and `$` of
+/// the template are plain text unless the syntax includes them, then
+/// translated back after substitution, placeholders left unsubstituted
+/// keeping their syntax.
+///
+/// ```rust
+/// use ksubst::Syntax;
+///
+/// let context = ksubst::context! {"HOME" => "/home/user"};
+/// let output = Syntax::Dollar.substitute("cd $HOME/${HOME} $MISSING", &context);
+/// assert_eq!(output.unwrap(), "cd /home/user//home/user $MISSING");
+///
+/// let output = Syntax::Percent.substitute("cd %HOME% & echo 100%", &context);
+/// assert_eq!(output.unwrap(), "cd /home/user & echo 100%");
+///
+/// let output = Syntax::Mustache.substitute("cd {{ HOME }} {{ MISSING }} ${HOME}", &context);
+/// assert_eq!(output.unwrap(), "cd /home/user {{ MISSING }} ${HOME}");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Syntax {
+    /// `${NAME}`, the default.
+    #[default]
+    Braces,
+    /// `$NAME` or `${NAME}`, like shells.
+    Dollar,
+    /// `%NAME%`, like Windows batch files.
+    Percent,
+    /// `{{NAME}}` or `{{ NAME }}`, like mustache templates.
+    Mustache,
+    /// Custom delimiters.
+    Delimiters(Delimiters),
+}
+
+impl Syntax {
+    /// Substitute variables in a template using this syntax.
+    ///
+    /// See [`substitute`](crate::substitute) for details about substitution
+    /// rules, inside placeholders.
+    pub fn substitute<L>(&self, template: &str, variables: &L) -> Result<String, Error>
+    where
+        L: Lookup + ?Sized,
+    {
+        Ok(self.restore(&substitute_with(self.translate(template), variables)?))
+    }
+
+    /// Translate a template using this syntax to the `${...}` syntax.
+    ///
+    /// The output must be given back to [`restore`](Self::restore) once
+    /// substituted.
+    pub fn translate(&self, template: &str) -> String {
+        match self {
+            Self::Braces => template.to_string(),
+            Self::Dollar => translate_dollar(template),
+            Self::Percent => translate(template, "%", "%"),
+            Self::Mustache => translate(template, "{{", "}}"),
+            Self::Delimiters(delimiters) => delimiters.translate(template),
+        }
+    }
+
+    /// Translate a substituted output back, restoring the syntax of
+    /// placeholders left unsubstituted.
+    pub fn restore(&self, output: &str) -> String {
+        match self {
+            Self::Braces => output.to_string(),
+            Self::Dollar => restore(output, "${", "}"),
+            Self::Percent => restore(output, "%", "%"),
+            Self::Mustache => restore(output, "{{", "}}"),
+            Self::Delimiters(delimiters) => delimiters.restore(output),
+        }
+    }
+}
+
+/// Custom placeholder delimiters, e.g. `<<NAME>>` instead of `${NAME}`.
+///
+/// Like other [`Syntax`] modes, templates are translated to the `${...}`
+/// syntax, where `${` and `$` of the template are plain text. This lets
+/// files whose own syntax uses `${...}`, like CI workflows, be templated
+/// as-is.
+///
+/// ```rust
+/// let delimiters = ksubst::Delimiters::new("<<", ">>").unwrap();
+/// let context = ksubst::context! {"IMAGE" => "app:1.2"};
+///
+/// let output = delimiters.substitute("image: <<IMAGE>>\nrun: echo ${{ github.sha }} <<MISSING>>", &context);
+/// assert_eq!(output.unwrap(), "image: app:1.2\nrun: echo ${{ github.sha }} <<MISSING>>");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delimiters {
+    open: String,
+    close: String,
+}
+
+impl Delimiters {
+    /// Create delimiters, which must not be empty.
+    pub fn new<O, C>(open: O, close: C) -> Result<Self, Error>
+    where
+        O: Into<String>,
+        C: Into<String>,
+    {
+        let (open, close) = (open.into(), close.into());
+        if open.is_empty() || close.is_empty() {
+            return Err(Error("placeholder delimiters can't be empty".to_string()));
+        }
+        Ok(Self { open, close })
+    }
+
+    /// Substitute variables in a template using these delimiters.
+    ///
+    /// See [`substitute`](crate::substitute) for details about substitution
+    /// rules, inside delimiters.
+    pub fn substitute<L>(&self, template: &str, variables: &L) -> Result<String, Error>
+    where
+        L: Lookup + ?Sized,
+    {
+        Ok(self.restore(&substitute_with(self.translate(template), variables)?))
+    }
+
+    /// Translate a template using these delimiters to the `${...}` syntax.
+    ///
+    /// Delimited text is only translated if it is a valid placeholder, on a
+    /// single line, whitespace around it being ignored. The output must be
+    /// given back to [`restore`](Self::restore) once substituted.
+    pub fn translate(&self, template: &str) -> String {
+        translate(template, &self.open, &self.close)
+    }
+
+    /// Translate a substituted output back, restoring the delimiters of
+    /// placeholders left unsubstituted.
+    pub fn restore(&self, output: &str) -> String {
+        restore(output, &self.open, &self.close)
+    }
+}
+
+/// Translate a template using delimiters to the `${...}` syntax.
+fn translate(template: &str, open: &str, close: &str) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find(open) {
+        push_literal(&mut output, &rest[..start]);
+        let body_start = start + open.len();
+        let placeholder = rest[body_start..]
+            .find(close)
+            .map(|len| &rest[body_start..body_start + len])
+            .filter(|body| !body.contains('\n'))
+            .and_then(|body| Some((body.len(), translate_body(body)?)));
+        match placeholder {
+            Some((len, placeholder)) => {
+                output.push_str(&placeholder);
+                rest = &rest[body_start + len + close.len()..];
+            }
+            None => {
+                push_literal(&mut output, open);
+                rest = &rest[body_start..];
+            }
+        }
+    }
+    push_literal(&mut output, rest);
+    output
+}
+
+/// Translate the body of a placeholder, moving the whitespace around it out
+/// of the `${...}` syntax if needed.
+fn translate_body(body: &str) -> Option<String> {
+    let placeholder = format!("${{{}}}", body);
+    if is_segment(&placeholder) {
+        return Some(placeholder);
+    }
+    let trimmed = body.trim();
+    let placeholder = format!("${{{}}}", trimmed);
+    if trimmed.len() == body.len() || !is_segment(&placeholder) {
+        return None;
+    }
+    let start = body.len() - body.trim_start().len();
+    Some(format!(
+        "{}{}{}{}{}{}{}",
+        LEAD,
+        &body[..start],
+        LEAD,
+        placeholder,
+        TRAIL,
+        &body[start + trimmed.len()..],
+        TRAIL
+    ))
+}
+
+/// Translate a template using the `$NAME` or `${...}` syntax.
+fn translate_dollar(template: &str) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        push_literal(&mut output, &rest[..start]);
+        rest = &rest[start..];
+        let segment = match rest.starts_with("${") {
+            true => template::parse_segment(rest, 0),
+            false => None,
+        };
+        match segment {
+            Some(Segment::Literal(_)) | None => {
+                let name_len = template::name_len(&rest[1..]);
+                if name_len > 0 {
+                    output.push(BARE);
+                    output.push_str("${");
+                    output.push_str(&rest[1..1 + name_len]);
+                    output.push('}');
+                } else {
+                    output.push(DOLLAR);
+                }
+                rest = &rest[1 + name_len..];
+            }
+            Some(segment) => {
+                output.push_str(segment.as_str());
+                rest = &rest[segment.span().end..];
+            }
+        }
+    }
+    push_literal(&mut output, rest);
+    output
+}
+
+/// Translate a substituted output back to delimiters.
+fn restore(output: &str, open: &str, close: &str) -> String {
+    let mut restored = String::with_capacity(output.len());
+    let mut rest = output;
+    // Values can't contain '$', so all placeholders come from templates
+    while let Some(start) = rest.find(['$', LEAD, TRAIL, BARE]) {
+        restored.push_str(&rest[..start]);
+        rest = &rest[start..];
+        // Padding of a substituted placeholder
+        if let Some((_, after)) = strip_pad(rest, TRAIL) {
+            rest = after;
+            continue;
+        }
+
+        let (lead, after) = strip_pad(rest, LEAD).unwrap_or(("", rest));
+        let bare = after.starts_with(BARE);
+        let after = after.strip_prefix(BARE).unwrap_or(after);
+        let placeholder = after
+            .strip_prefix("${")
+            .and_then(|body| Some(body.split_at(body.find('}')?)));
+        let Some((inner, after)) = placeholder else {
+            // Markers of a substituted placeholder, or a stray character
+            match after.len() == rest.len() {
+                true => {
+                    let len = rest.chars().next().map_or(0, char::len_utf8);
+                    restored.push_str(&rest[..len]);
+                    rest = &rest[len..];
+                }
+                false => rest = after,
+            }
+            continue;
+        };
+        let after = &after[1..];
+        let (trail, after) = match rest.starts_with(LEAD) {
+            true => strip_pad(after, TRAIL).unwrap_or(("", after)),
+            false => ("", after),
+        };
+        match bare {
+            true => restored.push('$'),
+            false => restored.push_str(open),
+        }
+        restored.push_str(lead);
+        restored.push_str(inner);
+        restored.push_str(trail);
+        if !bare {
+            restored.push_str(close);
+        }
+        rest = after;
+    }
+    restored.push_str(rest);
+    restored.replace(DOLLAR, "$")
+}
+
+/// Strip whitespace delimited by `marker` from the start of `text`.
+fn strip_pad(text: &str, marker: char) -> Option<(&str, &str)> {
+    let rest = text.strip_prefix(marker)?;
+    let end = rest.find(marker)?;
+    Some((&rest[..end], &rest[end + marker.len_utf8()..]))
+}
+
+/// Push literal text of a template, with `$` characters replaced.
+fn push_literal(output: &mut String, text: &str) {
+    output.extend(text.chars().map(|c| if c == '$' { DOLLAR } else { c }));
+}
+
+/// Whether text is a single placeholder, call, expression or comment.
+fn is_segment(text: &str) -> bool {
+    match template::parse_segment(text, 0) {
+        Some(Segment::Literal(_)) | None => false,
+        Some(segment) => segment.span().end == text.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context;
+
+    #[test]
+    fn custom_delimiters() {
+        let delimiters = Delimiters::new("<<", ">>").unwrap();
+        let context = context! {"A" => "a", "N" => 7};
+        let template =
+            "$A ${A} <<A>> <<N|pad(3)>> <<# comment>><<A.>>b <<MISSING>> <<>> << A\n>> <<a}>>";
+        assert_eq!(
+            delimiters.substitute(template, &context).unwrap(),
+            "$A ${A} a 007 <<# comment>>a.b <<MISSING>> <<>> << A\n>> <<a}>>"
+        );
+
+        let template = "<< A >><<  MISSING\t>><<MISSING >><< N + 1 >>";
+        assert_eq!(
+            delimiters.substitute(template, &context).unwrap(),
+            "a<<  MISSING\t>><<MISSING >><< N + 1 >>"
+        );
+        Delimiters::new("", "}}").unwrap_err();
+    }
+
+    #[test]
+    fn syntax_presets() {
+        let context = context! {"A" => "a", "EMPTY" => "", "N" => 7};
+        let cases = [
+            (Syntax::Braces, "$A ${A} ${MISSING}", "$A a ${MISSING}"),
+            (
+                Syntax::Dollar,
+                "$A-$A_ ${A} $$ $1 $ $MISSING ${MISSING} ${EMPTY-}x",
+                "a-$A_ a $$ $1 $ $MISSING ${MISSING} x",
+            ),
+            (
+                Syntax::Percent,
+                "%A%%A% 100% %MISSING% 50%",
+                "aa 100% %MISSING% 50%",
+            ),
+            (
+                Syntax::Mustache,
+                "{{A}} {{ A }} {{ MISSING }}{{MISSING}} {{ N|pad(3) }} ${A} {{",
+                "a a {{ MISSING }}{{MISSING}} 007 ${A} {{",
+            ),
+        ];
+        for (syntax, template, expected) in cases {
+            assert_eq!(
+                syntax.substitute(template, &context).unwrap(),
+                expected,
+                "{:?}",
+                syntax
+            );
+        }
+    }
+}
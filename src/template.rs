@@ -0,0 +1,626 @@
+//! Parsed representation of templates.
+//!
+//! This exposes the same grammar used for substitution, so that external
+//! tools can analyze templates without re-implementing it.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use ksubst::template::{parse, Segment};
+//!
+//! let segments = parse("https://${hostname.}example.com");
+//! assert_eq!(segments.len(), 3);
+//! match &segments[1] {
+//!     Segment::Placeholder(p) => {
+//!         assert_eq!(p.name, "hostname");
+//!         assert_eq!(p.suffix, Some("."));
+//!         assert_eq!(p.span, 8..20);
+//!     }
+//!     _ => unreachable!(),
+//! }
+//! ```
+
+use crate::{cond, expr};
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// A segment of a parsed template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Segment<'a> {
+    /// Text copied verbatim to the output.
+    Literal(Literal<'a>),
+    /// Variable placeholder, e.g. `${VAR}` or `${VAR.}`.
+    Placeholder(Placeholder<'a>),
+    /// Function call, e.g. `${now("%Y")}`.
+    Call(Call<'a>),
+    /// Arithmetic expression, e.g. `${PORT + 1}`.
+    Expression(Expression<'a>),
+    /// Comment, e.g. `${# @var PORT: listening port}`.
+    Comment(Comment<'a>),
+    /// Conditional block start, e.g. `${if ENV == "prod"}`.
+    Conditional(Conditional<'a>),
+}
+
+impl<'a> Segment<'a> {
+    /// Source text of this segment.
+    pub fn as_str(&self) -> &'a str {
+        match self {
+            Segment::Literal(l) => l.text,
+            Segment::Placeholder(p) => p.text,
+            Segment::Call(c) => c.text,
+            Segment::Expression(e) => e.text,
+            Segment::Comment(c) => c.text,
+            Segment::Conditional(c) => c.text,
+        }
+    }
+
+    /// Byte range of this segment in the template.
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Segment::Literal(l) => l.span.clone(),
+            Segment::Placeholder(p) => p.span.clone(),
+            Segment::Call(c) => c.span.clone(),
+            Segment::Expression(e) => e.span.clone(),
+            Segment::Comment(c) => c.span.clone(),
+            Segment::Conditional(c) => c.span.clone(),
+        }
+    }
+}
+
+/// Literal text segment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Literal<'a> {
+    /// Literal text.
+    pub text: &'a str,
+    /// Byte range in the template.
+    pub span: Range<usize>,
+}
+
+/// Variable placeholder segment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Placeholder<'a> {
+    /// Full placeholder text, e.g. `${VAR.}`.
+    pub text: &'a str,
+    /// Variable name.
+    pub name: &'a str,
+    /// Suffix appended to non-empty values, starting with `.` or `-`.
+    pub suffix: Option<&'a str>,
+    /// Filters applied to the value, in order.
+    pub filters: Vec<Filter<'a>>,
+    /// Byte range in the template.
+    pub span: Range<usize>,
+}
+
+/// Filter applied to a placeholder value, e.g. `pad(5)` in `${ID|pad(5)}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Filter<'a> {
+    /// Filter name.
+    pub name: &'a str,
+    /// Arguments, with quotes and escapes removed.
+    pub args: Vec<Cow<'a, str>>,
+}
+
+/// Function call segment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Call<'a> {
+    /// Full call text, e.g. `${now("%Y")}`.
+    pub text: &'a str,
+    /// Function name.
+    pub name: &'a str,
+    /// Arguments, with quotes and escapes removed.
+    pub args: Vec<Cow<'a, str>>,
+    /// Byte range in the template.
+    pub span: Range<usize>,
+}
+
+/// Arithmetic expression segment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Expression<'a> {
+    /// Full expression text, e.g. `${PORT + 1}`.
+    pub text: &'a str,
+    /// Expression source, without delimiters.
+    pub expression: &'a str,
+    /// Byte range in the template.
+    pub span: Range<usize>,
+}
+
+/// Comment segment, removed from rendered output with
+/// [`Builtin::Comments`](crate::Builtin::Comments).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Comment<'a> {
+    /// Full comment text, e.g. `${# note}`.
+    pub text: &'a str,
+    /// Comment content, without delimiters and surrounding whitespace.
+    pub content: &'a str,
+    /// Byte range in the template.
+    pub span: Range<usize>,
+}
+
+/// Conditional block start segment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Conditional<'a> {
+    /// Full block start text, e.g. `${if ENV == "prod"}`.
+    pub text: &'a str,
+    /// Condition source, without `if` and surrounding whitespace.
+    pub condition: &'a str,
+    /// Byte range in the template.
+    pub span: Range<usize>,
+}
+
+/// Parse a template into literal, placeholder, function call, expression, comment and conditional segments.
+///
+/// Placeholders have the form `${VAR}`, `${VAR.suffix}` or `${VAR-suffix}`,
+/// where variable names start with an ASCII letter or underscore followed
+/// by ASCII alphanumerics or underscores.
+///
+/// Placeholders can also pipe their value through filters, with the form
+/// `${VAR|filter}` or `${VAR|filter(arg, ...)|...}`, in which case they
+/// don't have a suffix. Placeholders with unknown filters are left as-is
+/// when substituting, like other syntaxes using `|`.
+///
+/// Function calls have the form `${name(arg, ...)}`, where arguments are
+/// either bare words or double-quoted strings (supporting `\"` and `\\`
+/// escapes). Neither kind of argument can contain a `}`.
+///
+/// Arithmetic expressions are made of integers, variable names, parentheses
+/// and the `+`, `-`, `*`, `/` and `%` operators, e.g. `${PORT + 1}`. As
+/// `${PORT-1}` is a placeholder with a `-1` suffix, subtractions need
+/// surrounding whitespace.
+///
+/// Comments have the form `${# text}`, with whitespace after the `#` so
+/// that shell lengths like `${#VAR}` are left as-is. They are removed from
+/// rendered output with [`Builtin::Comments`](crate::Builtin::Comments).
+/// See [`variables_metadata`](crate::variables_metadata) for documenting
+/// variables in comments.
+///
+/// Conditional blocks start with `${if CONDITION}`, where conditions are
+/// made of variable names, double-quoted strings, parentheses and the `==`,
+/// `!=`, `!`, `&&` and `||` operators, e.g. `${if ENV == "prod" && !DEBUG}`.
+/// Blocks are delimited by `${else}` and `${end}`, which are placeholders
+/// as far as parsing is concerned. See [`Builtin::If`](crate::Builtin::If).
+///
+/// Anything else, including malformed placeholders, is returned as literal
+/// text. Adjacent literal text is always merged into a single segment.
+pub fn parse(template: &str) -> Vec<Segment<'_>> {
+    segments(template).collect()
+}
+
+/// Lazily parse a template, yielding the same segments as [`parse`].
+pub(crate) fn segments(template: &str) -> Segments<'_> {
+    Segments {
+        template,
+        pos: 0,
+        literal_start: 0,
+        pending: None,
+    }
+}
+
+/// Iterator over the segments of a template.
+#[derive(Debug)]
+pub(crate) struct Segments<'a> {
+    template: &'a str,
+    /// Position to scan placeholders from.
+    pos: usize,
+    /// Start of the literal text not yielded yet.
+    literal_start: usize,
+    /// Segment to yield after the preceding literal.
+    pending: Option<Segment<'a>>,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(segment) = self.pending.take() {
+            return Some(segment);
+        }
+
+        let template = self.template;
+        while let Some(offset) = template[self.pos..].find("${") {
+            let start = self.pos + offset;
+            match parse_segment(template, start) {
+                Some(segment) => {
+                    let literal = self.literal_start..start;
+                    self.pos = segment.span().end;
+                    self.literal_start = self.pos;
+                    if literal.is_empty() {
+                        return Some(segment);
+                    }
+                    self.pending = Some(segment);
+                    return Some(literal_segment(template, literal));
+                }
+                None => self.pos = start + 1,
+            }
+        }
+
+        let literal = self.literal_start..template.len();
+        self.pos = template.len();
+        self.literal_start = self.pos;
+        if literal.is_empty() {
+            None
+        } else {
+            Some(literal_segment(template, literal))
+        }
+    }
+}
+
+fn literal_segment(template: &str, span: Range<usize>) -> Segment<'_> {
+    Segment::Literal(Literal {
+        text: &template[span.clone()],
+        span,
+    })
+}
+
+/// Try to parse a segment starting at `start` (pointing to `${`).
+pub(crate) fn parse_segment(template: &str, start: usize) -> Option<Segment<'_>> {
+    let body_start = start + 2;
+    let close = body_start + template[body_start..].find('}')?;
+    let body = &template[body_start..close];
+    let text = &template[start..close + 1];
+    let span = start..close + 1;
+
+    let comment = body
+        .strip_prefix('#')
+        .filter(|c| c.starts_with(char::is_whitespace));
+    if let Some(content) = comment {
+        return Some(Segment::Comment(Comment {
+            text,
+            content: content.trim(),
+            span,
+        }));
+    }
+
+    let condition = body
+        .strip_prefix("if")
+        .filter(|c| c.starts_with(char::is_whitespace))
+        .map(str::trim)
+        .filter(|c| cond::is_condition(c));
+    if let Some(condition) = condition {
+        return Some(Segment::Conditional(Conditional {
+            text,
+            condition,
+            span,
+        }));
+    }
+
+    let name_len = name_len(body);
+    if name_len > 0 {
+        let (name, rest) = body.split_at(name_len);
+        match rest.as_bytes().first() {
+            None => {
+                return Some(Segment::Placeholder(Placeholder {
+                    text,
+                    name,
+                    suffix: None,
+                    filters: Vec::new(),
+                    span,
+                }))
+            }
+            Some(b'.' | b'-') => {
+                return Some(Segment::Placeholder(Placeholder {
+                    text,
+                    name,
+                    suffix: Some(rest),
+                    filters: Vec::new(),
+                    span,
+                }))
+            }
+            _ if rest.trim_start().starts_with('|') => {
+                if let Some(filters) = parse_filters(rest.trim_start()) {
+                    return Some(Segment::Placeholder(Placeholder {
+                        text,
+                        name,
+                        suffix: None,
+                        filters,
+                        span,
+                    }));
+                }
+            }
+            Some(b'(') => {
+                if let Some(args) = rest[1..].strip_suffix(')').and_then(parse_args) {
+                    return Some(Segment::Call(Call {
+                        text,
+                        name,
+                        args,
+                        span,
+                    }));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if expr::is_expression(body) {
+        return Some(Segment::Expression(Expression {
+            text,
+            expression: body.trim(),
+            span,
+        }));
+    }
+    None
+}
+
+/// Parse a chain of `|filter` or `|filter(args)` items.
+fn parse_filters(input: &str) -> Option<Vec<Filter<'_>>> {
+    let mut filters = Vec::new();
+    let mut rest = input;
+    while !rest.is_empty() {
+        rest = rest.strip_prefix('|')?.trim_start();
+        let (name, tail) = rest.split_at(name_len(rest));
+        if name.is_empty() {
+            return None;
+        }
+        let tail = tail.trim_start();
+        let (args, tail) = match tail.strip_prefix('(') {
+            Some(tail) => {
+                let end = args_end(tail)?;
+                (parse_args(&tail[..end])?, &tail[end + 1..])
+            }
+            None => (Vec::new(), tail),
+        };
+        filters.push(Filter { name, args });
+        rest = tail.trim_start();
+    }
+    Some(filters)
+}
+
+/// Position of the closing parenthesis of an arguments list, skipping quoted strings.
+fn args_end(input: &str) -> Option<usize> {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (pos, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ')' if !quoted => return Some(pos),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parse comma-separated function call arguments.
+fn parse_args(input: &str) -> Option<Vec<Cow<'_, str>>> {
+    let mut args = Vec::new();
+    if input.trim().is_empty() {
+        return Some(args);
+    }
+
+    let mut rest = input;
+    loop {
+        rest = rest.trim_start();
+        let (arg, tail) = if let Some(quoted) = rest.strip_prefix('"') {
+            parse_quoted(quoted)?
+        } else {
+            let end = rest.find([',', '"', '(', ')']).unwrap_or(rest.len());
+            let arg = rest[..end].trim_end();
+            if arg.is_empty() {
+                return None;
+            }
+            (Cow::Borrowed(arg), &rest[end..])
+        };
+        args.push(arg);
+
+        rest = tail.trim_start();
+        match rest.strip_prefix(',') {
+            Some(tail) => rest = tail,
+            None if rest.is_empty() => return Some(args),
+            None => return None,
+        }
+    }
+}
+
+/// Parse a double-quoted string (without its opening quote), returning it along with the remaining input.
+pub(crate) fn parse_quoted(input: &str) -> Option<(Cow<'_, str>, &str)> {
+    let mut value = String::new();
+    let mut chars = input.char_indices();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '"' => {
+                let arg = if value.len() == pos {
+                    Cow::Borrowed(&input[..pos])
+                } else {
+                    Cow::Owned(value)
+                };
+                return Some((arg, &input[pos + 1..]));
+            }
+            '\\' => match chars.next()? {
+                (_, c @ ('"' | '\\')) => value.push(c),
+                _ => return None,
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Length of the variable name at the beginning of `input`, if any.
+pub(crate) fn name_len(input: &str) -> usize {
+    let bytes = input.as_bytes();
+    match bytes.first() {
+        Some(b) if b.is_ascii_alphabetic() || *b == b'_' => {}
+        _ => return 0,
+    }
+    bytes
+        .iter()
+        .position(|b| !(b.is_ascii_alphanumeric() || *b == b'_'))
+        .unwrap_or(bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placeholder<'a>(text: &'a str, suffix: Option<&'a str>, span: Range<usize>) -> Segment<'a> {
+        let name_end = text.find(['}', '.', '-']).unwrap();
+        Segment::Placeholder(Placeholder {
+            text,
+            name: &text[2..name_end],
+            suffix,
+            filters: Vec::new(),
+            span,
+        })
+    }
+
+    fn literal(text: &str, span: Range<usize>) -> Segment<'_> {
+        Segment::Literal(Literal { text, span })
+    }
+
+    #[test]
+    fn parse_segments() {
+        let segments = parse("foo ${VAR} ${VAR.}${VAR-x}bar");
+        assert_eq!(
+            segments,
+            vec![
+                literal("foo ", 0..4),
+                placeholder("${VAR}", None, 4..10),
+                literal(" ", 10..11),
+                placeholder("${VAR.}", Some("."), 11..18),
+                placeholder("${VAR-x}", Some("-x"), 18..26),
+                literal("bar", 26..29),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_malformed() {
+        for template in [
+            "",
+            "foo",
+            "foo ${ bar",
+            "${}",
+            "${1VAR}",
+            "${VAR",
+            "${VAR.",
+            "$VAR",
+        ] {
+            let segments = parse(template);
+            let joined: String = segments.iter().map(Segment::as_str).collect();
+            assert_eq!(joined, template);
+            assert!(segments.iter().all(|s| matches!(s, Segment::Literal(_))));
+        }
+    }
+
+    #[test]
+    fn parse_calls() {
+        let segments = parse(r#"${now()} ${now("%Y", local)} ${f("a\"b\\", x y)}"#);
+        let calls: Vec<(&str, Vec<&str>)> = segments
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Call(c) => Some((c.name, c.args.iter().map(|a| a.as_ref()).collect())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                ("now", vec![]),
+                ("now", vec!["%Y", "local"]),
+                ("f", vec!["a\"b\\", "x y"]),
+            ]
+        );
+        assert_eq!(segments[2].span(), 9..28);
+
+        for template in [
+            r#"${f(}"#,
+            r#"${f("a)}"#,
+            r#"${f(a,)}"#,
+            r#"${f(a)x}"#,
+            r#"${f("a" b)}"#,
+        ] {
+            let segments = parse(template);
+            assert!(
+                matches!(&segments[..], [Segment::Literal(_)]),
+                "{}",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn parse_filters() {
+        let segments =
+            parse(r#"${ID|pad(5)} ${ID | hex | pad( 8 )} ${ID|pad("|)")} ${ID|} ${ID|pad(}"#);
+        let filter = |name, args: &[&'static str]| Filter {
+            name,
+            args: args.iter().map(|a| Cow::Borrowed(*a)).collect(),
+        };
+        let filters: Vec<&[Filter<'_>]> = segments
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Placeholder(p) => Some(&p.filters[..]),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            filters,
+            vec![
+                &[filter("pad", &["5"])][..],
+                &[filter("hex", &[]), filter("pad", &["8"])],
+                &[filter("pad", &["|)"])],
+            ]
+        );
+        assert_eq!(segments.last().unwrap().as_str(), " ${ID|} ${ID|pad(}");
+    }
+
+    #[test]
+    fn parse_comments() {
+        let segments = parse("a${# note }b${# }${ # not a comment}");
+        assert_eq!(segments.len(), 5);
+        assert!(
+            matches!(&segments[1], Segment::Comment(c) if c.content == "note" && c.span == (1..11))
+        );
+        assert!(matches!(&segments[3], Segment::Comment(c) if c.content.is_empty()));
+        assert!(matches!(&segments[4], Segment::Literal(_)));
+
+        // Shell lengths are not comments
+        let template = "${#} ${#VAR} ${#arr[@]}";
+        assert_eq!(parse(template), vec![literal(template, 0..template.len())]);
+    }
+
+    #[test]
+    fn parse_conditionals() {
+        let segments = parse(r#"${if ENV == "prod" && !DEBUG }a${else}${end}${if}${if 'x'}"#);
+        assert!(matches!(
+            &segments[0],
+            Segment::Conditional(c) if c.condition == r#"ENV == "prod" && !DEBUG"# && c.span == (0..30)
+        ));
+        assert!(matches!(&segments[2], Segment::Placeholder(p) if p.name == "else"));
+        assert!(matches!(&segments[3], Segment::Placeholder(p) if p.name == "end"));
+        // Variables named `if` and other text are left alone
+        assert!(matches!(&segments[4], Segment::Placeholder(p) if p.name == "if"));
+        assert!(matches!(&segments[5], Segment::Literal(l) if l.text == "${if 'x'}"));
+    }
+
+    #[test]
+    fn parse_expressions() {
+        let segments = parse("${ PORT + 1 } ${PORT+1} ${PORT-1} ${(A * 2)} ${A B}");
+        let expressions: Vec<&str> = segments
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Expression(e) => Some(e.expression),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(expressions, vec!["PORT + 1", "PORT+1", "(A * 2)"]);
+        assert!(matches!(&segments[4], Segment::Placeholder(p) if p.suffix == Some("-1")));
+    }
+
+    #[test]
+    fn parse_nested_start() {
+        let segments = parse("${${VAR}");
+        assert_eq!(
+            segments,
+            vec![literal("${", 0..2), placeholder("${VAR}", None, 2..8)]
+        );
+    }
+}
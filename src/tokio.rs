@@ -0,0 +1,210 @@
+//! Asynchronous streaming substitution for [tokio] I/O.
+//!
+//! This module is available with the `tokio` feature enabled. Placeholders
+//! split across read or write chunks are handled transparently.
+//!
+//! [tokio]: https://docs.rs/tokio
+
+use crate::invalid_data;
+use crate::stream::Streamer;
+use ::tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+/// Size of the chunks read from the inner reader.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Asynchronous reader substituting variables in the data read from an inner reader.
+#[derive(Debug)]
+pub struct SubstReader<R> {
+    inner: R,
+    variables: HashMap<String, String>,
+    streamer: Streamer,
+    output: Vec<u8>,
+    position: usize,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> SubstReader<R> {
+    /// Wrap `inner`, substituting placeholders with values from `variables`.
+    pub fn new(inner: R, variables: HashMap<String, String>) -> Self {
+        Self {
+            inner,
+            variables,
+            streamer: Streamer::default(),
+            output: Vec::new(),
+            position: 0,
+            eof: false,
+        }
+    }
+
+    /// Consume this adapter, returning the inner reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SubstReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.position == this.output.len() {
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0u8; CHUNK_SIZE];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+
+            let filled = chunk_buf.filled();
+            let output = if filled.is_empty() {
+                this.eof = true;
+                this.streamer.finish(&this.variables)
+            } else {
+                this.streamer.feed(filled, &this.variables)
+            };
+            this.output = output.map_err(invalid_data)?.into_bytes();
+            this.position = 0;
+        }
+
+        let available = &this.output[this.position..];
+        let len = available.len().min(buf.remaining());
+        buf.put_slice(&available[..len]);
+        this.position += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Asynchronous writer substituting variables in the data written to an inner writer.
+///
+/// Output may be held back while it could still be part of a placeholder,
+/// so [`shutdown`] must be called to write out the remaining data.
+///
+/// [`shutdown`]: ::tokio::io::AsyncWriteExt::shutdown
+#[derive(Debug)]
+pub struct SubstWriter<W> {
+    inner: W,
+    variables: HashMap<String, String>,
+    streamer: Streamer,
+    output: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<W: AsyncWrite + Unpin> SubstWriter<W> {
+    /// Wrap `inner`, substituting placeholders with values from `variables`.
+    pub fn new(inner: W, variables: HashMap<String, String>) -> Self {
+        Self {
+            inner,
+            variables,
+            streamer: Streamer::default(),
+            output: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+
+    /// Consume this adapter, returning the inner writer.
+    ///
+    /// Any data not yet written out is lost.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Write buffered output to the inner writer.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.position < self.output.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.output[self.position..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.position += written;
+        }
+        self.output.clear();
+        self.position = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for SubstWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+
+        let output = this
+            .streamer
+            .feed(buf, &this.variables)
+            .map_err(invalid_data)?;
+        this.output = output.into_bytes();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if !this.finished {
+            let output = this
+                .streamer
+                .finish(&this.variables)
+                .map_err(invalid_data)?;
+            this.output = output.into_bytes();
+            this.finished = true;
+            ready!(this.poll_drain(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn env() -> HashMap<String, String> {
+        let mut env = HashMap::new();
+        env.insert("VAR".to_string(), "var".to_string());
+        env
+    }
+
+    #[::tokio::test]
+    async fn async_reader() {
+        let (client, mut server) = ::tokio::io::duplex(4);
+        let writer = ::tokio::spawn(async move {
+            server.write_all(b"foo ${VAR} ${VAR.}bar").await.unwrap();
+        });
+
+        let mut reader = SubstReader::new(client, env());
+        let mut output = String::new();
+        reader.read_to_string(&mut output).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(output, "foo var var.bar");
+    }
+
+    #[::tokio::test]
+    async fn async_writer() {
+        let mut writer = SubstWriter::new(Vec::new(), env());
+        for chunk in ["foo $", "{VA", "R} ${VAR", ".}bar ${"] {
+            writer.write_all(chunk.as_bytes()).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+        assert_eq!(writer.into_inner(), b"foo var var.bar ${");
+    }
+}
@@ -0,0 +1,106 @@
+//! Streaming substitution for synchronous writers.
+
+use crate::invalid_data;
+use crate::stream::Streamer;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Writer substituting variables in the data written to an inner writer.
+///
+/// Placeholders split across `write` calls are handled transparently.
+/// Output may be held back while it could still be part of a placeholder,
+/// so [`finish`](SubstWriter::finish) must be called to write out the
+/// remaining data.
+///
+/// ```rust
+/// use std::io::Write;
+///
+/// let context = ksubst::context! {"hostname" => "example.com"};
+/// let mut writer = ksubst::SubstWriter::new(Vec::new(), context);
+/// writer.write_all(b"https://${host").unwrap();
+/// writer.write_all(b"name}/").unwrap();
+///
+/// let output = writer.finish().unwrap();
+/// assert_eq!(output, b"https://example.com/");
+/// ```
+#[derive(Debug)]
+pub struct SubstWriter<W: Write> {
+    inner: W,
+    variables: HashMap<String, String>,
+    streamer: Streamer,
+}
+
+impl<W: Write> SubstWriter<W> {
+    /// Wrap `inner`, substituting placeholders with values from `variables`.
+    pub fn new(inner: W, variables: HashMap<String, String>) -> Self {
+        Self {
+            inner,
+            variables,
+            streamer: Streamer::default(),
+        }
+    }
+
+    /// Write out the remaining data and flush, returning the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let output = self
+            .streamer
+            .finish(&self.variables)
+            .map_err(invalid_data)?;
+        self.inner.write_all(output.as_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Consume this adapter, returning the inner writer.
+    ///
+    /// Any data not yet written out is lost.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for SubstWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let output = self
+            .streamer
+            .feed(buf, &self.variables)
+            .map_err(invalid_data)?;
+        self.inner.write_all(output.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    /// Flush the inner writer.
+    ///
+    /// Data which may still be part of a placeholder is not written out.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_writer() {
+        let context = crate::context! {"VAR" => "var"};
+        let input = "foo ${VAR} ${VAR.}bar ${MISSING} $${VAR}";
+
+        for size in 1..input.len() {
+            let mut writer = SubstWriter::new(Vec::new(), context.clone());
+            for chunk in input.as_bytes().chunks(size) {
+                writer.write_all(chunk).unwrap();
+            }
+            let output = writer.finish().unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "foo var var.bar ${MISSING} $var"
+            );
+        }
+
+        let mut writer = SubstWriter::new(Vec::new(), context);
+        writer.write_all(b"foo ${VAR").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.into_inner(), b"foo ");
+    }
+}